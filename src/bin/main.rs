@@ -1,6 +1,7 @@
-use std::cell::UnsafeCell;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
 
 use ext2;
 use ext2::fs;
@@ -43,13 +44,13 @@ fn main() {
 
 
 pub struct FileDisk {
-    file: UnsafeCell<File>,
+    file: File,
 }
 
 impl FileDisk {
     pub fn open(filename: &str) -> Result<Self, Error> {
         match File::options().write(true).read(true).open(filename) {
-            Ok(file) => Ok(Self { file: file.into() }),
+            Ok(file) => Ok(Self { file }),
             Err(_) => Err(Error::IOError("disk open failed!".to_string())),
         }
     }
@@ -57,63 +58,109 @@ impl FileDisk {
 
 impl Disk for FileDisk {
     fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
-        unsafe {
-            match (*self.file.get()).read(buffer) {
-                Ok(n) => Ok(n),
-                Err(_) => Err(Error::IOError("FileDisk read failed".to_string())),
-            }
+        match (&self.file).read(buffer) {
+            Ok(n) => Ok(n),
+            Err(_) => Err(Error::IOError("FileDisk read failed".to_string())),
         }
     }
 
     fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
-        unsafe {
-            match (*self.file.get()).write(buffer) {
-                Ok(n) => Ok(n),
-                Err(e) => {
-                    println!("{:?}", e);
-                    Err(Error::IOError("FileDisk read failed".to_string()))
-                }
+        match (&self.file).write(buffer) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                println!("{:?}", e);
+                Err(Error::IOError("FileDisk read failed".to_string()))
             }
         }
     }
 
     fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
-        let offset: u64 = offset.value();
         let mut buffer: Vec<u8> = Vec::new();
         buffer.resize(size as usize, 0);
-        match self.seek(offset) {
-            Ok(_) => {}
-            Err(_) => return Err(Error::IOError("seek disk failed!".to_string())),
-        };
-        let n_bytes: usize = match self.read(&mut buffer) {
-            Ok(n) => n,
-            Err(_) => return Err(Error::IOError("read disk failed!".to_string())),
-        };
-        if n_bytes != size as usize {
-            Err(Error::UnexpectedEof("Not enough bytes".to_string()))
-        } else {
-            Ok(buffer)
-        }
+        self.read_exact_at(offset.value()?, &mut buffer)?;
+        Ok(buffer)
     }
 
     fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error> {
-        let offset: u64 = offset.value();
-        match self.seek(offset) {
-            Ok(_) => {}
-            Err(e) => return Err(e),
-        };
-        match self.write(&buffer) {
-            Ok(n) => Ok(n),
+        self.write_all_at(offset.value()?, buffer)?;
+        Ok(buffer.len())
+    }
+
+    // pread/pwrite, the shared file cursor is left alone
+    #[cfg(unix)]
+    fn read_exact_at(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        match self.file.read_exact_at(buffer, offset) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::UnexpectedEof("Not enough bytes".to_string())),
+        }
+    }
+
+    #[cfg(unix)]
+    fn write_all_at(&self, offset: u64, buffer: &[u8]) -> Result<(), Error> {
+        match self.file.write_all_at(buffer, offset) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::IOError("write disk failed!".to_string())),
+        }
+    }
+
+    // Seek then read, moves the shared cursor and is not safe across threads
+    #[cfg(not(unix))]
+    fn read_exact_at(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.seek(offset)?;
+        match (&self.file).read_exact(buffer) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::UnexpectedEof("Not enough bytes".to_string())),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn write_all_at(&self, offset: u64, buffer: &[u8]) -> Result<(), Error> {
+        self.seek(offset)?;
+        match (&self.file).write_all(buffer) {
+            Ok(_) => Ok(()),
             Err(_) => Err(Error::IOError("write disk failed!".to_string())),
         }
     }
 
     fn seek(&self, offset: u64) -> Result<(), Error> {
-        unsafe {
-            match (*self.file.get()).seek(SeekFrom::Start(offset)) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(Error::IOError("seek disk failed".to_string())),
-            }
+        match (&self.file).seek(SeekFrom::Start(offset)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::IOError("seek disk failed".to_string())),
+        }
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        match self.file.sync_all() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::IOError("flush disk failed".to_string())),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Only positioned I/O keeps concurrent readers apart
+    #[cfg(unix)]
+    #[test]
+    fn interleaved_read_at() {
+        let path = std::env::temp_dir().join(format!("ext2-fs-filedisk-{}", std::process::id()));
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i / 1024) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let disk = FileDisk::open(path.to_str().unwrap()).unwrap();
+        std::thread::scope(|scope| {
+            for worker in 0..4u64 {
+                let disk = &disk;
+                scope.spawn(move || {
+                    for round in 0..200u64 {
+                        let block = (worker * 16 + round) % 64;
+                        let buffer = disk.read_at(&Offset::new(1024, block), 1024).unwrap();
+                        assert!(buffer.iter().all(|&b| b as u64 == block));
+                    }
+                });
+            }
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
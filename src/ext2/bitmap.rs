@@ -0,0 +1,146 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Block or inode bitmap of one group, bit `i` is the group's `i`th block or inode
+/// counting from 0, stored least significant bit first like on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitMap {
+    bytes: Vec<u8>,
+}
+
+impl BitMap {
+    pub fn new(bytes: Vec<u8>) -> BitMap {
+        BitMap { bytes }
+    }
+
+    /// All clear bitmap of `len` bytes
+    pub fn zeroed(len: usize) -> BitMap {
+        BitMap { bytes: vec![0u8; len] }
+    }
+
+    /// Number of bits
+    pub fn len(&self) -> usize {
+        self.bytes.len() * 8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.bytes[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    /// Set or clear bit `i`, returns its previous value
+    pub fn set(&mut self, i: usize, value: bool) -> bool {
+        let old = self.get(i);
+        match value {
+            true => self.bytes[i / 8] |= 1 << (i % 8),
+            false => self.bytes[i / 8] &= !(1 << (i % 8)),
+        }
+        old
+    }
+
+    pub fn find_first_clear(&self) -> Option<usize> {
+        self.find_first_clear_in(0..self.len())
+    }
+
+    /// First clear bit inside `range`, whole bytes that are full are skipped
+    pub fn find_first_clear_in(&self, range: Range<usize>) -> Option<usize> {
+        let end = range.end.min(self.len());
+        let mut i = range.start;
+        while i < end {
+            if i.is_multiple_of(8) && self.bytes[i / 8] == u8::MAX {
+                i += 8;
+                continue;
+            }
+            if !self.get(i) {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// First run of `len` clear bits inside `range`, when there is none the longest
+    /// shorter run. None when every bit in `range` is set.
+    pub fn find_clear_run_in(&self, range: Range<usize>, len: usize) -> Option<Range<usize>> {
+        let end = range.end.min(self.len());
+        let mut best: Option<Range<usize>> = None;
+        let mut i = range.start;
+        while i < end {
+            let Some(start) = self.find_first_clear_in(i..end) else {
+                break;
+            };
+            let mut run_end = start;
+            while run_end < end && run_end - start < len && !self.get(run_end) {
+                run_end += 1;
+            }
+            if run_end - start == len {
+                return Some(start..run_end);
+            }
+            if best.as_ref().is_none_or(|best| run_end - start > best.len()) {
+                best = Some(start..run_end);
+            }
+            i = run_end;
+        }
+        best
+    }
+
+    pub fn count_set(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_bits() {
+        let mut bitmap = BitMap::zeroed(2);
+        assert_eq!(bitmap.len(), 16);
+        for (bit, byte, mask) in [(0, 0, 0x01), (7, 0, 0x80), (8, 1, 0x01), (15, 1, 0x80)] {
+            assert!(!bitmap.set(bit, true));
+            assert!(bitmap.get(bit));
+            assert_eq!(bitmap.as_bytes()[byte] & mask, mask);
+            assert!(bitmap.set(bit, true));
+        }
+        assert_eq!(bitmap.as_bytes(), [0x81, 0x81]);
+        assert_eq!(bitmap.count_set(), 4);
+        assert_eq!(bitmap.find_first_clear(), Some(1));
+        assert!(bitmap.set(15, false));
+        assert!(!bitmap.get(15));
+        assert!(!bitmap.get(14));
+    }
+
+    #[test]
+    fn find_first_clear_skips_full_bytes() {
+        let mut bitmap = BitMap::new(vec![0xff, 0xff, 0x7f]);
+        assert_eq!(bitmap.find_first_clear(), Some(23));
+        assert_eq!(bitmap.find_first_clear_in(0..23), None);
+        bitmap.set(23, true);
+        assert_eq!(bitmap.find_first_clear(), None);
+        assert_eq!(BitMap::new(vec![0xfe]).find_first_clear_in(1..8), None);
+        assert_eq!(BitMap::new(vec![0x00]).find_first_clear_in(3..8), Some(3));
+    }
+
+    #[test]
+    fn clear_runs() {
+        let bitmap = BitMap::new(vec![0b1111_1000, 0xff]);
+        // Only a shorter run at the start, it is still the best one
+        assert_eq!(bitmap.find_clear_run_in(0..16, 5), Some(0..3));
+        assert_eq!(bitmap.find_clear_run_in(0..16, 2), Some(0..2));
+        assert_eq!(bitmap.find_clear_run_in(3..16, 1), None);
+        let bitmap = BitMap::new(vec![0b0001_0010, 0x00]);
+        // The first run long enough wins over the longer one after it
+        assert_eq!(bitmap.find_clear_run_in(0..16, 3), Some(5..8));
+        assert_eq!(bitmap.find_clear_run_in(0..16, 20), Some(5..16));
+        assert_eq!(bitmap.find_clear_run_in(0..4, 3), Some(2..4));
+    }
+}
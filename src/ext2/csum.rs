@@ -0,0 +1,60 @@
+// CRC32C (Castagnoli), reflected polynomial
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CRC32C_POLY,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continue a crc32c over `data`.
+/// Like the kernel's crc32c_le there is no inversion, start from `!0` for a fresh checksum
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// Offsets inside the on-disk inode
+const INODE_GENERATION: usize = 0x64;
+const INODE_CHECKSUM_LO: usize = 0x7c;
+const INODE_EXTRA_ISIZE: usize = 0x80;
+const INODE_CHECKSUM_HI: usize = 0x82;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Verify the metadata_csum checksum of a raw on-disk inode
+pub fn inode_checksum_valid(seed: u32, inode_num: u64, raw: &[u8]) -> bool {
+    let mut raw = raw.to_vec();
+    let get_u16 = |raw: &[u8], at: usize| u16::from_le_bytes([raw[at], raw[at + 1]]);
+    let has_hi = raw.len() > GOOD_OLD_INODE_SIZE
+        && get_u16(&raw, INODE_EXTRA_ISIZE) as usize >= INODE_CHECKSUM_HI + 2 - GOOD_OLD_INODE_SIZE;
+    let mut stored = get_u16(&raw, INODE_CHECKSUM_LO) as u32;
+    raw[INODE_CHECKSUM_LO..INODE_CHECKSUM_LO + 2].fill(0);
+    if has_hi {
+        stored |= (get_u16(&raw, INODE_CHECKSUM_HI) as u32) << 16;
+        raw[INODE_CHECKSUM_HI..INODE_CHECKSUM_HI + 2].fill(0);
+    }
+    let generation = &raw[INODE_GENERATION..INODE_GENERATION + 4];
+    let mut crc = crc32c(seed, &(inode_num as u32).to_le_bytes());
+    crc = crc32c(crc, generation);
+    crc = crc32c(crc, &raw);
+    match has_hi {
+        true => crc == stored,
+        false => crc & 0xffff == stored,
+    }
+}
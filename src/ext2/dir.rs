@@ -1,13 +1,16 @@
+use alloc::format;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::mem;
 use core::str;
 
+use crate::{align_up, to_slice};
 use crate::ext2::Ext2Filesystem;
 use crate::ext2::inode::Ext2Inode;
 use crate::fs::error::Error;
 use crate::fs::io::CoreRead;
-use crate::fs::stat::Stat;
+use crate::fs::stat::{Mode, Stat};
 
 #[repr(C)]
 #[derive(Debug, Default)]
@@ -22,6 +25,58 @@ pub struct Ext2DirEntryStruct {
     // Type indicator
 }
 
+impl Ext2DirEntryStruct {
+    /// Smallest record holding a `name_len` byte name, entries are 4 byte aligned
+    pub fn record_len(name_len: usize) -> usize {
+        align_up!(mem::size_of::<Self>() + name_len, 4) as usize
+    }
+
+    /// Length of the name. Without the FILETYPE feature the type byte is the
+    /// high byte of a 16 bit name length.
+    pub fn name_len(&self, has_filetype: bool) -> usize {
+        match has_filetype {
+            true => self.name_len as usize,
+            false => self.name_len as usize | (self.file_type as usize) << 8,
+        }
+    }
+
+    /// Header followed by `name`, zero padded to `rec_len`
+    pub fn to_bytes(&self, name: &str) -> Vec<u8> {
+        let size = mem::size_of::<Self>();
+        let len = core::cmp::max(self.rec_len as usize, Self::record_len(name.len()));
+        let mut bytes = vec![0u8; len];
+        bytes[..size].copy_from_slice(to_slice!(self, Self));
+        bytes[size..size + name.len()].copy_from_slice(name.as_bytes());
+        bytes
+    }
+
+    /// Parse the entry at the start of `bytes`, returns the header, the name and `rec_len`
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &str, usize), Error> {
+        let size = mem::size_of::<Self>();
+        let mut buf = bytes;
+        let entry = buf.read_struct::<Self>()?;
+        let name_end = size + entry.name_len as usize;
+        if bytes.len() < name_end {
+            return Err(Error::UnexpectedEof(format!("entry name needs {} bytes", name_end)));
+        }
+        let name = match str::from_utf8(&bytes[size..name_end]) {
+            Ok(name) => name,
+            Err(e) => return Err(Error::InvalidData(format!("entry name: {}", e))),
+        };
+        let rec_len = entry.rec_len as usize;
+        Ok((entry, name, rec_len))
+    }
+}
+
+/// Directory entry as `list` returns it, without the child's inode
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub name: String,
+    pub inode: u64,
+    // None without the FILETYPE feature or for an unknown type code
+    pub file_type: Option<Mode>,
+}
+
 // Directory entry
 #[derive(Debug)]
 pub struct Ext2DirEntry {
@@ -29,25 +84,35 @@ pub struct Ext2DirEntry {
     // file name
     inode_num: u64,
     // inode number
+    file_type: u8,
+    // type indicator, 0 when the FILETYPE feature is off
     inode: Ext2Inode,
 }
 
 impl Ext2DirEntry {
-    pub fn new(buffer: &Vec<u8>, offset: usize) -> (Ext2DirEntry, usize) {
+    /// Parse the entry at `offset` of a directory block, returns it and its `rec_len`.
+    /// Names which are not valid UTF-8 are converted lossily.
+    pub fn new(buffer: &[u8], offset: usize, has_filetype: bool) -> Result<(Ext2DirEntry, usize), Error> {
         let size = mem::size_of::<Ext2DirEntryStruct>();
-        let mut buf = &buffer[offset..offset + size];
-        let ext2_dir_entry = buf.read_struct::<Ext2DirEntryStruct>().unwrap();
-        let name_slice = &buffer[offset + size..offset + size + ext2_dir_entry.name_len as usize];
-        let name = match str::from_utf8(name_slice) {
-            Ok(v) => v,
-            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-        };
+        let corrupt = || Error::InvalidData(format!("corrupt directory entry at offset {}", offset));
+        let mut buf = buffer.get(offset..offset + size).ok_or_else(corrupt)?;
+        let ext2_dir_entry = buf.read_struct::<Ext2DirEntryStruct>()?;
+        let rec_len = ext2_dir_entry.rec_len as usize;
+        let name_end = offset + size + ext2_dir_entry.name_len(has_filetype);
+        // The name has to fit in the record, which has to fit in the block
+        if rec_len < size || name_end > offset + rec_len || offset + rec_len > buffer.len() {
+            return Err(corrupt());
+        }
         let dir_entry = Ext2DirEntry {
-            file_name: String::from(name),
+            file_name: String::from_utf8_lossy(&buffer[offset + size..name_end]).into_owned(),
             inode_num: ext2_dir_entry.inode_num as u64,
+            file_type: match has_filetype {
+                true => ext2_dir_entry.file_type,
+                false => 0,
+            },
             inode: Default::default(),
         };
-        (dir_entry, ext2_dir_entry.rec_len as usize)
+        Ok((dir_entry, rec_len))
     }
     pub fn get_inode(&mut self, fs: &Ext2Filesystem) -> Result<(), Error> {
         Ok(self.inode = fs.read_inode(self.inode_num)?)
@@ -59,10 +124,54 @@ impl Ext2DirEntry {
         return self.file_name.clone();
     }
 
+    /// Like `file_name` but borrows the name instead of cloning it
+    pub fn file_name_ref(&self) -> &str {
+        &self.file_name
+    }
+
+    /// Move the name out without copying it, the entry's name is empty afterwards
+    pub fn take_name(&mut self) -> String {
+        mem::take(&mut self.file_name)
+    }
+
     /// Returns the inode number
     pub fn inode_num(&self) -> u64 {
         self.inode_num
     }
+    /// Returns the file type recorded in the entry, without reading the inode
+    pub fn file_type(&self) -> Option<Mode> {
+        Self::file_type_mode(self.file_type)
+    }
+    /// File type for the type code of a directory entry, None when unknown
+    pub fn file_type_mode(code: u8) -> Option<Mode> {
+        match code {
+            1 => Some(Mode::FILE),
+            2 => Some(Mode::DIRECTORY),
+            3 => Some(Mode::CHAR_DEVICE),
+            4 => Some(Mode::BLOCK_DEVICE),
+            5 => Some(Mode::FIFO),
+            6 => Some(Mode::SOCKET),
+            7 => Some(Mode::SYMLINK),
+            _ => None,
+        }
+    }
+    /// Type code stored in a directory entry for an inode's `i_mode`, 0 when unknown
+    pub fn file_type_code(i_mode: u16) -> u8 {
+        const CODES: [(Mode, u8); 7] = [
+            (Mode::FILE, 1),
+            (Mode::DIRECTORY, 2),
+            (Mode::CHAR_DEVICE, 3),
+            (Mode::BLOCK_DEVICE, 4),
+            (Mode::FIFO, 5),
+            (Mode::SOCKET, 6),
+            (Mode::SYMLINK, 7),
+        ];
+        let format = i_mode & 0xf000;
+        match CODES.iter().find(|(mode, _)| mode.bits() == format) {
+            Some((_, code)) => *code,
+            None => 0,
+        }
+    }
     pub fn inode(&self) -> Ext2Inode {
         self.inode
     }
@@ -74,3 +183,130 @@ impl Ext2DirEntry {
         self.inode.metadata().is_dir()
     }
 }
+
+/// Open directory with a cursor, entries are read one at a time
+pub struct DirHandle<'a> {
+    fs: &'a Ext2Filesystem,
+    inode: Ext2Inode,
+    blocks: Vec<u64>,
+    // Position of the next entry: index into blocks and byte offset in that block
+    block_index: usize,
+    offset: usize,
+    buffer: Option<Vec<u8>>,
+}
+
+impl<'a> DirHandle<'a> {
+    pub fn new(fs: &'a Ext2Filesystem, inode: Ext2Inode, blocks: Vec<u64>) -> DirHandle<'a> {
+        DirHandle {
+            fs,
+            inode,
+            blocks,
+            block_index: 0,
+            offset: 0,
+            buffer: None,
+        }
+    }
+
+    /// Read the next entry, None once the end of the directory is reached
+    pub fn read_entry(&mut self) -> Result<Option<Ext2DirEntry>, Error> {
+        loop {
+            if self.block_index >= self.blocks.len() {
+                return Ok(None);
+            }
+            if self.buffer.is_none() {
+                self.buffer = Some(self.fs.read_block(self.blocks[self.block_index])?);
+            }
+            let buffer = self.buffer.as_ref().unwrap();
+            if self.offset >= buffer.len() {
+                self.block_index += 1;
+                self.offset = 0;
+                self.buffer = None;
+                continue;
+            }
+            let (mut entry, rec_len) =
+                Ext2DirEntry::new(buffer, self.offset, self.fs.super_block.has_filetype())?;
+            self.offset += rec_len;
+            // Unused entry
+            if entry.inode_num() == 0 {
+                continue;
+            }
+            entry.get_inode(self.fs)?;
+            return Ok(Some(entry));
+        }
+    }
+
+    /// Go back to the first entry
+    pub fn rewind(&mut self) {
+        self.block_index = 0;
+        self.offset = 0;
+        self.buffer = None;
+    }
+
+    pub fn inode(&self) -> Ext2Inode {
+        self.inode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_type_codes() {
+        let modes = [
+            Mode::FILE,
+            Mode::DIRECTORY,
+            Mode::CHAR_DEVICE,
+            Mode::BLOCK_DEVICE,
+            Mode::FIFO,
+            Mode::SOCKET,
+            Mode::SYMLINK,
+        ];
+        for (code, mode) in (1..=7).zip(modes) {
+            assert_eq!(Ext2DirEntry::file_type_mode(code).unwrap().bits(), mode.bits());
+            assert_eq!(Ext2DirEntry::file_type_code(mode.bits() | 0o644), code);
+        }
+        assert!(Ext2DirEntry::file_type_mode(0).is_none());
+        assert!(Ext2DirEntry::file_type_mode(8).is_none());
+    }
+
+    #[test]
+    fn entry_bytes_round_trip() {
+        for name in ["a", "ab", "abc", "abcd", "abcde", "lost+found", &"n".repeat(255)] {
+            let entry = Ext2DirEntryStruct {
+                inode_num: 12,
+                rec_len: Ext2DirEntryStruct::record_len(name.len()) as u16,
+                name_len: name.len() as u8,
+                file_type: 1,
+            };
+            let bytes = entry.to_bytes(name);
+            assert_eq!(bytes.len() % 4, 0);
+            assert_eq!(bytes.len(), (8 + name.len()).next_multiple_of(4));
+            let (parsed, parsed_name, rec_len) = Ext2DirEntryStruct::from_bytes(&bytes).unwrap();
+            assert_eq!((parsed.inode_num, parsed.file_type), (12, 1));
+            assert_eq!((parsed_name, rec_len), (name, bytes.len()));
+        }
+        // A larger rec_len pads the record with zeros
+        let entry = Ext2DirEntryStruct { inode_num: 2, rec_len: 1024, name_len: 1, file_type: 2 };
+        let bytes = entry.to_bytes(".");
+        assert_eq!(bytes.len(), 1024);
+        assert!(bytes[9..].iter().all(|&b| b == 0));
+        assert!(Ext2DirEntryStruct::from_bytes(&bytes[..8]).is_err());
+    }
+
+    #[test]
+    fn borrowed_name_matches_the_owned_one() {
+        let mut block = Ext2DirEntryStruct { inode_num: 12, rec_len: 16, name_len: 5, file_type: 1 }.to_bytes("hello");
+        block.extend(Ext2DirEntryStruct { inode_num: 13, rec_len: 16, name_len: 6, file_type: 2 }.to_bytes("world!"));
+        let mut offset = 0;
+        for name in ["hello", "world!"] {
+            let (entry, rec_len) = Ext2DirEntry::new(&block, offset, true).unwrap();
+            assert_eq!(entry.file_name_ref(), name);
+            assert_eq!(entry.file_name_ref(), entry.file_name());
+            let mut entry = entry;
+            assert_eq!(entry.take_name(), name);
+            assert_eq!(entry.file_name_ref(), "");
+            offset += rec_len;
+        }
+    }
+}
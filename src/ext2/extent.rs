@@ -0,0 +1,195 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::mem;
+
+use crate::fs::disk::{Disk, Offset};
+use crate::fs::error::Error;
+use crate::ext2::inode::BlockMapping;
+use crate::fs::io::CoreRead;
+
+pub const EXT4_EXTENT_MAGIC: u16 = 0xF30A;
+// Deepest tree the kernel builds
+const EXT4_MAX_EXTENT_DEPTH: u16 = 5;
+// Lengths past this mark an extent allocated but never written, it reads as zeros
+const EXT4_INIT_MAX_LEN: u16 = 32768;
+
+/// Starts the root node in i_block and every tree block
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Ext4ExtentHeader {
+    pub eh_magic: u16,
+    // Number of valid entries following the header
+    pub eh_entries: u16,
+    // Capacity of the node in entries
+    pub eh_max: u16,
+    // 0 for leaves holding extents, otherwise the node holds indexes
+    pub eh_depth: u16,
+    pub eh_generation: u32,
+}
+
+/// Interior node entry pointing at the block of the next level
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Ext4ExtentIdx {
+    // First logical block the child covers
+    pub ei_block: u32,
+    pub ei_leaf_lo: u32,
+    pub ei_leaf_hi: u16,
+    pub ei_unused: u16,
+}
+
+/// Leaf entry mapping a run of logical blocks to contiguous physical ones
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Ext4Extent {
+    // First logical block of the run
+    pub ee_block: u32,
+    pub ee_len: u16,
+    pub ee_start_hi: u16,
+    pub ee_start_lo: u32,
+}
+
+impl Ext4ExtentIdx {
+    pub fn leaf(&self) -> u64 {
+        (self.ei_leaf_hi as u64) << 32 | self.ei_leaf_lo as u64
+    }
+}
+
+impl Ext4Extent {
+    pub fn start(&self) -> u64 {
+        (self.ee_start_hi as u64) << 32 | self.ee_start_lo as u64
+    }
+    pub fn is_unwritten(&self) -> bool {
+        self.ee_len > EXT4_INIT_MAX_LEN
+    }
+    pub fn len(&self) -> u64 {
+        match self.is_unwritten() {
+            true => (self.ee_len - EXT4_INIT_MAX_LEN) as u64,
+            false => self.ee_len as u64,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Every entry takes the same 12 bytes as the header
+const _: () = assert!(mem::size_of::<Ext4ExtentIdx>() == 12 && mem::size_of::<Ext4Extent>() == 12);
+
+/// `len` logical blocks from `logical` stored from physical block `physical`,
+/// which is 0 when the run reads as zeros
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExtentRun {
+    pub logical: u64,
+    pub physical: u64,
+    pub len: u64,
+}
+
+/// Walk the extent tree rooted in `i_block` and list its runs in logical order
+pub fn extent_map(disk: &dyn Disk, i_block: &[u8], block_size: u64) -> Result<Vec<ExtentRun>, Error> {
+    let mut runs = Vec::new();
+    walk(disk, i_block, block_size, None, &mut runs)?;
+    runs.sort_by_key(|run| run.logical);
+    Ok(runs)
+}
+
+/// Physical block of `logical` in `runs` sorted by `extent_map`, 0 for a hole
+pub fn lookup(runs: &[ExtentRun], logical: u64) -> u64 {
+    let i = runs.partition_point(|run| run.logical + run.len <= logical);
+    match runs.get(i) {
+        Some(run) if run.logical <= logical && run.physical != 0 => run.physical + logical - run.logical,
+        _ => 0,
+    }
+}
+
+/// Block mapping of an extent-mapped inode
+pub struct ExtentMapping {
+    runs: Vec<ExtentRun>,
+    data_blocks_count: u64,
+    curr: u64,
+}
+
+impl ExtentMapping {
+    pub fn new(runs: Vec<ExtentRun>, data_blocks_count: u64) -> ExtentMapping {
+        ExtentMapping { runs, data_blocks_count, curr: 0 }
+    }
+}
+
+impl Iterator for ExtentMapping {
+    type Item = Result<u64, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.curr >= self.data_blocks_count {
+            return None;
+        }
+        self.curr += 1;
+        Some(self.physical(self.curr - 1))
+    }
+}
+
+impl BlockMapping for ExtentMapping {
+    fn physical(&mut self, i: u64) -> Result<u64, Error> {
+        Ok(lookup(&self.runs, i))
+    }
+}
+
+fn walk(
+    disk: &dyn Disk,
+    node: &[u8],
+    block_size: u64,
+    depth: Option<u16>,
+    runs: &mut Vec<ExtentRun>,
+) -> Result<(), Error> {
+    let mut reader = node;
+    let header = reader.read_struct::<Ext4ExtentHeader>()?;
+    if header.eh_magic != EXT4_EXTENT_MAGIC {
+        return Err(Error::InvalidData(format!("bad extent header magic {:#x}", header.eh_magic)));
+    }
+    // Each level has to be one less deep than its parent, which also stops loops
+    if header.eh_depth > EXT4_MAX_EXTENT_DEPTH || depth.is_some_and(|depth| depth != header.eh_depth) {
+        return Err(Error::InvalidData(format!("extent node at bad depth {}", header.eh_depth)));
+    }
+    let capacity = (node.len() - mem::size_of::<Ext4ExtentHeader>()) / mem::size_of::<Ext4Extent>();
+    if header.eh_entries as usize > capacity {
+        return Err(Error::InvalidData(format!(
+            "extent node holds {} entries, room for {}",
+            header.eh_entries, capacity
+        )));
+    }
+    for _ in 0..header.eh_entries {
+        if header.eh_depth == 0 {
+            let extent = reader.read_struct::<Ext4Extent>()?;
+            runs.push(ExtentRun {
+                logical: extent.ee_block as u64,
+                physical: match extent.is_unwritten() {
+                    true => 0,
+                    false => extent.start(),
+                },
+                len: extent.len(),
+            });
+        } else {
+            let index = reader.read_struct::<Ext4ExtentIdx>()?;
+            let child = disk.read_at(&Offset::new(block_size, index.leaf()), block_size)?;
+            walk(disk, &child, block_size, Some(header.eh_depth - 1), runs)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extent_lengths() {
+        let extent = |ee_len| Ext4Extent { ee_len, ..Default::default() };
+        assert!(extent(0).is_empty());
+        assert_eq!((extent(5).len(), extent(5).is_unwritten()), (5, false));
+        // 32768 is the longest initialized extent, past it the length is offset
+        assert_eq!((extent(32768).len(), extent(32768).is_unwritten()), (32768, false));
+        assert_eq!((extent(32768 + 3).len(), extent(32768 + 3).is_unwritten()), (3, true));
+        assert!(!extent(32768 + 3).is_empty());
+        let runs = [ExtentRun { logical: 0, physical: 100, len: 2 }, ExtentRun { logical: 4, physical: 0, len: 1 }];
+        assert_eq!([0, 1, 2, 4, 5].map(|logical| lookup(&runs, logical)), [100, 101, 0, 0, 0]);
+    }
+}
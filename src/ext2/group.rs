@@ -1,4 +1,6 @@
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::ToString;
 use alloc::vec::Vec;
 use core::mem;
 
@@ -47,6 +49,18 @@ impl Ext2GroupDesc {
     }
 }
 
+/// Per group summary, see `Ext2Filesystem::group_stats`
+#[derive(Debug, Copy, Clone)]
+pub struct GroupStat {
+    pub group_num: usize,
+    pub free_blocks: u64,
+    pub free_inodes: u64,
+    pub used_dirs: u64,
+    pub block_bitmap_loc: u64,
+    pub inode_bitmap_loc: u64,
+    pub inode_table_loc: u64,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct GroupDesc {
     pub group_num: usize,
@@ -65,6 +79,17 @@ impl GroupDesc {
             first_inode_num: group_num as u64 * inodes_per_group as u64 + 1,
         }
     }
+
+    /// Location of the slot of `inode_num` in this group's inode table.
+    /// The table is contiguous, so the block is found by the slot's byte position in it.
+    pub fn inode_offset(&self, inode_num: u64, inode_size: u64, block_size: u64) -> Offset {
+        let position = (inode_num - self.first_inode_num) * inode_size;
+        Offset::new_offset(
+            block_size,
+            self.ext2_group_desc.bg_inode_table as u64 + position / block_size,
+            position % block_size,
+        )
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -72,22 +97,52 @@ pub struct Ext2BlockGroups {
     block_size: u64,
     group_count: u64,
     inodes_per_group: u64,
+    inode_size: u64,
+    blocks_count: u64,
+    first_data_block: u64,
+    // First block of the descriptor table, a backup copy's when mounted from one
+    desc_block: u64,
 }
 
 impl Ext2BlockGroups {
     /// Read the Block Groups
     pub fn new(super_block: &Ext2SuperBlock) -> Result<Ext2BlockGroups, Error> {
+        if super_block.s_blocks_per_group == 0 || super_block.s_inodes_per_group == 0 {
+            return Err(Error::InvalidData("zero blocks or inodes per group".to_string()));
+        }
+        // mkfs gives every group the same number of inodes, a group count from a
+        // damaged block count or blocks per group shows up as a mismatch here
+        let group_count = super_block.get_groups_count() as u64;
+        let inodes = group_count * super_block.s_inodes_per_group as u64;
+        if inodes != super_block.s_inodes_count as u64 {
+            return Err(Error::InvalidData(format!(
+                "{} groups of {} inodes do not match the inode count {}",
+                group_count, super_block.s_inodes_per_group, super_block.s_inodes_count
+            )));
+        }
         let result = Ext2BlockGroups {
             // block_groups,
             block_size: super_block.get_block_size(),
             group_count: super_block.get_groups_count() as u64,
             inodes_per_group: super_block.s_inodes_per_group as u64,
+            inode_size: super_block.s_inode_size as u64,
+            blocks_count: super_block.s_blocks_count as u64,
+            first_data_block: super_block.s_first_data_block as u64,
+            desc_block: super_block.group_desc_block(),
         };
         Ok(result)
     }
 
+    /// Read the descriptors from the table starting at `desc_block` instead of the primary one
+    pub fn with_desc_block(self, desc_block: u64) -> Ext2BlockGroups {
+        Ext2BlockGroups { desc_block, ..self }
+    }
+
     /// Determine which block group the inode belongs to and return the group
     pub fn get_inode_group(&self, inode_num: u64, disk: &Box<dyn Disk>) -> Result<GroupDesc, Error> {
+        if inode_num == 0 || inode_num > self.group_count * self.inodes_per_group {
+            return Err(Error::InvalidInput(format!("inode {} out of range", inode_num)));
+        }
         let group_num = (inode_num - 1) / self.inodes_per_group;
         let desc = self.fetch_group_desc(group_num, disk)?;
         Ok(GroupDesc {
@@ -96,6 +151,20 @@ impl Ext2BlockGroups {
             first_inode_num: group_num * self.inodes_per_group + 1,
         })
     }
+    /// Check the descriptor of the last group, past the end of the real descriptor
+    /// table it reads as zeros or garbage
+    pub fn validate(&self, disk: &Box<dyn Disk>) -> Result<(), Error> {
+        let last = self.group_count - 1;
+        let desc = self.fetch_group_desc(last, disk)?;
+        let blocks = [desc.bg_block_bitmap, desc.bg_inode_bitmap, desc.bg_inode_table];
+        if blocks.iter().any(|&b| (b as u64) <= self.first_data_block) {
+            return Err(Error::InvalidData(format!(
+                "group {} descriptor points into the superblock area, the group count {} looks wrong",
+                last, self.group_count
+            )));
+        }
+        Ok(())
+    }
     pub fn get_group(&self, group_num: u64, disk: &Box<dyn Disk>) -> Result<GroupDesc, Error> {
         let desc = self.fetch_group_desc(group_num, disk)?;
         Ok(GroupDesc {
@@ -109,10 +178,24 @@ impl Ext2BlockGroups {
         let block_size = self.block_size;
         let offset = Offset::new_offset(
             block_size,
-            if block_size == 1024 { 2 } else { 1 },
+            self.desc_block,
             group_num * size,
         );
         let buffer = disk.read_at(&offset, size)?;
-        buffer.as_slice().read_struct::<Ext2GroupDesc>()
+        let desc = buffer.as_slice().read_struct::<Ext2GroupDesc>()?;
+        // Block numbers come straight from disk, don't let them address past the end
+        let table_blocks = (self.inodes_per_group * self.inode_size).div_ceil(block_size);
+        let blocks = [
+            desc.bg_block_bitmap as u64,
+            desc.bg_inode_bitmap as u64,
+            desc.bg_inode_table as u64 + table_blocks - 1,
+        ];
+        if blocks.iter().any(|b| *b >= self.blocks_count) {
+            return Err(Error::InvalidData(format!(
+                "group {} descriptor points past the last block",
+                group_num
+            )));
+        }
+        Ok(desc)
     }
 }
@@ -0,0 +1,114 @@
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+
+use bitflags::Flags;
+
+use crate::ext2::superblock::{Ext2SuperBlock, FeatureCompat, FeatureIncompat, FeatureRoCompat};
+
+/// Superblock summary, see `Ext2Filesystem::describe`
+#[derive(Debug, Clone)]
+pub struct FsInfo {
+    pub volume_name: String,
+    pub last_mounted: String,
+    pub uuid: [u8; 16],
+    pub rev_level: u32,
+    pub block_size: u64,
+    pub blocks_count: u64,
+    pub free_blocks: u64,
+    pub reserved_blocks: u64,
+    pub inodes_count: u64,
+    pub free_inodes: u64,
+    pub blocks_per_group: u64,
+    pub inodes_per_group: u64,
+    pub inode_size: u64,
+    pub compat: FeatureCompat,
+    pub incompat: FeatureIncompat,
+    pub ro_compat: FeatureRoCompat,
+    pub mount_count: u16,
+    pub max_mount_count: u16,
+    // Seconds since the epoch
+    pub mtime: u32,
+    pub wtime: u32,
+    pub lastcheck: u32,
+}
+
+impl FsInfo {
+    pub fn new(sb: &Ext2SuperBlock) -> FsInfo {
+        FsInfo {
+            volume_name: c_string(&sb.s_volume_name),
+            last_mounted: c_string(&sb.s_last_mounted),
+            uuid: sb.s_uuid,
+            rev_level: sb.s_rev_level,
+            block_size: sb.get_block_size(),
+            blocks_count: sb.s_blocks_count as u64,
+            free_blocks: sb.s_free_blocks_count as u64,
+            reserved_blocks: sb.s_r_blocks_count as u64,
+            inodes_count: sb.s_inodes_count as u64,
+            free_inodes: sb.s_free_inodes_count as u64,
+            blocks_per_group: sb.s_blocks_per_group as u64,
+            inodes_per_group: sb.s_inodes_per_group as u64,
+            inode_size: sb.s_inode_size as u64,
+            compat: sb.feature_compat(),
+            incompat: sb.feature_incompat(),
+            ro_compat: sb.feature_ro_compat(),
+            mount_count: sb.s_mnt_count,
+            max_mount_count: sb.s_max_mnt_count,
+            mtime: sb.s_mtime,
+            wtime: sb.s_wtime,
+            lastcheck: sb.s_lastcheck,
+        }
+    }
+}
+
+// Fixed size, NUL padded superblock string
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// Lower case flag names like dumpe2fs prints them, unknown bits in hex
+fn write_flags<T: Flags<Bits = u32>>(f: &mut Formatter<'_>, flags: &T) -> core::fmt::Result {
+    for (name, _) in flags.iter_names() {
+        write!(f, " {}", name.to_ascii_lowercase())?;
+    }
+    let unknown = flags.bits() & !T::all().bits();
+    if unknown != 0 {
+        write!(f, " {:#x}", unknown)?;
+    }
+    Ok(())
+}
+
+impl Display for FsInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Filesystem volume name:   {}", self.volume_name)?;
+        writeln!(f, "Last mounted on:          {}", self.last_mounted)?;
+        write!(f, "Filesystem UUID:          ")?;
+        for (i, b) in self.uuid.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Filesystem revision #:    {}", self.rev_level)?;
+        write!(f, "Filesystem features:     ")?;
+        write_flags(f, &self.compat)?;
+        write_flags(f, &self.incompat)?;
+        write_flags(f, &self.ro_compat)?;
+        writeln!(f)?;
+        writeln!(f, "Inode count:              {}", self.inodes_count)?;
+        writeln!(f, "Block count:              {}", self.blocks_count)?;
+        writeln!(f, "Reserved block count:     {}", self.reserved_blocks)?;
+        writeln!(f, "Free blocks:              {}", self.free_blocks)?;
+        writeln!(f, "Free inodes:              {}", self.free_inodes)?;
+        writeln!(f, "Block size:               {}", self.block_size)?;
+        writeln!(f, "Blocks per group:         {}", self.blocks_per_group)?;
+        writeln!(f, "Inodes per group:         {}", self.inodes_per_group)?;
+        writeln!(f, "Inode size:               {}", self.inode_size)?;
+        writeln!(f, "Mount count:              {}", self.mount_count)?;
+        writeln!(f, "Maximum mount count:      {}", self.max_mount_count)?;
+        writeln!(f, "Last mount time:          {}", self.mtime)?;
+        writeln!(f, "Last write time:          {}", self.wtime)?;
+        write!(f, "Last checked:             {}", self.lastcheck)
+    }
+}
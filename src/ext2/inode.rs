@@ -2,12 +2,16 @@ use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
+use core::cmp::min;
 use core::mem;
 use core::str;
 
-use crate::{align_up, to_slice};
+use crate::to_slice;
+use crate::ext2::csum::inode_checksum_valid;
 use crate::ext2::dir::{Ext2DirEntry, Ext2DirEntryStruct};
+use crate::ext2::extent::{self, ExtentMapping, ExtentRun};
 use crate::ext2::Ext2Filesystem;
 use crate::ext2::group::Ext2BlockGroups;
 use crate::fs::disk::{Disk, Offset};
@@ -23,6 +27,11 @@ pub const EXT2_TRIPLY_IND_BLOCK: usize = EXT2_DOUBLY_IND_BLOCK + 1;
 pub const EXT2_N_BLOCKS: usize = EXT2_TRIPLY_IND_BLOCK + 1;
 pub const I_BLOCKS_SIZE: usize = EXT2_N_BLOCKS * 4;
 
+/// Block pointers one index block holds, the fanout of each indirect level
+pub const fn pointers_per_block(block_size: u64) -> u64 {
+    block_size / mem::size_of::<u32>() as u64
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Ext2InodeStruct {
@@ -74,9 +83,11 @@ impl Ext2InodeStruct {
     pub fn new_dir(perm: u16, first_block: u32, size: u32) -> Self {
         let mut new = Self::default();
         new.i_mode = Mode::DIRECTORY.bits() | perm;
-        new.i_links_count = 1;
+        // The entry in the parent and its own "."
+        new.i_links_count = 2;
         new.i_block[0] = first_block;
-        new.i_blocks = 2;
+        // Counted in 512 byte sectors, the one block takes size / 512 of them
+        new.i_blocks = size / 512;
         new.i_size = size;
         new
     }
@@ -85,7 +96,7 @@ impl Ext2InodeStruct {
         new.i_mode = Mode::FILE.bits() | perm;
         new.i_links_count = 1;
         new.i_block[0] = first_block;
-        new.i_blocks = 2;
+        new.i_blocks = size / 512;
         new.i_size = size;
         new
     }
@@ -99,6 +110,15 @@ impl Ext2InodeStruct {
     pub fn is_file(&self) -> bool {
         Mode::from_bits_truncate(self.i_mode).is_file()
     }
+    /// Symlink with its target stored in i_block, it owns no data blocks
+    /// apart from an extended attribute block
+    pub fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let acl_sectors = match self.i_file_acl {
+            0 => 0,
+            _ => block_size / 512,
+        };
+        Mode::from_bits_truncate(self.i_mode).is_symlink() && self.i_blocks as u64 == acl_sectors
+    }
     pub fn size(&self) -> u64 {
         // Calculate the size in bytes
         if self.is_file() {
@@ -107,11 +127,70 @@ impl Ext2InodeStruct {
             self.i_size as u64
         }
     }
+    pub fn set_size(&mut self, size: u64) {
+        self.i_size = size as u32;
+        if self.is_file() {
+            self.i_size_high = (size >> 32) as u32;
+        }
+    }
     pub const fn blocks(&self) -> [u32; EXT2_N_BLOCKS] {
         self.i_block
     }
 }
 
+/// Fields past the first 128 bytes of large inodes, zero when the inode is too small to hold them
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Ext2InodeExtra {
+    pub extra_isize: u16,
+    // Bytes in use past the first 128
+    pub ctime_extra: u32,
+    // Nanoseconds << 2 | epoch bits, for each of the times
+    pub mtime_extra: u32,
+    pub atime_extra: u32,
+    pub crtime: u32,
+    // Creation time
+    pub crtime_extra: u32,
+}
+
+impl Ext2InodeExtra {
+    const GOOD_OLD_INODE_SIZE: usize = 128;
+
+    /// Parse the extra fields from a whole on-disk inode
+    pub fn parse(raw: &[u8]) -> Ext2InodeExtra {
+        let base = Self::GOOD_OLD_INODE_SIZE;
+        if raw.len() < base + 2 {
+            return Ext2InodeExtra::default();
+        }
+        let extra_isize = u16::from_le_bytes([raw[base], raw[base + 1]]);
+        let end = core::cmp::min(raw.len(), base + extra_isize as usize);
+        // A field counts only when i_extra_isize covers all of it
+        let get_u32 = |at: usize| match base + at + 4 <= end {
+            true => u32::from_le_bytes(raw[base + at..base + at + 4].try_into().unwrap()),
+            false => 0,
+        };
+        Ext2InodeExtra {
+            extra_isize,
+            ctime_extra: get_u32(4),
+            mtime_extra: get_u32(8),
+            atime_extra: get_u32(12),
+            crtime: get_u32(16),
+            crtime_extra: get_u32(20),
+        }
+    }
+
+    /// Seconds and nanoseconds of a time with its extra field.
+    /// The low 2 bits of the extra field extend the signed 32 bit seconds past 2038.
+    pub fn decode_time(seconds: u32, extra: u32) -> (i64, i64) {
+        match extra {
+            0 => (seconds as i64, 0),
+            _ => (
+                seconds as i32 as i64 + (((extra & 3) as i64) << 32),
+                (extra >> 2) as i64,
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Ext2Inode {
     pub(crate) inode_num: u64,
@@ -125,6 +204,8 @@ pub struct Ext2Inode {
     pub(crate) size: u64,
     // Size in bytes
     pub(crate) data_blocks_count: u64, // Number of data blocks
+    pub(crate) extra: Ext2InodeExtra,
+    // Fields of large inodes
 }
 
 impl Ext2Inode {
@@ -134,18 +215,22 @@ impl Ext2Inode {
         block_size: u64,
         block_groups: &Ext2BlockGroups,
         inode_num: u64,
+        csum_seed: Option<u32>,
     ) -> Result<Ext2Inode, Error> {
         // Determinate the block group
         let group = block_groups.get_inode_group(inode_num, disk)?;
         // Calculate the offset
-        let offset = Offset::new_offset(
-            block_size,
-            group.ext2_group_desc.bg_inode_table as u64,
-            (inode_num - group.first_inode_num) * inode_size,
-        );
+        let offset = group.inode_offset(inode_num, inode_size, block_size);
         // Read the inode from the disk
         let buffer = disk.read_at(&offset, inode_size)?;
+        // Verify the checksum on metadata_csum filesystems
+        if let Some(seed) = csum_seed {
+            if !inode_checksum_valid(seed, inode_num, &buffer) {
+                return Err(Error::InvalidData(format!("inode {} checksum mismatch", inode_num)));
+            }
+        }
         let inode = buffer.as_slice().read_struct::<Ext2InodeStruct>()?;
+        let extra = Ext2InodeExtra::parse(&buffer);
         // Calculate the size
         let size = inode.size();
         // Calculate the number of data blocks
@@ -154,6 +239,11 @@ impl Ext2Inode {
             true => data_blocks_count,
             false => data_blocks_count + 1
         };
+        // i_block of a fast symlink holds the target, not block numbers
+        let data_blocks_count = match inode.is_fast_symlink(block_size) {
+            true => 0,
+            false => data_blocks_count,
+        };
         Ok(Ext2Inode {
             inode_num,
             ext2_inode: inode,
@@ -161,21 +251,17 @@ impl Ext2Inode {
             block_size,
             size,
             data_blocks_count,
+            extra,
         })
     }
 
-    pub fn write(&self, disk: &Box<dyn Disk>, block_groups: &Ext2BlockGroups) {
+    pub fn write(&self, disk: &Box<dyn Disk>, block_groups: &Ext2BlockGroups) -> Result<(), Error> {
         // Determinate the block group
-        let group = block_groups.get_inode_group(self.inode_num, disk).unwrap();
+        let group = block_groups.get_inode_group(self.inode_num, disk)?;
         // Calculate the offset
-        let offset = Offset::new_offset(
-            self.block_size,
-            group.ext2_group_desc.bg_inode_table as u64,
-            (self.inode_num - group.first_inode_num) * self.inode_size,
-        );
-        self.ext2_inode;
-        disk.write_at(&offset, to_slice!(&self.ext2_inode, Ext2InodeStruct))
-            .unwrap();
+        let offset = group.inode_offset(self.inode_num, self.inode_size, self.block_size);
+        disk.write_at(&offset, to_slice!(&self.ext2_inode, Ext2InodeStruct))?;
+        Ok(())
     }
     pub fn blocks(&self) -> [u32; EXT2_N_BLOCKS] {
         self.ext2_inode.blocks()
@@ -183,6 +269,25 @@ impl Ext2Inode {
     pub fn inode(&self) -> u64 {
         self.inode_num
     }
+    /// The inode as stored on disk
+    pub fn raw(&self) -> &Ext2InodeStruct {
+        &self.ext2_inode
+    }
+    /// File version (for NFS)
+    pub fn generation(&self) -> u32 {
+        self.ext2_inode.i_generation
+    }
+    /// Block holding the extended attributes
+    pub fn file_acl(&self) -> u32 {
+        self.ext2_inode.i_file_acl
+    }
+    /// Directory ACL, shares its slot with the upper size bits of regular files
+    pub fn dir_acl(&self) -> u32 {
+        match self.ext2_inode.is_file() {
+            true => 0,
+            false => self.ext2_inode.i_size_high,
+        }
+    }
     /// Read blocks iterator
     pub fn read_blocks_iter<'a>(&'a self, disk: &'a Box<dyn Disk>) -> Result<ReadBlock<'a>, Error> {
         Ok(ReadBlock {
@@ -192,26 +297,62 @@ impl Ext2Inode {
         })
     }
 
+    /// Blocks of the file in order with holes reported instead of read,
+    /// the last block is cut at the file size
+    pub fn read_blocks_sparse<'a>(&'a self, disk: &'a Box<dyn Disk>) -> Result<ReadBlockData<'a>, Error> {
+        Ok(ReadBlockData {
+            disk,
+            block_size: self.block_size,
+            remaining: self.get_size(),
+            blocks: self.get_blocks_iter(disk)?,
+        })
+    }
+
     /// Read file content
     pub fn read(&self, disk: &Box<dyn Disk>) -> Result<Vec<u8>, Error> {
         let mut buffer: Vec<u8> = Vec::new();
-        for block in self.read_blocks_iter(disk)? {
-            buffer.extend(&block?);
-        }
+        self.read_with(disk, |block| {
+            buffer.extend(block);
+            Ok(())
+        })?;
         Ok(buffer)
     }
 
+    /// Feed file content to `f` one block at a time, holes read as zeros
+    pub fn read_with(
+        &self,
+        disk: &Box<dyn Disk>,
+        mut f: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut remaining = self.get_size();
+        for block in self.get_blocks_iter(disk)? {
+            if remaining == 0 {
+                break;
+            }
+            let len = min(remaining, self.block_size) as usize;
+            match block? {
+                0 => f(&vec![0u8; len])?,
+                block => f(&disk.read_at(&Offset::new(self.block_size, block), self.block_size)?[..len])?,
+            }
+            remaining -= len as u64;
+        }
+        Ok(())
+    }
+
     /// Block numbers iterator
     pub fn get_blocks_iter<'a>(
         &'a self,
         disk: &'a Box<dyn Disk>,
-    ) -> Result<ReadBlockNum<'a>, Error> {
-        Ok(ReadBlockNum::new(
-            disk,
-            &self.ext2_inode.i_block,
-            self.block_size,
-            self.data_blocks_count,
-        ))
+    ) -> Result<Box<dyn BlockMapping + 'a>, Error> {
+        match self.is_extent_mapped() {
+            true => Ok(Box::new(ExtentMapping::new(self.extent_map(disk)?, self.data_blocks_count))),
+            false => Ok(Box::new(ReadBlockNum::new(
+                disk,
+                &self.ext2_inode.i_block,
+                self.block_size,
+                self.data_blocks_count,
+            ))),
+        }
     }
 
     /// Resolve a child by name - return the child's inode
@@ -222,20 +363,42 @@ impl Ext2Inode {
         block_groups: &Ext2BlockGroups,
         name: &str,
     ) -> Option<Ext2Inode> {
-        match self.read_dir(disk, fs, name) {
-            Ok(entries) => match entries.get(name) {
-                Some(dir_entry) => Some(
-                    Ext2Inode::new(
-                        disk,
-                        self.inode_size,
-                        self.block_size,
-                        block_groups,
-                        dir_entry.inode_num(),
-                    )
-                        .ok()?,
-                ),
-                None => None,
-            },
+        let inode_num = self.find_entry(disk, name.as_bytes()).ok()??;
+        Ext2Inode::new(
+            disk,
+            self.inode_size,
+            self.block_size,
+            block_groups,
+            inode_num,
+            fs.super_block.csum_seed(),
+        )
+        .ok()
+    }
+
+    /// Inode number of the entry called `name`, names match only when all
+    /// `name_len` bytes are equal, they need not be UTF-8
+    pub fn find_entry(&self, disk: &Box<dyn Disk>, name: &[u8]) -> Result<Option<u64>, Error> {
+        Ok(self
+            .read_dir_raw(disk)?
+            .into_iter()
+            .find(|(_, _, entry_name)| entry_name.as_slice() == name)
+            .map(|(inode_num, _, _)| inode_num))
+    }
+
+    /// Resolve a child by inode number - return the child's name
+    pub fn get_child_by_inode(
+        &self,
+        disk: &Box<dyn Disk>,
+        fs: &Ext2Filesystem,
+        inode_num: u64,
+    ) -> Option<String> {
+        match self.read_dir(disk, fs, "") {
+            Ok(entries) => entries
+                .into_iter()
+                .find(|(name, dir_entry)| {
+                    dir_entry.inode_num() == inode_num && name != "." && name != ".."
+                })
+                .map(|(name, _)| name),
             Err(_) => None,
         }
     }
@@ -245,7 +408,10 @@ impl Ext2Inode {
         if !self.metadata().is_symlink() {
             return Err(Error::InvalidData("is not a symbolic link".to_string()));
         }
-        if self.size <= I_BLOCKS_SIZE as u64 {
+        if self.ext2_inode.is_fast_symlink(self.block_size) {
+            if self.size > I_BLOCKS_SIZE as u64 {
+                return Err(Error::InvalidData(format!("inode {} symlink too long", self.inode_num)));
+            }
             let buffer: [u8; I_BLOCKS_SIZE] = unsafe { mem::transmute(self.ext2_inode.i_block) };
             let target = &buffer[0..self.size as usize];
             match str::from_utf8(target) {
@@ -275,46 +441,274 @@ impl Ext2Inode {
                 let mut offset: usize = 0;
                 // Iterate over block directory entries
                 while offset < self.block_size as usize {
-                    let (mut dir_entry, rec_len) = Ext2DirEntry::new(&buffer, offset);
-                    dir_entry.get_inode(fs)?;
+                    let (mut dir_entry, rec_len) = Ext2DirEntry::new(&buffer, offset, fs.super_block.has_filetype())?;
                     offset += rec_len;
-                    entries.insert(dir_entry.file_name(), dir_entry);
+                    // Unused entry
+                    if dir_entry.inode_num() == 0 {
+                        continue;
+                    }
+                    dir_entry.get_inode(fs)?;
+                    // The key owns the name, the stored entry is left without one
+                    entries.insert(dir_entry.take_name(), dir_entry);
                 }
             }
             Ok(entries)
         }
     }
+
+    /// Read directory entries as stored on disk - (inode number, file type, name bytes).
+    /// Unused entries (inode 0) are skipped, names are not validated and no inode is read.
+    pub fn read_dir_raw(&self, disk: &Box<dyn Disk>) -> Result<Vec<(u64, u8, Vec<u8>)>, Error> {
+        if !self.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!(
+                "inode {} Not a directory",
+                self.inode_num
+            )));
+        }
+        let size = mem::size_of::<Ext2DirEntryStruct>();
+        let mut entries = Vec::new();
+        for buffer in self.read_blocks_iter(disk)? {
+            let buffer = buffer?;
+            let mut offset: usize = 0;
+            while offset + size <= buffer.len() {
+                let entry = buffer[offset..].as_ref().read_struct::<Ext2DirEntryStruct>()?;
+                let name_end = offset + size + entry.name_len as usize;
+                if entry.rec_len == 0 || name_end > buffer.len() {
+                    return Err(Error::InvalidData(format!(
+                        "inode {} corrupt directory entry",
+                        self.inode_num
+                    )));
+                }
+                if entry.inode_num != 0 {
+                    entries.push((
+                        entry.inode_num as u64,
+                        entry.file_type,
+                        buffer[offset + size..name_end].to_vec(),
+                    ));
+                }
+                offset += entry.rec_len as usize;
+            }
+        }
+        Ok(entries)
+    }
+
     pub fn find_last_dir_entry(
         &self,
         disk: &Box<(dyn Disk + 'static)>,
+        name_len: usize,
+        has_filetype: bool,
     ) -> Result<(u64, usize), Error> {
         if !self.metadata().is_dir() {
-            Err(Error::InvalidInput(format!(
+            return Err(Error::InvalidInput(format!(
                 "inode {} Not a directory",
                 self.inode_num
-            )))
-        } else {
-            let mut block_num = 0usize;
-            let mut offset_block = 0usize;
-            let size = mem::size_of::<Ext2DirEntryStruct>();
-            'all_block: for buffer in self.read_blocks_iter(disk)? {
-                let buffer = buffer?;
-                let mut offset: usize = 0;
-                // Iterate over block directory entries
-                while offset < self.block_size as usize {
-                    let mut buf = &buffer[offset..offset + size];
-                    let entry = buf.read_struct::<Ext2DirEntryStruct>().unwrap();
-                    let entry_size = align_up!(entry.name_len as usize + size, 4);
-                    if entry_size < entry.rec_len.into() || entry.inode_num == 0 {
-                        offset_block = offset;
-                        break 'all_block;
-                    }
-                    offset += entry.rec_len as usize;
+            )));
+        }
+        // Room a new entry needs, rec_len always stays a multiple of 4
+        let need = Ext2DirEntryStruct::record_len(name_len);
+        let size = mem::size_of::<Ext2DirEntryStruct>();
+        for block_num in self.get_blocks(disk)? {
+            let buffer = disk.read_at(&Offset::new(self.block_size, block_num), self.block_size)?;
+            let mut offset: usize = 0;
+            // Iterate over block directory entries
+            while offset + size <= self.block_size as usize {
+                let mut buf = &buffer[offset..offset + size];
+                let entry = buf.read_struct::<Ext2DirEntryStruct>()?;
+                if entry.rec_len == 0 || entry.rec_len % 4 != 0 {
+                    return Err(Error::InvalidData(format!(
+                        "block {} entry at {} has rec_len {}",
+                        block_num, offset, entry.rec_len
+                    )));
+                }
+                let used = match entry.inode_num {
+                    0 => 0,
+                    _ => Ext2DirEntryStruct::record_len(entry.name_len(has_filetype)),
+                };
+                if entry.rec_len as usize >= used + need {
+                    return Ok((block_num, offset));
                 }
-                block_num += 1;
+                offset += entry.rec_len as usize;
+            }
+        }
+        Err(Error::IOError(format!("directory inode {} is full", self.inode_num)))
+    }
+
+    /// Slot in i_block for a logical block and the index at each indirection level below it
+    fn block_path(&self, logical_index: u64) -> Result<(usize, Vec<u64>), Error> {
+        if self.is_extent_mapped() {
+            return Err(Error::InvalidData("extent-mapped inodes are read only".to_string()));
+        }
+        let per_block = pointers_per_block(self.block_size);
+        let mut i = logical_index;
+        if i < EXT2_NDIR_BLOCKS as u64 {
+            return Ok((i as usize, Vec::new()));
+        }
+        i -= EXT2_NDIR_BLOCKS as u64;
+        if i < per_block {
+            return Ok((EXT2_IND_BLOCK, Vec::from([i])));
+        }
+        i -= per_block;
+        if i < per_block * per_block {
+            return Ok((EXT2_DOUBLY_IND_BLOCK, Vec::from([i / per_block, i % per_block])));
+        }
+        i -= per_block * per_block;
+        if i >= per_block * per_block * per_block {
+            return Err(Error::InvalidInput(format!(
+                "logical block {} out of range",
+                logical_index
+            )));
+        }
+        Ok((
+            EXT2_TRIPLY_IND_BLOCK,
+            Vec::from([i / per_block / per_block, i / per_block % per_block, i % per_block]),
+        ))
+    }
+
+    /// Physical block behind logical block `logical_index`, 0 for a hole
+    pub fn get_block_ptr(&self, disk: &Box<dyn Disk>, logical_index: u64) -> Result<u64, Error> {
+        if self.is_extent_mapped() {
+            return self.get_blocks_iter(disk)?.physical(logical_index);
+        }
+        let (slot, indexes) = self.block_path(logical_index)?;
+        let mut block = self.ext2_inode.i_block[slot] as u64;
+        for index in indexes {
+            if block == 0 {
+                break;
+            }
+            let offset = Offset::new_offset(self.block_size, block, index * mem::size_of::<u32>() as u64);
+            let bytes: [u8; 4] = disk.read_at(&offset, 4)?.try_into().expect("incorrect length");
+            block = u32::from_le_bytes(bytes) as u64;
+        }
+        Ok(block)
+    }
+
+    /// Point logical block `logical_index` at `new_block` and persist the inode.
+    /// Missing indirect blocks on the way are allocated and zeroed.
+    pub fn set_block_ptr(
+        &mut self,
+        fs: &mut Ext2Filesystem,
+        logical_index: u64,
+        new_block: u32,
+    ) -> Result<(), Error> {
+        let (slot, indexes) = self.block_path(logical_index)?;
+        if indexes.is_empty() {
+            self.ext2_inode.i_block[slot] = new_block;
+            return self.write(&fs.disk, &fs.block_groups);
+        }
+        if self.ext2_inode.i_block[slot] == 0 {
+            self.ext2_inode.i_block[slot] = self.alloc_table_block(fs)?;
+        }
+        let mut table = self.ext2_inode.i_block[slot] as u64;
+        let last = indexes.len() - 1;
+        for (level, index) in indexes.into_iter().enumerate() {
+            let offset = index * mem::size_of::<u32>() as u64;
+            if level == last {
+                fs.write_block(table, offset, &new_block.to_le_bytes())?;
+                break;
+            }
+            let buffer = fs.read_block(table)?;
+            let bytes: [u8; 4] = buffer[offset as usize..offset as usize + 4]
+                .try_into()
+                .expect("incorrect length");
+            let mut next = u32::from_le_bytes(bytes);
+            if next == 0 {
+                next = self.alloc_table_block(fs)?;
+                fs.write_block(table, offset, &next.to_le_bytes())?;
+            }
+            table = next as u64;
+        }
+        self.write(&fs.disk, &fs.block_groups)
+    }
+
+    /// Free every block at or past logical block `from_logical`, data blocks and
+    /// the index blocks left empty, zeroing their pointers, then persist the inode.
+    pub fn free_indirect_blocks(&mut self, fs: &mut Ext2Filesystem, from_logical: u64) -> Result<(), Error> {
+        if self.is_extent_mapped() {
+            return Err(Error::InvalidData("extent-mapped inodes are read only".to_string()));
+        }
+        let per_block = pointers_per_block(self.block_size);
+        let mut freed = 0u64;
+        for i in from_logical..EXT2_NDIR_BLOCKS as u64 {
+            let block = self.ext2_inode.i_block[i as usize];
+            if block != 0 {
+                fs.free_block(block as u64)?;
+                self.ext2_inode.i_block[i as usize] = 0;
+                freed += 1;
+            }
+        }
+        // First logical block each top level table maps
+        let mut base = EXT2_NDIR_BLOCKS as u64;
+        for (level, slot) in [EXT2_IND_BLOCK, EXT2_DOUBLY_IND_BLOCK, EXT2_TRIPLY_IND_BLOCK]
+            .into_iter()
+            .enumerate()
+        {
+            let level = level as u32 + 1;
+            let table = self.ext2_inode.i_block[slot] as u64;
+            if table != 0 && Self::free_table(fs, table, level, base, from_logical, &mut freed)? {
+                fs.free_block(table)?;
+                self.ext2_inode.i_block[slot] = 0;
+                freed += 1;
             }
-            Ok((self.blocks()[block_num] as u64, offset_block))
+            base += per_block.pow(level);
         }
+        let i_blocks = freed * (self.block_size / 512);
+        self.ext2_inode.i_blocks = (self.ext2_inode.i_blocks as u64).saturating_sub(i_blocks) as u32;
+        self.data_blocks_count = self.data_blocks_count.min(from_logical);
+        self.write(&fs.disk, &fs.block_groups)
+    }
+
+    /// Free the part of a `level` deep table mapping logical blocks at or past `from`,
+    /// `base` is the first logical block it maps. Returns whether the table is now empty.
+    fn free_table(
+        fs: &mut Ext2Filesystem,
+        table: u64,
+        level: u32,
+        base: u64,
+        from: u64,
+        freed: &mut u64,
+    ) -> Result<bool, Error> {
+        let ptr_size = mem::size_of::<u32>();
+        let mut buffer = fs.read_block(table)?;
+        let per_block = fs.pointers_per_block();
+        // Logical blocks mapped by one entry
+        let span = per_block.pow(level - 1);
+        let mut changed = false;
+        let mut empty = true;
+        for i in 0..per_block {
+            let at = i as usize * ptr_size;
+            let block = u32::from_le_bytes(buffer[at..at + ptr_size].try_into().expect("incorrect length")) as u64;
+            if block == 0 {
+                continue;
+            }
+            let start = base + i * span;
+            let free = match start + span <= from {
+                true => false,
+                false if level == 1 => true,
+                false => Self::free_table(fs, block, level - 1, start, from, freed)?,
+            };
+            if free {
+                fs.free_block(block)?;
+                buffer[at..at + ptr_size].fill(0);
+                *freed += 1;
+                changed = true;
+            } else {
+                empty = false;
+            }
+        }
+        if changed && !empty {
+            fs.write_block(table, 0, &buffer)?;
+        }
+        Ok(empty)
+    }
+
+    /// Allocate a zeroed block for an indirect block table
+    fn alloc_table_block(&mut self, fs: &mut Ext2Filesystem) -> Result<u32, Error> {
+        let block_num = match fs.alloc_block() {
+            Some(block_num) => block_num,
+            None => return Err(Error::NoSpace(format!("no block for an index table of inode {}", self.inode_num))),
+        };
+        self.ext2_inode.i_blocks += (self.block_size / 512) as u32;
+        Ok(block_num)
     }
 
     /// Block numbers
@@ -325,6 +719,15 @@ impl Ext2Inode {
         }
     }
 
+    /// Logical to physical block mapping, holes map to block 0
+    pub fn block_map(&self, disk: &Box<dyn Disk>) -> Result<Vec<(u64, u64)>, Error> {
+        let mut map = Vec::new();
+        for (i, block) in self.get_blocks_iter(disk)?.enumerate() {
+            map.push((i as u64, block?));
+        }
+        Ok(map)
+    }
+
     /// Block size in bytes
     pub fn get_block_size(&self) -> u64 {
         self.block_size
@@ -335,8 +738,51 @@ impl Ext2Inode {
         self.size
     }
 
+    /// Directory indexed with an htree. The index hides in entries a linear scan
+    /// skips, so reading works, but new entries would bypass the index.
+    pub fn is_indexed(&self) -> bool {
+        let stat = self.metadata();
+        stat.is_dir() && stat.flags().contains(FileFlags::HASH_DIR)
+    }
+
+    /// ext4 inode whose i_block holds an extent tree instead of block pointers
+    pub fn is_extent_mapped(&self) -> bool {
+        self.metadata().flags().contains(FileFlags::EXT4_EXTENTS)
+    }
+
+    /// Runs of the extent tree in i_block, in logical order
+    pub fn extent_map(&self, disk: &Box<dyn Disk>) -> Result<Vec<ExtentRun>, Error> {
+        let i_block: Vec<u8> = self.ext2_inode.i_block.iter().flat_map(|b| b.to_le_bytes()).collect();
+        extent::extent_map(disk.as_ref(), &i_block, self.block_size)
+    }
+
+    /// Fields of large inodes, all zero for 128 byte inodes
+    pub fn extra(&self) -> Ext2InodeExtra {
+        self.extra
+    }
+
+    /// Device number of a character or block device in the Linux `dev_t` encoding, 0 otherwise.
+    /// ext2 keeps the old 8:8 form in i_block[0] and the newer 12:20 form in i_block[1].
+    fn rdev(&self) -> u64 {
+        let mode = Mode::from_bits_truncate(self.ext2_inode.i_mode);
+        if !mode.is_char_device() && !mode.is_block_device() {
+            return 0;
+        }
+        let (old, new) = (self.ext2_inode.i_block[0], self.ext2_inode.i_block[1]);
+        let (major, minor) = match old {
+            0 => ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00)),
+            _ => ((old >> 8) & 0xff, old & 0xff),
+        };
+        let (major, minor) = (major as u64, minor as u64);
+        ((major & 0xfff) << 8) | ((major & !0xfff) << 32) | (minor & 0xff) | ((minor & !0xff) << 12)
+    }
+
     /// Given a path, query the file system to get information about a file, directory, etc.
     pub fn metadata(&self) -> Stat {
+        let raw = &self.ext2_inode;
+        let (atime, atime_nsec) = Ext2InodeExtra::decode_time(raw.i_atime, self.extra.atime_extra);
+        let (mtime, mtime_nsec) = Ext2InodeExtra::decode_time(raw.i_mtime, self.extra.mtime_extra);
+        let (ctime, ctime_nsec) = Ext2InodeExtra::decode_time(raw.i_ctime, self.extra.ctime_extra);
         Stat {
             dev: 0,
             ino: self.inode_num,
@@ -344,14 +790,14 @@ impl Ext2Inode {
             nlink: self.ext2_inode.i_links_count as u64,
             uid: self.ext2_inode.i_uid as u32,
             gid: self.ext2_inode.i_gid as u32,
-            rdev: 0,
+            rdev: self.rdev(),
             size: self.size,
-            atime: self.ext2_inode.i_atime as i64,
-            atime_nsec: self.ext2_inode.i_atime as i64 * 1_000_000,
-            mtime: self.ext2_inode.i_mtime as i64,
-            mtime_nsec: self.ext2_inode.i_mtime as i64 * 1_000_000,
-            ctime: self.ext2_inode.i_ctime as i64,
-            ctime_nsec: self.ext2_inode.i_ctime as i64 * 1_000_000,
+            atime,
+            atime_nsec,
+            mtime,
+            mtime_nsec,
+            ctime,
+            ctime_nsec,
             blksize: self.block_size,
             blocks: self.ext2_inode.i_blocks as u64,
             flags: FileFlags::from_bits_truncate(self.ext2_inode.i_flags),
@@ -359,6 +805,13 @@ impl Ext2Inode {
     }
 }
 
+/// Logical to physical mapping of an inode's blocks, iterating yields every
+/// block up to the end of the file in order, holes as 0
+pub trait BlockMapping: Iterator<Item = Result<u64, Error>> {
+    /// Physical block of logical block `i`
+    fn physical(&mut self, i: u64) -> Result<u64, Error>;
+}
+
 pub struct ReadBlockNum<'a> {
     blocks_per_block: u64,
     // number of block number (each block number is sizeof u32) in a block
@@ -379,7 +832,7 @@ impl ReadBlockNum<'_> {
         block_size: u64,
         data_blocks_count: u64,
     ) -> ReadBlockNum<'a> {
-        let blocks_per_block = block_size / mem::size_of::<u32>() as u64;
+        let blocks_per_block = pointers_per_block(block_size);
         ReadBlockNum {
             blocks_per_block,
             i_block,
@@ -402,6 +855,10 @@ impl ReadBlockNum<'_> {
 
     /// Get singly indirect block
     fn get_indirect_block(&mut self, i: u64, indirect_block_num: u64) -> Result<u64, Error> {
+        // A missing table means everything below it is a hole
+        if indirect_block_num == 0 {
+            return Ok(0);
+        }
         let offset = Offset::new(self.block_size, indirect_block_num);
         let indirect_blocks = self.disk.read_at(&offset, self.block_size)?;
         let addr: usize = i as usize * mem::size_of::<u32>();
@@ -448,21 +905,27 @@ impl Iterator for ReadBlockNum<'_> {
             None
         } else {
             self.curr = self.curr + 1;
-            if i < self.first_indirect_block {
-                Some(self.get_direct_block(i))
-            } else if i < self.first_doubly_indirect_block {
-                let i = i - self.first_indirect_block;
-                let indirect_block_num = self.i_block[EXT2_IND_BLOCK] as u64;
-                Some(self.get_indirect_block(i, indirect_block_num))
-            } else if i < self.first_triply_indirect_block {
-                let i = i - self.first_doubly_indirect_block;
-                let doubly_indirect_block_num = self.i_block[EXT2_DOUBLY_IND_BLOCK] as u64;
-                Some(self.get_doubly_indirect_block(i, doubly_indirect_block_num))
-            } else {
-                let i = i - self.first_triply_indirect_block;
-                let triply_indirect_block_num = self.i_block[EXT2_TRIPLY_IND_BLOCK] as u64;
-                Some(self.get_triply_indirect_block(i, triply_indirect_block_num))
-            }
+            Some(self.physical(i))
+        }
+    }
+}
+
+impl BlockMapping for ReadBlockNum<'_> {
+    fn physical(&mut self, i: u64) -> Result<u64, Error> {
+        if i < self.first_indirect_block {
+            self.get_direct_block(i)
+        } else if i < self.first_doubly_indirect_block {
+            let i = i - self.first_indirect_block;
+            let indirect_block_num = self.i_block[EXT2_IND_BLOCK] as u64;
+            self.get_indirect_block(i, indirect_block_num)
+        } else if i < self.first_triply_indirect_block {
+            let i = i - self.first_doubly_indirect_block;
+            let doubly_indirect_block_num = self.i_block[EXT2_DOUBLY_IND_BLOCK] as u64;
+            self.get_doubly_indirect_block(i, doubly_indirect_block_num)
+        } else {
+            let i = i - self.first_triply_indirect_block;
+            let triply_indirect_block_num = self.i_block[EXT2_TRIPLY_IND_BLOCK] as u64;
+            self.get_triply_indirect_block(i, triply_indirect_block_num)
         }
     }
 }
@@ -470,7 +933,7 @@ impl Iterator for ReadBlockNum<'_> {
 pub struct ReadBlock<'a> {
     disk: &'a Box<dyn Disk>,
     block_size: u64,
-    blocks: ReadBlockNum<'a>,
+    blocks: Box<dyn BlockMapping + 'a>,
 }
 
 impl ReadBlock<'_> {
@@ -499,3 +962,39 @@ impl Iterator for ReadBlock<'_> {
         }
     }
 }
+
+/// One block of file content from `read_blocks_sparse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockData {
+    // Unallocated, reads as this many zeros
+    Hole(usize),
+    Data(Vec<u8>),
+}
+
+pub struct ReadBlockData<'a> {
+    disk: &'a Box<dyn Disk>,
+    block_size: u64,
+    // Bytes of the file not yet yielded
+    remaining: u64,
+    blocks: Box<dyn BlockMapping + 'a>,
+}
+
+impl Iterator for ReadBlockData<'_> {
+    type Item = Result<BlockData, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let len = min(self.remaining, self.block_size);
+        self.remaining -= len;
+        let block = match self.blocks.next()? {
+            Ok(block) => block,
+            Err(e) => return Some(Err(e)),
+        };
+        if block == 0 {
+            return Some(Ok(BlockData::Hole(len as usize)));
+        }
+        Some(self.disk.read_at(&Offset::new(self.block_size, block), len).map(BlockData::Data))
+    }
+}
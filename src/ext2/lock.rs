@@ -0,0 +1,49 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use core::cell::RefCell;
+
+use crate::fs::error::Error;
+
+#[derive(Debug, Copy, Clone)]
+enum Lock {
+    // Number of holders
+    Shared(u32),
+    Exclusive,
+}
+
+/// In-memory advisory locks keyed by inode number, flock style.
+/// Nothing on disk changes and nothing forces a caller to take them.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    locks: RefCell<BTreeMap<u64, Lock>>,
+}
+
+impl LockTable {
+    /// Take a shared or exclusive lock, fails right away instead of waiting
+    pub fn lock(&self, inode_num: u64, exclusive: bool) -> Result<(), Error> {
+        let mut locks = self.locks.borrow_mut();
+        let lock = match (locks.get(&inode_num), exclusive) {
+            (None, true) => Lock::Exclusive,
+            (None, false) => Lock::Shared(1),
+            (Some(Lock::Shared(n)), false) => Lock::Shared(n + 1),
+            _ => return Err(Error::PermissionDenied(format!("inode {} is locked", inode_num))),
+        };
+        locks.insert(inode_num, lock);
+        Ok(())
+    }
+
+    /// Release one lock on the inode
+    pub fn unlock(&self, inode_num: u64) -> Result<(), Error> {
+        let mut locks = self.locks.borrow_mut();
+        match locks.get(&inode_num).copied() {
+            Some(Lock::Shared(n)) if n > 1 => {
+                locks.insert(inode_num, Lock::Shared(n - 1));
+            }
+            Some(_) => {
+                locks.remove(&inode_num);
+            }
+            None => return Err(Error::InvalidInput(format!("inode {} is not locked", inode_num))),
+        }
+        Ok(())
+    }
+}
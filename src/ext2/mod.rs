@@ -2,75 +2,402 @@
 
 use alloc::{format, vec};
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ops::Range;
 use core::slice::SlicePattern;
 use core::str;
 
-use crate::{align_up, int_get, to_slice};
-use crate::ext2::dir::{Ext2DirEntry, Ext2DirEntryStruct};
-use crate::ext2::group::{EXT2_GROUP_DESC_SIZE, Ext2BlockGroups, Ext2GroupDesc};
+use crate::{int_get, to_slice};
+use crate::ext2::bitmap::BitMap;
+use crate::ext2::csum::crc32c;
+use crate::ext2::dir::{DirHandle, Ext2DirEntry, Ext2DirEntryStruct, ListEntry};
+use crate::ext2::group::{EXT2_GROUP_DESC_SIZE, Ext2BlockGroups, Ext2GroupDesc, GroupStat};
+use crate::ext2::info::FsInfo;
 use crate::ext2::inode::{Ext2Inode, Ext2InodeStruct};
-use crate::ext2::superblock::Ext2SuperBlock;
-use crate::fs::{base_dir, base_file};
-use crate::fs::disk::{Disk, Offset};
+use crate::ext2::lock::LockTable;
+use crate::ext2::scan::ScannedBitmaps;
+use crate::ext2::superblock::{Ext2SuperBlock, FeatureRoCompat};
+use crate::fs::{base_dir, base_file, wildcard_match};
+use crate::fs::cache::WriteCache;
+use crate::fs::disk::{Disk, Offset, ReadOnlyDisk};
 use crate::fs::error::Error;
 use crate::fs::file::FsFile;
 use crate::fs::io::CoreRead;
-use crate::fs::stat::Stat;
+use crate::fs::stat::{Mode, Stat};
+use crate::ext2::walk::WalkIter;
 
+pub mod bitmap;
+pub mod csum;
 pub mod dir;
+pub mod extent;
 pub mod group;
+pub mod info;
 pub mod inode;
+pub mod lock;
+pub mod scan;
 pub mod superblock;
+pub mod walk;
+#[cfg(test)]
+mod tests;
 
 const EXT2_ROOT_INO: u64 = 2;
+// s_state bit set while the filesystem is cleanly unmounted
+const EXT2_VALID_FS: u16 = 1;
+const EXT2_ERROR_FS: u16 = 2;
+// Symlinks followed in one lookup before giving up, the same limit as Linux
+const MAX_SYMLINK_HOPS: u32 = 40;
+// Longest name a directory entry holds
+const EXT2_NAME_LEN: usize = 255;
 
 pub struct Ext2Filesystem {
     pub disk: Box<dyn Disk>,
     super_block: Ext2SuperBlock,
     pub block_groups: Ext2BlockGroups,
+    // In-memory bitmaps used instead of the on-disk ones, see MountOptions
+    bitmaps: Option<RefCell<ScannedBitmaps>>,
+    // Block bitmap of the group allocated from last, written back on a group change
+    // or sync instead of after every allocation
+    block_bitmap_cache: RefCell<Option<CachedBitmap>>,
+    locks: LockTable,
+    max_path_len: usize,
+    read_only: bool,
+    // Writer identity, only root and s_def_resuid may allocate the reserved blocks
+    uid: u32,
+}
+
+struct CachedBitmap {
+    group: u64,
+    bitmap: BitMap,
+    dirty: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MountOptions {
+    /// When false the block and inode bitmaps are rebuilt from a scan of every
+    /// inode at mount time and kept in memory, the on-disk bitmaps are not used
+    /// for allocation and the free counts are recomputed from the scan.
+    /// Meant for recovering damaged images.
+    pub trust_bitmaps: bool,
+    /// Longest path accepted in bytes, 4096 like Linux by default
+    pub max_path_len: usize,
+    /// Refuse every write. The superblock state and mount count are left alone,
+    /// so images on write-protected media mount too. Always on with metadata_csum.
+    pub read_only: bool,
+    /// User the writes are made as, allocation stops at the `s_r_blocks_count`
+    /// reserve unless this is root or `s_def_resuid`. Root by default.
+    pub uid: u32,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions { trust_bitmaps: true, max_path_len: 4096, read_only: false, uid: 0 }
+    }
 }
 
 impl Ext2Filesystem {
     pub fn mount(disk: Box<dyn Disk>) -> Result<Ext2Filesystem, Error> {
+        Self::mount_with_options(disk, MountOptions::default())
+    }
+
+    pub fn mount_with_options(disk: Box<dyn Disk>, options: MountOptions) -> Result<Ext2Filesystem, Error> {
         let super_block = Ext2SuperBlock::new(disk.as_ref())?;
         let block_groups = Ext2BlockGroups::new(&super_block.clone())?;
-        Ok(Ext2Filesystem {
+        Self::mount_parts(disk, super_block, block_groups, options)
+    }
+
+    fn mount_parts(
+        disk: Box<dyn Disk>,
+        super_block: Ext2SuperBlock,
+        block_groups: Ext2BlockGroups,
+        options: MountOptions,
+    ) -> Result<Ext2Filesystem, Error> {
+        block_groups.validate(&disk)?;
+        // Nothing here recomputes metadata checksums, writing would leave them stale
+        let read_only = options.read_only || super_block.has_metadata_csum();
+        let disk: Box<dyn Disk> = match read_only {
+            true => Box::new(ReadOnlyDisk::new(disk)),
+            false => disk,
+        };
+        let mut fs = Ext2Filesystem {
             disk,
             super_block,
             block_groups,
-        })
+            bitmaps: None,
+            block_bitmap_cache: RefCell::new(None),
+            locks: LockTable::default(),
+            max_path_len: options.max_path_len,
+            read_only,
+            uid: options.uid,
+        };
+        if !options.trust_bitmaps {
+            fs.bitmaps = Some(RefCell::new(ScannedBitmaps::scan(&fs)?));
+            fs.apply_scanned_counts()?;
+        }
+        if fs.read_only {
+            return Ok(fs);
+        }
+        // Not clean until unmount, other tools then know to check the image
+        fs.super_block.s_state &= !EXT2_VALID_FS;
+        fs.super_block.s_mnt_count = fs.super_block.s_mnt_count.wrapping_add(1);
+        fs.super_block.write(fs.disk.as_ref())?;
+        Ok(fs)
+    }
+
+    /// Superblock copy at the start of group `group_num`. The primary may be unreadable,
+    /// so every block size is tried with the default of 8 * block size blocks per group.
+    pub fn read_backup_superblock(disk: &dyn Disk, group_num: u64) -> Result<Ext2SuperBlock, Error> {
+        for log_block_size in 0..7u32 {
+            let block_size = 1024u64 << log_block_size;
+            let first = (block_size == 1024) as u64;
+            let offset = match group_num {
+                0 => Ext2SuperBlock::SUPER_BLOCK_SIZE * Ext2SuperBlock::SUPER_BLOCK,
+                _ => (first + group_num * block_size * 8) * block_size,
+            };
+            let Ok(sb) = Ext2SuperBlock::new_at(disk, offset) else {
+                continue;
+            };
+            // Dynamic revisions record which group the copy belongs to
+            if sb.s_log_block_size == log_block_size
+                && sb.s_blocks_per_group as u64 == block_size * 8
+                && (sb.s_rev_level == 0 || sb.s_block_group_nr as u64 == group_num)
+            {
+                return Ok(sb);
+            }
+        }
+        Err(Error::NotFound(format!("no superblock backup in group {}", group_num)))
+    }
+
+    /// Recover an image with a damaged primary superblock. The backup superblock and
+    /// group descriptors of `group_num` are copied over the primary ones, then the image
+    /// is mounted. Free counts are as old as the backup, a `trust_bitmaps: false` mount
+    /// or fsck brings them up to date.
+    pub fn mount_from_backup(disk: Box<dyn Disk>, group_num: u64) -> Result<Ext2Filesystem, Error> {
+        Self::mount_from_backup_with_options(disk, group_num, MountOptions::default())
+    }
+
+    /// Like `mount_from_backup`. Read-only mounts, and metadata_csum images whose checksums
+    /// would go stale, copy nothing: the backups are used in place and the image is not written.
+    pub fn mount_from_backup_with_options(
+        disk: Box<dyn Disk>,
+        group_num: u64,
+        options: MountOptions,
+    ) -> Result<Ext2Filesystem, Error> {
+        let mut super_block = Self::read_backup_superblock(disk.as_ref(), group_num)?;
+        let block_size = super_block.get_block_size();
+        let first = super_block.s_first_data_block as u64;
+        let backup_start = first + group_num * super_block.s_blocks_per_group as u64;
+        if options.read_only || super_block.has_metadata_csum() {
+            let block_groups = Ext2BlockGroups::new(&super_block)?.with_desc_block(backup_start + 1);
+            return Self::mount_parts(disk, super_block, block_groups, options);
+        }
+        let desc_bytes = super_block.get_groups_count() as u64 * EXT2_GROUP_DESC_SIZE as u64;
+        let descs = disk.read_at(&Offset::new(block_size, backup_start + 1), desc_bytes.div_ceil(block_size) * block_size)?;
+        disk.write_all_at(super_block.group_desc_block() * block_size, &descs)?;
+        super_block.s_block_group_nr = 0;
+        super_block.write(disk.as_ref())?;
+        Self::mount_with_options(disk, options)
+    }
+
+    /// Mount behind a `WriteCache` so repeated metadata updates are coalesced
+    pub fn mount_cached(disk: Box<dyn Disk>) -> Result<Ext2Filesystem, Error> {
+        let block_size = Ext2SuperBlock::new(disk.as_ref())?.get_block_size();
+        Self::mount(Box::new(WriteCache::new(disk, block_size)))
     }
 
     /// Get inode by number
     pub fn read_inode(&self, inode_num: u64) -> Result<Ext2Inode, Error> {
+        if inode_num < 1 || inode_num > self.super_block.s_inodes_count as u64 {
+            return Err(Error::InvalidData(format!(
+                "inode {} out of range 1..={}",
+                inode_num, self.super_block.s_inodes_count
+            )));
+        }
         Ext2Inode::new(
             &self.disk,
             self.super_block.s_inode_size as u64,
             self.super_block.get_block_size(),
             &self.block_groups,
             inode_num,
+            self.super_block.csum_seed(),
         )
     }
 
+    /// Inode number of the root directory
+    pub fn root_inode_num(&self) -> u64 {
+        EXT2_ROOT_INO
+    }
+
+    /// Whether the image carries an ext3 journal, it is never replayed
+    /// so the contents may be stale if the image was not cleanly unmounted
+    pub fn has_journal(&self) -> bool {
+        self.super_block.has_journal()
+    }
+
+    /// Inode number of the journal, None without a journal
+    pub fn journal_inode(&self) -> Option<u64> {
+        match self.has_journal() {
+            true => Some(self.super_block.s_journal_inum as u64),
+            false => None,
+        }
+    }
+
+    /// Take an advisory lock on an inode, shared locks stack, an exclusive one
+    /// conflicts with any other. Only coordinates handles within this mount.
+    pub fn lock(&self, inode_num: u64, exclusive: bool) -> Result<(), Error> {
+        self.locks.lock(inode_num, exclusive)
+    }
+
+    /// Release an advisory lock taken with `lock`
+    pub fn unlock(&self, inode_num: u64) -> Result<(), Error> {
+        self.locks.unlock(inode_num)
+    }
+
+    /// Resolve a relative path starting from the directory inode `inode_num`
+    pub fn open_at(&self, inode_num: u64, relative_path: &str) -> Result<Ext2Inode, Error> {
+        let inode = self.read_inode(inode_num)?;
+        if !inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("inode {} Not a directory", inode_num)));
+        }
+        let (inode, _) = self.resolve_relative(relative_path, inode, false)?;
+        Ok(inode)
+    }
+
+    /// Build the absolute path of an inode.
+    /// Directories walk up through `..`, other inodes are searched from the root,
+    /// a hard-linked inode gets the first path found. A `..` chain or directory tree
+    /// that loops back on itself is an error instead of a hang.
+    pub fn path_of(&self, inode_num: u64) -> Result<String, Error> {
+        let inode = self.read_inode(inode_num)?;
+        if inode_num == EXT2_ROOT_INO {
+            return Ok("/".to_string());
+        }
+        if inode.metadata().is_dir() {
+            let mut parts = Vec::new();
+            let mut current = inode;
+            let mut visited = BTreeSet::new();
+            while current.inode_num != EXT2_ROOT_INO {
+                if !visited.insert(current.inode_num) {
+                    return Err(Error::InvalidData(format!(
+                        "directory inode {} is its own ancestor",
+                        current.inode_num
+                    )));
+                }
+                let not_found = || Error::NotFound(format!("inode {} has no parent", current.inode_num));
+                let parent = current
+                    .get_child(&self.disk, self, &self.block_groups, "..")
+                    .ok_or_else(not_found)?;
+                let name = parent
+                    .get_child_by_inode(&self.disk, self, current.inode_num)
+                    .ok_or_else(not_found)?;
+                parts.push(name);
+                current = parent;
+            }
+            parts.reverse();
+            Ok(format!("/{}", parts.join("/")))
+        } else {
+            let root_inode = self.read_inode(EXT2_ROOT_INO)?;
+            let mut visited = BTreeSet::from([EXT2_ROOT_INO]);
+            match self.find_path(root_inode, "", inode_num, &mut visited)? {
+                Some(path) => Ok(path),
+                None => Err(Error::NotFound(format!("inode {} not linked", inode_num))),
+            }
+        }
+    }
+
+    /// Depth first search for the first entry below `dir` pointing at `inode_num`.
+    /// Directories in `visited` are not entered again, so a loop ends the search.
+    fn find_path(
+        &self,
+        dir: Ext2Inode,
+        dir_path: &str,
+        inode_num: u64,
+        visited: &mut BTreeSet<u64>,
+    ) -> Result<Option<String>, Error> {
+        for (name, entry) in dir.read_dir(&self.disk, self, dir_path)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = format!("{}/{}", dir_path, name);
+            if entry.inode_num() == inode_num {
+                return Ok(Some(path));
+            }
+            if entry.is_dir() && visited.insert(entry.inode_num()) {
+                if let Some(path) = self.find_path(entry.inode(), &path, inode_num, visited)? {
+                    return Ok(Some(path));
+                }
+            }
+        }
+        Ok(None)
+    }
+
     /// Get inode by path
     fn resolve<'a>(&'a self, path: &'a str) -> Result<(Ext2Inode, String), Error> {
         let root_inode = self.read_inode(EXT2_ROOT_INO)?;
         self.resolve_relative(path, root_inode, false)
     }
 
+    /// Reject a path longer than `max_path_len` or with a name no entry can hold
+    fn check_path(&self, path: &str) -> Result<(), Error> {
+        if path.len() > self.max_path_len {
+            return Err(Error::NameTooLong(format!(
+                "path of {} bytes, at most {}",
+                path.len(),
+                self.max_path_len
+            )));
+        }
+        if let Some(part) = path.split('/').find(|part| part.len() > EXT2_NAME_LEN) {
+            return Err(Error::NameTooLong(format!(
+                "name of {} bytes in {}, at most {}",
+                part.len(),
+                path,
+                EXT2_NAME_LEN
+            )));
+        }
+        Ok(())
+    }
+
     /// Get inode by relative path
     fn resolve_relative<'a>(
         &'a self,
         path: &'a str,
+        inode: Ext2Inode,
+        link: bool,
+    ) -> Result<(Ext2Inode, String), Error> {
+        let mut hops = 0;
+        self.resolve_follow(path, inode, link, &mut hops, &mut Vec::new())
+    }
+
+    /// Resolve `path` from the root and return every inode passed on the way,
+    /// starting with the root. Symlinks contribute the steps through their target.
+    pub fn resolve_components(&self, path: &str) -> Result<Vec<(String, Ext2Inode)>, Error> {
+        let root_inode = self.read_inode(EXT2_ROOT_INO)?;
+        let mut trail = Vec::from([("/".to_string(), root_inode)]);
+        let mut hops = 0;
+        // A relative path starts at the root as well, no need to record it twice
+        let path = path.trim_start_matches('/');
+        self.resolve_follow(path, root_inode, false, &mut hops, &mut trail)?;
+        Ok(trail)
+    }
+
+    /// Resolve `path` from `inode`, symlinks are followed except a final one when `link` is set.
+    /// A symlink target resolves from the directory holding the link, `hops` counts the
+    /// symlinks followed so far so a loop ends with an error.
+    fn resolve_follow(
+        &self,
+        path: &str,
         mut inode: Ext2Inode,
         link: bool,
+        hops: &mut u32,
+        trail: &mut Vec<(String, Ext2Inode)>,
     ) -> Result<(Ext2Inode, String), Error> {
+        self.check_path(path)?;
         if path.starts_with("/") {
             // if the path is absolute, resolve from root inode
             inode = self.read_inode(EXT2_ROOT_INO)?;
+            trail.push(("/".to_string(), inode));
         }
         let path_parts: Vec<_> = path.split("/").collect();
         let last = path_parts.len() - 1;
@@ -83,10 +410,19 @@ impl Ext2Filesystem {
                     Some(child) => {
                         let resolve_symlink = child.metadata().is_symlink() && (!link || i != last);
                         if resolve_symlink {
+                            *hops += 1;
+                            if *hops > MAX_SYMLINK_HOPS {
+                                return Err(Error::InvalidInput(format!(
+                                    "{} Too many levels of symbolic links",
+                                    path
+                                )));
+                            }
                             let target = child.read_link(&self.disk)?;
-                            (inode, file_name) = self.resolve_relative(&target, inode, link)?;
+                            // Every link inside the target is followed, it is not the last component
+                            (inode, file_name) = self.resolve_follow(&target, inode, false, hops, trail)?;
                         } else {
-                            inode = child
+                            inode = child;
+                            trail.push((part.to_string(), child));
                         }
                     }
                     None => {
@@ -113,11 +449,39 @@ impl Ext2Filesystem {
         }
     }
 
+    /// Open a file by inode number without walking a path, the name is left empty
+    pub fn open_inode(&mut self, inode_num: u64) -> Result<FsFile<'_>, Error> {
+        let inode = self.read_inode(inode_num)?;
+        if !self.is_inode_allocated(inode_num)? {
+            return Err(Error::NotFound(format!("inode {} is not in use", inode_num)));
+        }
+        if inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("inode {} Is a directory", inode_num)));
+        }
+        let blocks = inode.get_blocks(&self.disk)?;
+        Ok(FsFile::new(self, inode, blocks, String::new()))
+    }
+
+    /// Open a directory for reading its entries one at a time
+    pub fn open_dir(&self, path: &str) -> Result<DirHandle<'_>, Error> {
+        let (inode, _) = self.resolve(path)?;
+        if !inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("{} Not a directory", path)));
+        }
+        let blocks = inode.get_blocks(&self.disk)?;
+        Ok(DirHandle::new(self, inode, blocks))
+    }
+
     /// Get block size
     fn get_block_size(&self) -> u64 {
         self.super_block.get_block_size()
     }
 
+    /// Block pointers in one index block, 256 for 1 KiB blocks and 1024 for 4 KiB
+    pub fn pointers_per_block(&self) -> u64 {
+        inode::pointers_per_block(self.get_block_size())
+    }
+
     fn get_groups_count(&self) -> usize {
         self.super_block.get_groups_count()
     }
@@ -131,57 +495,343 @@ impl Ext2Filesystem {
         self.super_block.s_free_blocks_count as u64
     }
 
-    /// Read the contents of a given directory
+    /// Superblock summary in one call, its Display prints a dumpe2fs style report
+    pub fn describe(&self) -> FsInfo {
+        FsInfo::new(&self.super_block)
+    }
+
+    /// Free counts and metadata locations of every block group
+    pub fn group_stats(&self) -> Result<Vec<GroupStat>, Error> {
+        let mut stats = Vec::new();
+        for group_num in 0..self.get_groups_count() {
+            let desc = self.block_groups.fetch_group_desc(group_num as u64, &self.disk)?;
+            stats.push(GroupStat {
+                group_num,
+                free_blocks: desc.bg_free_blocks_count as u64,
+                free_inodes: desc.bg_free_inodes_count as u64,
+                used_dirs: desc.bg_used_dirs_count as u64,
+                block_bitmap_loc: desc.bg_block_bitmap as u64,
+                inode_bitmap_loc: desc.bg_inode_bitmap as u64,
+                inode_table_loc: desc.bg_inode_table as u64,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Free blocks `uid` may allocate, the reserved blocks are left out
+    /// for everyone except root and `s_def_resuid`
+    pub fn available_for(&self, uid: u32) -> u64 {
+        let free = self.get_free_blocks_count();
+        match uid == 0 || uid == self.super_block.s_def_resuid as u32 {
+            true => free,
+            false => free.saturating_sub(self.super_block.s_r_blocks_count as u64),
+        }
+    }
+
+    /// Read the contents of a given directory, keyed by name, `.` and `..` included.
+    /// The names are moved into the keys, `file_name` of the entries is empty.
     pub fn read_dir(&self, path: &str) -> Result<BTreeMap<String, Ext2DirEntry>, Error> {
         let (inode, _) = self.resolve(path)?;
         inode.read_dir(&self.disk, self, path)
     }
+
+    /// Like `read_dir` without the `.` and `..` entries
+    pub fn read_dir_children(&self, path: &str) -> Result<BTreeMap<String, Ext2DirEntry>, Error> {
+        let mut entries = self.read_dir(path)?;
+        entries.remove(".");
+        entries.remove("..");
+        Ok(entries)
+    }
+
+    /// Read the contents of a given directory in on-disk order, `.` and `..` included
+    pub fn read_dir_ordered(&self, path: &str) -> Result<Vec<Ext2DirEntry>, Error> {
+        let mut dir = self.open_dir(path)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = dir.read_entry()? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Entries of a directory sorted by name, `.` and `..` included. Types come from the
+    /// entries themselves, no child inode is read. Names that are not UTF-8 are converted lossily.
+    pub fn list(&self, path: &str) -> Result<Vec<ListEntry>, Error> {
+        let (inode, _) = self.resolve(path)?;
+        let has_filetype = self.super_block.has_filetype();
+        let mut entries: Vec<ListEntry> = inode
+            .read_dir_raw(&self.disk)?
+            .into_iter()
+            .map(|(inode, file_type, name)| ListEntry {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                inode,
+                file_type: match has_filetype {
+                    true => Ext2DirEntry::file_type_mode(file_type),
+                    false => None,
+                },
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Walk every entry below the directory `root`, depth first
+    pub fn walk(&self, root: &str) -> Result<WalkIter<'_>, Error> {
+        let (inode, _) = self.resolve(root)?;
+        if !inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("{} Not a directory", root)));
+        }
+        Ok(WalkIter::new(self, root, inode))
+    }
+
+    /// Every path naming `inode_num`, found by scanning the whole tree.
+    /// Symlinks are not followed and no directory is entered twice,
+    /// so a corrupt directory loop cannot keep it going.
+    pub fn links_of(&self, inode_num: u64) -> Result<Vec<String>, Error> {
+        let mut links = Vec::new();
+        if inode_num == EXT2_ROOT_INO {
+            links.push("/".to_string());
+        }
+        for entry in self.walk("/")? {
+            let (path, stat) = entry?;
+            if stat.ino == inode_num {
+                links.push(path);
+            }
+        }
+        Ok(links)
+    }
+
+    /// Inodes whose `i_links_count` disagrees with the directory entries naming them,
+    /// as `(inode_num, stored_links, actual_links)`. `.` and `..` count as links,
+    /// reserved inodes other than the root and inodes free in the bitmap are not checked.
+    /// Every directory is read once, a corrupt loop adds a link but does not repeat.
+    pub fn verify_links(&self) -> Result<Vec<(u64, u16, u16)>, Error> {
+        let mut counts: BTreeMap<u64, u32> = BTreeMap::new();
+        let mut dirs = Vec::from([("".to_string(), self.read_inode(EXT2_ROOT_INO)?)]);
+        let mut visited = BTreeSet::from([EXT2_ROOT_INO]);
+        for entry in self.walk("/")? {
+            let (path, stat) = entry?;
+            if stat.is_dir() && visited.insert(stat.ino) {
+                dirs.push((path, self.read_inode(stat.ino)?));
+            }
+        }
+        for (path, dir) in dirs {
+            for (_, entry) in dir.read_dir(&self.disk, self, &path)? {
+                *counts.entry(entry.inode_num()).or_insert(0) += 1;
+            }
+        }
+        let mut mismatched = Vec::new();
+        let first_ino = self.super_block.s_first_ino as u64;
+        for inode_num in 1..=self.super_block.s_inodes_count as u64 {
+            if inode_num < first_ino && inode_num != EXT2_ROOT_INO {
+                continue;
+            }
+            // A freed inode keeps whatever link count it had
+            if !self.is_inode_allocated(inode_num)? {
+                continue;
+            }
+            let actual = counts.get(&inode_num).copied().unwrap_or(0);
+            let stored = match self.read_inode(inode_num) {
+                Ok(inode) => inode.ext2_inode.i_links_count,
+                // Never initialised inodes may fail their checksum, only matters if referenced
+                Err(_) if actual == 0 => continue,
+                Err(e) => return Err(e),
+            };
+            if stored as u32 != actual {
+                mismatched.push((inode_num, stored, actual.min(u16::MAX as u32) as u16));
+            }
+        }
+        Ok(mismatched)
+    }
+
+    /// Rewrite `i_links_count` to the number of directory entries found by `verify_links`,
+    /// returns how many inodes were changed. Inodes nothing points at are left alone,
+    /// they need to be freed or reconnected rather than set to zero links.
+    pub fn fix_links(&mut self) -> Result<usize, Error> {
+        let mut fixed = 0;
+        for (inode_num, _, actual) in self.verify_links()? {
+            if actual == 0 {
+                continue;
+            }
+            let mut inode = self.read_inode(inode_num)?;
+            inode.ext2_inode.i_links_count = actual;
+            inode.write(&self.disk, &self.block_groups)?;
+            fixed += 1;
+        }
+        Ok(fixed)
+    }
+
+    /// Bytes allocated to `path` and everything below it,
+    /// inodes reachable through several hard links are counted once
+    pub fn disk_usage(&self, path: &str) -> Result<u64, Error> {
+        let (inode, _) = self.resolve(path)?;
+        let stat = inode.metadata();
+        let mut usage = stat.blocks * 512;
+        if stat.is_dir() {
+            let mut seen = BTreeSet::new();
+            seen.insert(stat.ino);
+            for entry in self.walk(path)? {
+                let (_, stat) = entry?;
+                if seen.insert(stat.ino) {
+                    usage += stat.blocks * 512;
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Expand a path pattern with `*` and `?` wildcards into the matching absolute paths.
+    /// Missing paths match nothing, a directory that cannot be read is an error.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<String>, Error> {
+        let mut paths = vec![String::new()];
+        for part in pattern.split("/").filter(|part| !part.is_empty()) {
+            let mut matched = Vec::new();
+            for path in paths {
+                if part.contains(['*', '?']) {
+                    let dir = match path.is_empty() {
+                        true => "/",
+                        false => path.as_str(),
+                    };
+                    let inode = match self.resolve(dir) {
+                        Ok((inode, _)) => inode,
+                        // A dangling symlink matched by an earlier part
+                        Err(Error::NotFound(_)) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    // Files matched by an earlier part have nothing below them
+                    if !inode.metadata().is_dir() {
+                        continue;
+                    }
+                    let entries = inode.read_dir(&self.disk, self, dir)?;
+                    for name in entries.keys() {
+                        if name != "." && name != ".." && wildcard_match(part, name) {
+                            matched.push(format!("{}/{}", path, name));
+                        }
+                    }
+                } else {
+                    let path = format!("{}/{}", path, part);
+                    match self.resolve(&path) {
+                        Ok(_) => matched.push(path),
+                        Err(Error::NotFound(_)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            paths = matched;
+        }
+        Ok(paths
+            .into_iter()
+            .map(|path| match path.is_empty() {
+                true => "/".to_string(),
+                false => path,
+            })
+            .collect())
+    }
     fn mk_default_dir(&self, path: &str) -> Result<(), Error> {
         let (parent_inode, _) = self.resolve(&base_dir(path))?;
         let (current_inode, _) = self.resolve(path)?;
-        let (block_num, _) = current_inode.find_last_dir_entry(&self.disk)?;
+        let block_num = current_inode.blocks()[0] as u64;
         let mut current_dir = Ext2DirEntryStruct::default();
         let mut parent_dir = Ext2DirEntryStruct::default();
+        let dir_type = match self.super_block.has_filetype() {
+            true => 2,
+            false => 0,
+        };
         // dir .
-        current_dir.rec_len = align_up!(8 + 1, 4) as u16;
+        current_dir.rec_len = Ext2DirEntryStruct::record_len(1) as u16;
         current_dir.inode_num = current_inode.inode_num as u32;
-        current_dir.file_type = 2;
+        current_dir.file_type = dir_type;
         current_dir.name_len = 1;
         // dir ..
         parent_dir.rec_len = (self.get_block_size() - current_dir.rec_len as u64) as u16;
         parent_dir.inode_num = parent_inode.inode_num as u32;
-        parent_dir.file_type = 2;
+        parent_dir.file_type = dir_type;
         parent_dir.name_len = 2;
-        self.write_block(block_num, 0, to_slice!(&current_dir, Ext2DirEntryStruct))?;
-        self.write_block(block_num, 8, ".".as_bytes())?;
-        self.write_block(
-            block_num,
-            current_dir.rec_len as u64,
-            to_slice!(&parent_dir, Ext2DirEntryStruct),
-        )?;
-        self.write_block(block_num, (current_dir.rec_len + 8) as u64, "..".as_bytes())?;
+        let mut block = current_dir.to_bytes(".");
+        block.extend(parent_dir.to_bytes(".."));
+        self.write_block(block_num, 0, &block)?;
         Ok(())
     }
     pub fn mk_dir(&mut self, path: &str, perm: u16) -> Result<(), Error> {
-        self.new_dir_entry(path, perm, false)?;
-        self.mk_default_dir(path)?;
-        Ok(())
+        let (inode, name) = self.new_dir_entry(path, perm, false)?;
+        if let Err(e) = self.mk_default_dir(path) {
+            let undo = self
+                .resolve(&base_dir(path))
+                .and_then(|(parent_inode, _)| self.remove_dir_entry(&parent_inode, &name))
+                .and(self.set_group_dirs(inode.inode_num, -1))
+                .and(self.release_new_inode(inode.inode_num, inode.blocks()[0]));
+            return self.rolled_back(e, undo);
+        }
+        // The new ".." links back to the parent
+        let (parent_inode, _) = self.resolve(&base_dir(path))?;
+        self.add_links(parent_inode.inode_num, 1)
+    }
+
+    /// Remove an empty directory
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), Error> {
+        let root_inode = self.read_inode(EXT2_ROOT_INO)?;
+        let (mut inode, name) = self.resolve_relative(path, root_inode, true)?;
+        if !inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("{} Not a directory", path)));
+        }
+        if inode.inode_num == EXT2_ROOT_INO {
+            return Err(Error::PermissionDenied(format!("{} is the root directory", path)));
+        }
+        let entries = inode.read_dir_raw(&self.disk)?;
+        if entries.iter().any(|(_, _, name)| name != b"." && name != b"..") {
+            return Err(Error::InvalidInput(format!("{} Directory not empty", path)));
+        }
+        let (parent_inode, _) = self.resolve(&base_dir(path))?;
+        self.remove_dir_entry(&parent_inode, &name)?;
+        self.add_links(parent_inode.inode_num, -1)?;
+        self.set_group_dirs(inode.inode_num, -1)?;
+        inode.free_indirect_blocks(self, 0)?;
+        inode.ext2_inode.set_size(0);
+        inode.ext2_inode.i_links_count = 0;
+        // Same dtime choice as remove_file
+        let raw = &inode.ext2_inode;
+        let sb = &self.super_block;
+        inode.ext2_inode.i_dtime = raw.i_ctime.max(raw.i_mtime).max(sb.s_wtime).max(sb.s_mkfs_time);
+        inode.write(&self.disk, &self.block_groups)?;
+        self.free_inode(inode.inode_num)
+    }
+
+    /// Change the link count of an inode by `delta`
+    fn add_links(&self, inode_num: u64, delta: i32) -> Result<(), Error> {
+        let mut inode = self.read_inode(inode_num)?;
+        let links = inode.ext2_inode.i_links_count as i32 + delta;
+        inode.ext2_inode.i_links_count = links.clamp(0, u16::MAX as i32) as u16;
+        inode.write(&self.disk, &self.block_groups)
     }
     pub fn new_file(&mut self, path: &str, perm: u16) -> Result<FsFile, Error> {
         let (inode, name) = self.new_dir_entry(path, perm, true)?;
         Ok(FsFile::new(self, inode, vec![inode.blocks()[0] as u64], name))
     }
     pub fn new_dir_entry(&mut self, path: &str, perm: u16, is_file: bool) -> Result<(Ext2Inode, String), Error> {
+        self.check_path(path)?;
         match self.is_exist(path) {
             true => Err(Error::FileExists(format!("{}", path))),
             false => {
                 let (parent_inode, _) = self.resolve(&base_dir(path))?;
+                if parent_inode.is_indexed() {
+                    return Err(Error::InvalidInput(format!(
+                        "{} is an indexed directory, adding entries is not supported",
+                        base_dir(path)
+                    )));
+                }
                 let block_size = self.super_block.get_block_size();
-                let (block_num, offset) = parent_inode.find_last_dir_entry(&self.disk)?;
-                let buffer = self.read_block(block_num).unwrap();
-                let entry_size = core::mem::size_of::<Ext2DirEntryStruct>();
-                let new_inum = self.alloc_inode_num().unwrap();
-                let new_block_num = self.alloc_block().unwrap();
+                let new_name = base_file(path);
+                let (block_num, offset) = parent_inode.find_last_dir_entry(&self.disk, new_name.len(), self.super_block.has_filetype())?;
+                let buffer = self.read_block(block_num)?;
+                let new_inum = self
+                    .alloc_inode_num()
+                    .ok_or_else(|| Error::NoSpace(format!("no free inode for {}", path)))?;
+                let new_block_num = match self.alloc_block() {
+                    Some(block_num) => block_num,
+                    None => {
+                        let undo = self.free_inode(new_inum);
+                        return self.rolled_back(Error::NoSpace(format!("no free block for {}", path)), undo);
+                    }
+                };
                 let inode_new = Ext2Inode {
                     inode_num: new_inum,
                     ext2_inode: match is_file {
@@ -192,40 +842,231 @@ impl Ext2Filesystem {
                     block_size,
                     size: block_size,
                     data_blocks_count: 1,
+                    extra: Default::default(),
                 };
-                inode_new.write(&self.disk, &self.block_groups);
-                let mut entry = buffer[offset..]
-                    .as_slice()
-                    .read_struct::<Ext2DirEntryStruct>()?;
-                entry.rec_len = align_up!(entry_size + entry.name_len as usize, 4) as u16;
-                let mut new_entry = Ext2DirEntryStruct::default();
-                let new_name = base_file(path);
-                new_entry.rec_len = (block_size - offset as u64 - entry.rec_len as u64) as u16;
-                new_entry.inode_num = new_inum as u32;
-                new_entry.file_type = match is_file {
-                    true => 1,
-                    false => 2,
+                if let Err(e) = inode_new.write(&self.disk, &self.block_groups) {
+                    let undo = self.release_new_inode(new_inum, new_block_num);
+                    return self.rolled_back(e, undo);
+                }
+                // Without FILETYPE the byte is the high half of name_len
+                let file_type = match (self.super_block.has_filetype(), is_file) {
+                    (false, _) => 0,
+                    (true, true) => 1,
+                    (true, false) => 2,
                 };
-                new_entry.name_len = new_name.len() as u8;
-                self.write_block(
-                    block_num,
-                    offset as u64,
-                    to_slice!(&entry, Ext2DirEntryStruct),
-                )?;
-                self.write_block(
-                    block_num,
-                    offset as u64 + entry.rec_len as u64,
-                    to_slice!(&new_entry, Ext2DirEntryStruct),
-                )?;
-                self.write_block(
-                    block_num,
-                    offset as u64 + entry.rec_len as u64 + entry_size as u64,
-                    new_name.as_bytes(),
-                )?;
+                if let Err(e) = self.add_dir_entry(block_num, offset, &buffer, &new_name, new_inum, file_type) {
+                    let undo = self.release_new_inode(new_inum, new_block_num);
+                    return self.rolled_back(e, undo);
+                }
+                if !is_file {
+                    self.set_group_dirs(new_inum, 1)?;
+                }
                 Ok((inode_new, new_name))
             }
         }
     }
+
+    /// Put an entry for `inode_num` into the slot `find_last_dir_entry` picked in `buffer`
+    fn add_dir_entry(
+        &self,
+        block_num: u64,
+        offset: usize,
+        buffer: &[u8],
+        name: &str,
+        inode_num: u64,
+        file_type: u8,
+    ) -> Result<(), Error> {
+        let mut entry = buffer[offset..].as_ref().read_struct::<Ext2DirEntryStruct>()?;
+        // An unused entry is taken over whole, a live one gives up its slack
+        let old_len = entry.rec_len;
+        let name_len = entry.name_len(self.super_block.has_filetype());
+        entry.rec_len = match entry.inode_num {
+            0 => 0,
+            _ => Ext2DirEntryStruct::record_len(name_len) as u16,
+        };
+        let new_entry = Ext2DirEntryStruct {
+            inode_num: inode_num as u32,
+            rec_len: old_len - entry.rec_len,
+            name_len: name.len() as u8,
+            file_type,
+        };
+        // The new entry goes in first, until the old one shrinks it stays hidden in its slack
+        self.write_block(
+            block_num,
+            offset as u64 + entry.rec_len as u64,
+            &new_entry.to_bytes(name),
+        )?;
+        if entry.inode_num != 0 {
+            self.write_block(
+                block_num,
+                offset as u64,
+                to_slice!(&entry, Ext2DirEntryStruct),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Undo the allocations of a half created inode after a later step failed.
+    /// Every step is tried, the first failure is returned.
+    fn release_new_inode(&mut self, inode_num: u64, block_num: u32) -> Result<(), Error> {
+        self.zero_inode(inode_num)
+            .and(self.free_block(block_num as u64))
+            .and(self.free_inode(inode_num))
+    }
+
+    /// Fail with `error`, the first thing that went wrong, after its undo steps ran.
+    /// If those failed too the image is inconsistent and is marked as having errors.
+    fn rolled_back<T>(&mut self, error: Error, undo: Result<(), Error>) -> Result<T, Error> {
+        if undo.is_err() {
+            self.super_block.s_state |= EXT2_ERROR_FS;
+        }
+        Err(error)
+    }
+    /// Unlink a file, freeing its inode and blocks when the last link goes away
+    pub fn remove_file(&mut self, path: &str) -> Result<(), Error> {
+        let root_inode = self.read_inode(EXT2_ROOT_INO)?;
+        let (mut inode, name) = self.resolve_relative(path, root_inode, true)?;
+        if inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("{} Is a directory", path)));
+        }
+        let (parent_inode, _) = self.resolve(&base_dir(path))?;
+        self.remove_dir_entry(&parent_inode, &name)?;
+        inode.ext2_inode.i_links_count = inode.ext2_inode.i_links_count.saturating_sub(1);
+        if inode.ext2_inode.i_links_count > 0 {
+            return inode.write(&self.disk, &self.block_groups);
+        }
+        self.release_acl_block(&mut inode)?;
+        // Fast symlinks keep their target in i_block and own no blocks
+        if inode.ext2_inode.i_blocks != 0 {
+            inode.free_indirect_blocks(self, 0)?;
+        }
+        inode.ext2_inode.set_size(0);
+        // A deleted inode needs a dtime, there is no clock so reuse the newest time known.
+        // Small values read as orphan list links, the superblock times keep it above those.
+        let raw = &inode.ext2_inode;
+        let sb = &self.super_block;
+        inode.ext2_inode.i_dtime = raw.i_ctime.max(raw.i_mtime).max(sb.s_wtime).max(sb.s_mkfs_time);
+        inode.write(&self.disk, &self.block_groups)?;
+        self.free_inode(inode.inode_num)
+    }
+
+    /// Drop the inode's reference to its extended attribute block, freeing it once unshared
+    fn release_acl_block(&mut self, inode: &mut Ext2Inode) -> Result<(), Error> {
+        let block_num = inode.ext2_inode.i_file_acl as u64;
+        if block_num == 0 {
+            return Ok(());
+        }
+        // h_refcount follows the 4 byte h_magic of the attribute block header
+        let buffer = self.read_block(block_num)?;
+        let refcount = u32::from_le_bytes(buffer[4..8].try_into().expect("incorrect length"));
+        match refcount > 1 {
+            true => {
+                self.write_block(block_num, 4, &(refcount - 1).to_le_bytes())?;
+            }
+            false => self.free_block(block_num)?,
+        }
+        inode.ext2_inode.i_file_acl = 0;
+        let sectors = (self.get_block_size() / 512) as u32;
+        inode.ext2_inode.i_blocks = inode.ext2_inode.i_blocks.saturating_sub(sectors);
+        Ok(())
+    }
+    /// Drop the entry `name` from the directory and repack its block so the
+    /// live entries stay contiguous and the last one runs to the end of the block
+    fn remove_dir_entry(&self, dir: &Ext2Inode, name: &str) -> Result<(), Error> {
+        let block_size = self.get_block_size() as usize;
+        let entry_size = core::mem::size_of::<Ext2DirEntryStruct>();
+        for block_num in dir.get_blocks(&self.disk)? {
+            let buffer = self.read_block(block_num)?;
+            let mut entries = Vec::new();
+            let mut found = false;
+            let mut offset = 0;
+            while offset + entry_size <= block_size {
+                let entry = buffer[offset..].as_ref().read_struct::<Ext2DirEntryStruct>()?;
+                if entry.rec_len == 0 {
+                    return Err(Error::InvalidData(format!("block {} has a zero rec_len entry", block_num)));
+                }
+                let name_end = offset + entry_size + entry.name_len(self.super_block.has_filetype());
+                let entry_name = &buffer[offset + entry_size..name_end];
+                let rec_len = entry.rec_len as usize;
+                if entry.inode_num != 0 {
+                    match !found && entry_name == name.as_bytes() {
+                        true => found = true,
+                        false => entries.push((entry, entry_name)),
+                    }
+                }
+                offset += rec_len;
+            }
+            if !found {
+                continue;
+            }
+            let mut packed = vec![0u8; block_size];
+            let mut offset = 0;
+            let last = entries.len().wrapping_sub(1);
+            for (i, (mut entry, entry_name)) in entries.into_iter().enumerate() {
+                let len = Ext2DirEntryStruct::record_len(entry_name.len());
+                entry.rec_len = match i == last {
+                    true => (block_size - offset) as u16,
+                    false => len as u16,
+                };
+                packed[offset..offset + entry_size].copy_from_slice(to_slice!(&entry, Ext2DirEntryStruct));
+                packed[offset + entry_size..offset + entry_size + entry_name.len()].copy_from_slice(entry_name);
+                offset += len;
+            }
+            if offset == 0 {
+                // Nothing left, one unused entry spans the block
+                let empty = Ext2DirEntryStruct {
+                    rec_len: block_size as u16,
+                    ..Default::default()
+                };
+                packed[..entry_size].copy_from_slice(to_slice!(&empty, Ext2DirEntryStruct));
+            }
+            self.write_block(block_num, 0, &packed)?;
+            return Ok(());
+        }
+        Err(Error::NotFound(format!("{} No such file or directory", name)))
+    }
+
+    /// Rewrite the file type of every entry in a directory from its inode's mode,
+    /// returns how many entries were changed
+    pub fn fix_dir_types(&mut self, path: &str) -> Result<usize, Error> {
+        if !self.super_block.has_filetype() {
+            return Err(Error::InvalidInput("filesystem has no FILETYPE feature".to_string()));
+        }
+        let (dir, _) = self.resolve(path)?;
+        if !dir.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("{} Not a directory", path)));
+        }
+        let block_size = self.get_block_size() as usize;
+        let entry_size = core::mem::size_of::<Ext2DirEntryStruct>();
+        let mut fixed = 0;
+        for block_num in dir.get_blocks(&self.disk)? {
+            let mut buffer = self.read_block(block_num)?;
+            let mut changed = false;
+            let mut offset = 0;
+            while offset + entry_size <= block_size {
+                let mut entry = buffer[offset..].as_ref().read_struct::<Ext2DirEntryStruct>()?;
+                if entry.rec_len == 0 {
+                    return Err(Error::InvalidData(format!("block {} has a zero rec_len entry", block_num)));
+                }
+                if entry.inode_num != 0 {
+                    let inode = self.read_inode(entry.inode_num as u64)?;
+                    let code = Ext2DirEntry::file_type_code(inode.ext2_inode.i_mode);
+                    if entry.file_type != code {
+                        entry.file_type = code;
+                        buffer[offset..offset + entry_size].copy_from_slice(to_slice!(&entry, Ext2DirEntryStruct));
+                        changed = true;
+                        fixed += 1;
+                    }
+                }
+                offset += entry.rec_len as usize;
+            }
+            if changed {
+                self.write_block(block_num, 0, &buffer)?;
+            }
+        }
+        Ok(fixed)
+    }
+
     pub fn is_exist(&self, path: &str) -> bool {
         match self.resolve(path) {
             Ok(_) => true,
@@ -233,12 +1074,63 @@ impl Ext2Filesystem {
         }
     }
     /// Given a path, query the file system to get information about a file, directory, etc.
-    fn metadata(&self, path: &str) -> Result<Stat, Error> {
+    pub fn metadata(&self, path: &str) -> Result<Stat, Error> {
         let root_inode = self.read_inode(EXT2_ROOT_INO)?;
         let (inode, _) = self.resolve_relative(path, root_inode, true)?;
         Ok(inode.metadata())
     }
 
+    /// Set the access and modification times, `None` keeps the current value.
+    /// There is no clock here, so the change time is moved forward to the newest time given.
+    pub fn set_times(&mut self, path: &str, atime: Option<u32>, mtime: Option<u32>) -> Result<(), Error> {
+        let (mut inode, _) = self.resolve(path)?;
+        if let Some(atime) = atime {
+            inode.ext2_inode.i_atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            inode.ext2_inode.i_mtime = mtime;
+        }
+        inode.ext2_inode.i_ctime = inode.ext2_inode.i_ctime.max(atime.unwrap_or(0)).max(mtime.unwrap_or(0));
+        inode.write(&self.disk, &self.block_groups)
+    }
+
+    /// Check whether `uid`/`gid` may access `path` as requested by the read, write and
+    /// execute bits of `want` (any of user, group or other). uid 0 is always allowed.
+    pub fn access(&self, path: &str, uid: u32, gid: u32, want: Mode) -> Result<bool, Error> {
+        let (inode, _) = self.resolve(path)?;
+        Ok(Self::permitted(&inode.metadata(), uid, gid, want))
+    }
+
+    /// Whether `uid`/`gid` gets the `want` bits on `stat`, root gets everything
+    fn permitted(stat: &Stat, uid: u32, gid: u32, want: Mode) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        // Fold the requested bits of all classes into a single rwx triple
+        let want = (want.perm() | want.perm() >> 3 | want.perm() >> 6) & 0o7;
+        let granted = if stat.uid == uid {
+            stat.mode.perm() >> 6
+        } else if stat.gid == gid {
+            stat.mode.perm() >> 3
+        } else {
+            stat.mode.perm()
+        } & 0o7;
+        granted & want == want
+    }
+
+    /// Resolve `path` like `resolve` but require search permission
+    /// on every directory passed through for `uid`/`gid`
+    pub fn resolve_checked(&self, path: &str, uid: u32, gid: u32) -> Result<Ext2Inode, Error> {
+        let trail = self.resolve_components(path)?;
+        let (_, inode) = trail[trail.len() - 1];
+        for (name, dir) in &trail[..trail.len() - 1] {
+            if !Self::permitted(&dir.metadata(), uid, gid, Mode::O_EXEC) {
+                return Err(Error::PermissionDenied(format!("{} search denied on {}", path, name)));
+            }
+        }
+        Ok(inode)
+    }
+
     /// Reads a symbolic link, returning the file that the link points to
     fn read_link(&self, path: &str) -> Result<String, Error> {
         // Read value of a symbolic link
@@ -246,6 +1138,78 @@ impl Ext2Filesystem {
         let (inode, _) = self.resolve_relative(path, root_inode, true)?;
         inode.read_link(&self.disk)
     }
+    /// CRC-32C of a file's contents, read one block at a time
+    pub fn content_hash(&self, path: &str) -> Result<u32, Error> {
+        let (inode, _) = self.resolve(path)?;
+        if inode.metadata().is_dir() {
+            return Err(Error::InvalidInput(format!("{} Is a directory", path)));
+        }
+        let block_size = self.get_block_size();
+        let mut remaining = inode.get_size();
+        let mut crc = !0;
+        for block in inode.get_blocks_iter(&self.disk)? {
+            let len = core::cmp::min(remaining, block_size) as usize;
+            crc = match block? {
+                0 => crc32c(crc, &vec![0u8; len]),
+                block => crc32c(crc, &self.read_block(block)?[..len]),
+            };
+            remaining -= len as u64;
+        }
+        Ok(!crc)
+    }
+    /// Fill `buf` from the inode's data starting at byte `offset`, holes read as zeros
+    pub fn read_exact_at(&self, inode: &Ext2Inode, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let end = offset.checked_add(buf.len() as u64);
+        if end.is_none_or(|end| end > inode.get_size()) {
+            return Err(Error::UnexpectedEof(format!(
+                "{} bytes at {} past the end of inode {}",
+                buf.len(),
+                offset,
+                inode.inode_num
+            )));
+        }
+        let block_size = self.get_block_size();
+        let blocks = inode.get_blocks(&self.disk)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_block = pos % block_size;
+            let n = core::cmp::min(buf.len() - done, (block_size - in_block) as usize);
+            let chunk = &mut buf[done..done + n];
+            match blocks.get((pos / block_size) as usize).copied().unwrap_or(0) {
+                0 => chunk.fill(0),
+                block => self.disk.read_exact_at(block * block_size + in_block, chunk)?,
+            }
+            done += n;
+        }
+        Ok(())
+    }
+    /// Read up to `len` bytes of an inode's data at `offset` without an `FsFile`,
+    /// stopping at the end of the file. Nothing is returned at or past the end.
+    pub fn pread(&self, inode_num: u64, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let inode = self.read_inode(inode_num)?;
+        let end = offset.saturating_add(len).min(inode.get_size());
+        let mut buf = vec![0u8; end.saturating_sub(offset) as usize];
+        if !buf.is_empty() {
+            self.read_exact_at(&inode, offset, &mut buf)?;
+        }
+        Ok(buf)
+    }
+    /// Write `data` into a file at `offset` through the `FsFile` write path, blocks are
+    /// allocated as needed and the size grows when the write ends past it
+    pub fn pwrite(&mut self, inode_num: u64, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let mut file = self.open_inode(inode_num)?;
+        file.seek(offset);
+        file.write(data)
+    }
+    /// Read `len` bytes at an absolute byte offset on the disk
+    pub fn read_bytes(&self, byte_offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        self.disk.read_at(&Offset::absolute(byte_offset), len)
+    }
+    /// Write `data` at an absolute byte offset on the disk
+    pub fn write_bytes(&self, byte_offset: u64, data: &[u8]) -> Result<usize, Error> {
+        self.disk.write_at(&Offset::absolute(byte_offset), data)
+    }
     pub fn read_block(&self, block_num: u64) -> Result<Vec<u8>, Error> {
         let block_size = self.get_block_size();
         let offset = Offset::new(block_size, block_num);
@@ -260,93 +1224,241 @@ impl Ext2Filesystem {
         };
         self.disk.write_at(&offset, buffer)
     }
+    /// Overwrite a whole block, `data` has to be exactly one block long
+    pub fn write_block_full(&self, block_num: u64, data: &[u8]) -> Result<(), Error> {
+        let block_size = self.get_block_size();
+        if data.len() as u64 != block_size {
+            return Err(Error::InvalidInput(format!(
+                "{} bytes given for a {} byte block",
+                data.len(),
+                block_size
+            )));
+        }
+        if block_num >= self.get_blocks_count() {
+            return Err(Error::InvalidInput(format!("block {} out of range", block_num)));
+        }
+        self.disk.write_all_at(block_num * block_size, data)
+    }
 }
 
 impl Ext2Filesystem {
-    fn get_block_bitmap(&self, num: u64) -> Result<Vec<u8>, Error> {
+    fn get_block_bitmap(&self, num: u64) -> Result<BitMap, Error> {
+        if let Some(bitmaps) = &self.bitmaps {
+            return Ok(bitmaps.borrow().blocks[num as usize].clone());
+        }
+        if let Some(cached) = self.block_bitmap_cache.borrow().as_ref() {
+            if cached.group == num {
+                return Ok(cached.bitmap.clone());
+            }
+        }
         let group = self.block_groups.get_group(num, &self.disk)?;
         let bitmap_block_num = group.ext2_group_desc.bg_block_bitmap as u64;
         let block_size = self.get_block_size();
         let offset = Offset::new(block_size, bitmap_block_num);
-        self.disk.read_at(&offset, block_size)
+        Ok(BitMap::new(self.disk.read_at(&offset, block_size)?))
     }
+    /// Write the cached block bitmap back if it changed since it was cached
+    fn flush_block_bitmap(&self) -> Result<(), Error> {
+        let mut cache = self.block_bitmap_cache.borrow_mut();
+        if let Some(cached) = cache.as_mut().filter(|cached| cached.dirty) {
+            let group = self.block_groups.get_group(cached.group, &self.disk)?;
+            let offset = Offset::new(self.get_block_size(), group.ext2_group_desc.bg_block_bitmap as u64);
+            self.disk.write_at(&offset, cached.bitmap.as_bytes())?;
+            cached.dirty = false;
+        }
+        Ok(())
+    }
+    /// Allocate a block filled with zeros
     pub fn alloc_block(&mut self) -> Option<u32> {
-        for i in 0..self.get_groups_count() {
-            match self.alloc_block_group(i as u64) {
+        self.alloc_block_with(true)
+    }
+    /// Allocate a block with its old contents, for callers that overwrite the whole block
+    pub fn alloc_block_uninit(&mut self) -> Option<u32> {
+        self.alloc_block_with(false)
+    }
+    fn alloc_block_with(&mut self, zero_fill: bool) -> Option<u32> {
+        // Start from the group allocated from last so its cached bitmap is reused
+        let count = self.get_groups_count() as u64;
+        let start = self.block_bitmap_cache.borrow().as_ref().map_or(0, |cached| cached.group);
+        for i in (start..count).chain(0..start) {
+            match self.alloc_block_group(i) {
                 None => continue,
-                Some(block_num) => return Some(block_num),
+                Some(block_num) => {
+                    if zero_fill {
+                        let zeros = vec![0u8; self.get_block_size() as usize];
+                        if self.write_block(block_num as u64, 0, &zeros).is_err() {
+                            let _ = self.free_block(block_num as u64);
+                            return None;
+                        }
+                    }
+                    return Some(block_num);
+                }
             }
         }
         None
     }
     pub fn alloc_block_group(&mut self, group_num: u64) -> Option<u32> {
-        let mut bitmap = self.get_block_bitmap(group_num).ok()?;
-        let mut chunk = u8::MAX;
-        let mut bnum = 0u32;
-        for i in 0..self.get_block_size() as usize {
-            chunk = bitmap[i];
-            if chunk != u8::MAX {
-                break;
-            }
-            bnum += 8;
-        }
-        if chunk != u8::MAX {
-            while chunk & 1 != 0 {
-                chunk >>= 1;
-                bnum += 1;
-            }
-        } else {
+        if self.available_for(self.uid) == 0 {
             return None;
         }
-        bnum += 1;
-        self.bitmap_set_bit(&mut bitmap, bnum, true).ok()?;
+        let mut bitmap = self.get_block_bitmap(group_num).ok()?;
+        let per_group = self.super_block.s_blocks_per_group;
+        let bit = bitmap.find_first_clear_in(0..per_group as usize)?;
+        bitmap.set(bit, true);
         self.set_block_bitmap(group_num, &bitmap).ok()?;
         self.set_group_free(group_num as u32, 0, -1).ok()?;
-        self.set_sb_free(0, -1);
-        Some(bnum)
+        self.set_sb_free(0, -1).ok()?;
+        // Bit n of group g's bitmap is block first_data_block + g * blocks_per_group + n
+        let first = self.super_block.s_first_data_block;
+        Some(first + group_num as u32 * per_group + bit as u32)
     }
 
-    fn set_block_bitmap(&self, num: u64, bitmap: &Vec<u8>) -> Result<(), Error> {
-        let group = self.block_groups.get_group(num, &self.disk)?;
-        let bitmap_block_num = group.ext2_group_desc.bg_block_bitmap as u64;
-        let block_size = self.get_block_size();
-        let offset = Offset::new(block_size, bitmap_block_num);
-        match self.disk.write_at(&offset, bitmap.as_slice()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    /// Allocate `count` blocks, physically contiguous when some group has a free run
+    /// that long, otherwise made of the longest runs available. The blocks are not zeroed.
+    pub fn alloc_block_run(&mut self, count: u64) -> Result<Vec<u64>, Error> {
+        if count > self.available_for(self.uid) {
+            return Err(Error::NoSpace(format!("{} blocks requested", count)));
         }
+        let first = self.super_block.s_first_data_block as u64;
+        let per_group = self.super_block.s_blocks_per_group as u64;
+        let mut blocks = Vec::new();
+        while (blocks.len() as u64) < count {
+            let want = count - blocks.len() as u64;
+            // The first run long enough wins, else the longest one of any group
+            let mut best: Option<(u64, Range<usize>)> = None;
+            for group in 0..self.get_groups_count() as u64 {
+                let bits = per_group.min(self.get_blocks_count() - first - group * per_group);
+                let bitmap = self.get_block_bitmap(group)?;
+                if let Some(run) = bitmap.find_clear_run_in(0..bits as usize, want as usize) {
+                    let long_enough = run.len() as u64 == want;
+                    if best.as_ref().is_none_or(|(_, best)| run.len() > best.len()) {
+                        best = Some((group, run));
+                    }
+                    if long_enough {
+                        break;
+                    }
+                }
+            }
+            let Some((group, run)) = best else {
+                // The free counts said otherwise, give back what was taken
+                for block in blocks {
+                    let _ = self.free_block(block);
+                }
+                return Err(Error::NoSpace(format!("{} blocks requested", count)));
+            };
+            let mut bitmap = self.get_block_bitmap(group)?;
+            for bit in run.clone() {
+                bitmap.set(bit, true);
+            }
+            self.set_block_bitmap(group, &bitmap)?;
+            self.set_group_free(group as u32, 0, -(run.len() as i64))?;
+            self.set_sb_free(0, -(run.len() as i64))?;
+            blocks.extend(run.map(|bit| first + group * per_group + bit as u64));
+        }
+        Ok(blocks)
     }
-    fn bitmap_set_bit(
-        &self,
-        bitmap: &mut Vec<u8>,
-        bnum: u32,
-        set: bool,
-    ) -> Result<(), Error> {
-        let index = ((bnum - 1) / 8) as usize;
-        let bit_n = (bnum - 1) % 8;
-        let mut c = bitmap[index];
-        if set {
-            c |= 1 << bit_n;
-        } else {
-            c &= !(1 << bit_n);
+
+    /// Return a block to its group's bitmap
+    pub fn free_block(&mut self, block_num: u64) -> Result<(), Error> {
+        let first = self.super_block.s_first_data_block as u64;
+        let per_group = self.super_block.s_blocks_per_group as u64;
+        if block_num < first || block_num >= self.get_blocks_count() {
+            return Err(Error::InvalidInput(format!("block {} out of range", block_num)));
+        }
+        let group = (block_num - first) / per_group;
+        let bit = ((block_num - first) % per_group) as usize;
+        let mut bitmap = self.get_block_bitmap(group)?;
+        if !bitmap.set(bit, false) {
+            return Err(Error::InvalidData(format!("block {} already free", block_num)));
         }
-        bitmap[index] = c;
+        self.set_block_bitmap(group, &bitmap)?;
+        self.set_group_free(group as u32, 0, 1)?;
+        self.set_sb_free(0, 1)?;
         Ok(())
     }
 
-    pub fn get_inode_bitmap(&self, num: u64) -> Result<Vec<u8>, Error> {
+    fn set_block_bitmap(&self, num: u64, bitmap: &BitMap) -> Result<(), Error> {
+        if let Some(bitmaps) = &self.bitmaps {
+            bitmaps.borrow_mut().blocks[num as usize] = bitmap.clone();
+            return Ok(());
+        }
+        if num >= self.get_groups_count() as u64 {
+            return Err(Error::InvalidInput(format!("group {} out of range", num)));
+        }
+        if self.block_bitmap_cache.borrow().as_ref().is_some_and(|cached| cached.group != num) {
+            self.flush_block_bitmap()?;
+        }
+        *self.block_bitmap_cache.borrow_mut() = Some(CachedBitmap { group: num, bitmap: bitmap.clone(), dirty: true });
+        Ok(())
+    }
+    pub fn get_inode_bitmap(&self, num: u64) -> Result<BitMap, Error> {
+        if let Some(bitmaps) = &self.bitmaps {
+            return Ok(bitmaps.borrow().inodes[num as usize].clone());
+        }
         let group = self.block_groups.get_group(num, &self.disk)?;
         let bitmap_block_num = group.ext2_group_desc.bg_inode_bitmap as u64;
         let block_size = self.get_block_size();
         let offset = Offset::new(block_size, bitmap_block_num);
-        self.disk.read_at(&offset, block_size)
+        Ok(BitMap::new(self.disk.read_at(&offset, block_size)?))
+    }
+    /// Block bitmap of a group, bit n is block first_data_block + group * blocks_per_group + n
+    pub fn block_bitmap(&self, group_num: u64) -> Result<BitMap, Error> {
+        self.get_block_bitmap(group_num)
+    }
+
+    /// Inode bitmap of a group, bit n is inode group * inodes_per_group + n + 1
+    pub fn inode_bitmap(&self, group_num: u64) -> Result<BitMap, Error> {
+        self.get_inode_bitmap(group_num)
+    }
+
+    /// Whether the bitmap marks a block in use
+    pub fn is_block_allocated(&self, block_num: u64) -> Result<bool, Error> {
+        let first = self.super_block.s_first_data_block as u64;
+        let per_group = self.super_block.s_blocks_per_group as u64;
+        if block_num < first || block_num >= self.get_blocks_count() {
+            return Err(Error::InvalidInput(format!("block {} out of range", block_num)));
+        }
+        let bitmap = self.get_block_bitmap((block_num - first) / per_group)?;
+        Ok(bitmap.get(((block_num - first) % per_group) as usize))
+    }
+
+    /// Whether the bitmap marks an inode in use
+    pub fn is_inode_allocated(&self, inode_num: u64) -> Result<bool, Error> {
+        if inode_num < 1 || inode_num > self.super_block.s_inodes_count as u64 {
+            return Err(Error::InvalidInput(format!("inode {} out of range", inode_num)));
+        }
+        let per_group = self.super_block.s_inodes_per_group as u64;
+        let bitmap = self.get_inode_bitmap((inode_num - 1) / per_group)?;
+        Ok(bitmap.get(((inode_num - 1) % per_group) as usize))
+    }
+
+    /// Return an inode to its group's bitmap
+    pub fn free_inode(&mut self, inode_num: u64) -> Result<(), Error> {
+        if inode_num < 1 || inode_num > self.super_block.s_inodes_count as u64 {
+            return Err(Error::InvalidInput(format!("inode {} out of range", inode_num)));
+        }
+        let per_group = self.super_block.s_inodes_per_group as u64;
+        let group = (inode_num - 1) / per_group;
+        let mut bitmap = self.get_inode_bitmap(group)?;
+        if !bitmap.set(((inode_num - 1) % per_group) as usize, false) {
+            return Err(Error::InvalidData(format!("inode {} already free", inode_num)));
+        }
+        self.set_inode_bitmap(inode_num, &bitmap)?;
+        self.set_group_free(group as u32, 1, 0)?;
+        self.set_sb_free(1, 0)?;
+        Ok(())
     }
-    fn set_inode_bitmap(&self, inode_num: u64, bitmap: &Vec<u8>) -> Result<(), Error> {
+    fn set_inode_bitmap(&self, inode_num: u64, bitmap: &BitMap) -> Result<(), Error> {
+        if let Some(bitmaps) = &self.bitmaps {
+            let group = (inode_num - 1) / self.super_block.s_inodes_per_group as u64;
+            bitmaps.borrow_mut().inodes[group as usize] = bitmap.clone();
+            return Ok(());
+        }
         let group = self.block_groups.get_inode_group(inode_num, &self.disk)?;
         let bitmap_block_num = group.ext2_group_desc.bg_inode_bitmap as u64;
         let block_size = self.get_block_size();
         let offset = Offset::new(block_size, bitmap_block_num);
-        match self.disk.write_at(&offset, bitmap.as_slice()) {
+        match self.disk.write_at(&offset, bitmap.as_bytes()) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
         }
@@ -362,30 +1474,52 @@ impl Ext2Filesystem {
     }
     pub fn alloc_inode_num_group(&mut self, group_num: u64) -> Option<u64> {
         let mut bitmap = self.get_inode_bitmap(group_num).ok()?;
-        let mut chunk = u8::MAX;
-        let mut inum = 8u32;
-        //reserved inode 1~10
-        for i in 1..self.get_block_size() as usize {
-            chunk = bitmap[i];
-            if chunk != u8::MAX {
-                break;
-            }
-            inum += 8;
+        let per_group = self.super_block.s_inodes_per_group as usize;
+        // Only group 0 holds the reserved inodes below s_first_ino
+        let first = match group_num {
+            0 => self.super_block.first_ino().max(1) as usize - 1,
+            _ => 0,
+        };
+        let bit = bitmap.find_first_clear_in(first..per_group)?;
+        bitmap.set(bit, true);
+        let inode_num = group_num * per_group as u64 + bit as u64 + 1;
+        self.set_inode_bitmap(inode_num, &bitmap).ok()?;
+        self.set_group_free(group_num as u32, -1, 0).ok()?;
+        self.set_sb_free(-1, 0).ok()?;
+        self.zero_inode(inode_num).ok()?;
+        Some(inode_num)
+    }
+
+    /// Group holding `inode_num` and the absolute byte offset of its inode table slot
+    pub fn locate_inode(&self, inode_num: u64) -> Result<(usize, u64), Error> {
+        if inode_num < 1 || inode_num > self.super_block.s_inodes_count as u64 {
+            return Err(Error::InvalidInput(format!("inode {} out of range", inode_num)));
         }
-        if chunk != u8::MAX {
-            while chunk & 1 != 0 {
-                chunk >>= 1;
-                inum += 1;
-            }
-        } else {
-            return None;
+        let inode_size = self.super_block.s_inode_size as u64;
+        let group = self.block_groups.get_inode_group(inode_num, &self.disk)?;
+        let offset = group.inode_offset(inode_num, inode_size, self.get_block_size());
+        Ok((group.group_num, offset.value()?))
+    }
+
+    /// Store `raw` in the inode table slot of `inode_num`, bytes past the struct are left alone
+    pub fn write_inode_struct(&self, inode_num: u64, raw: &Ext2InodeStruct) -> Result<(), Error> {
+        if inode_num < 1 || inode_num > self.super_block.s_inodes_count as u64 {
+            return Err(Error::InvalidInput(format!("inode {} out of range", inode_num)));
         }
-        inum += 1;
-        self.bitmap_set_bit(&mut bitmap, inum, true).ok()?;
-        self.set_inode_bitmap(inum as u64, &bitmap).ok()?;
-        self.set_group_free(group_num as u32, -1, 0).ok()?;
-        self.set_sb_free(-1, 0);
-        Some(inum as u64)
+        let inode_size = self.super_block.s_inode_size as u64;
+        let group = self.block_groups.get_inode_group(inode_num, &self.disk)?;
+        let offset = group.inode_offset(inode_num, inode_size, self.get_block_size());
+        self.disk.write_at(&offset, to_slice!(raw, Ext2InodeStruct))?;
+        Ok(())
+    }
+
+    /// Clear the whole inode table slot so no stale fields survive a reuse
+    fn zero_inode(&self, inode_num: u64) -> Result<(), Error> {
+        let inode_size = self.super_block.s_inode_size as u64;
+        let group = self.block_groups.get_inode_group(inode_num, &self.disk)?;
+        let offset = group.inode_offset(inode_num, inode_size, self.get_block_size());
+        self.disk.write_at(&offset, &vec![0u8; inode_size as usize])?;
+        Ok(())
     }
 
     pub fn set_group_free(
@@ -398,80 +1532,229 @@ impl Ext2Filesystem {
         let block_size = self.get_block_size();
         let offset = Offset::new_offset(
             block_size,
-            if block_size == 1024 { 2 } else { 1 },
+            self.super_block.group_desc_block(),
             group_num as u64 * size,
         );
         let buffer = self.disk.read_at(&offset, size)?;
         let mut desc = buffer.as_slice().read_struct::<Ext2GroupDesc>()?;
-        let mut bg_free_blocks_count = desc.bg_free_blocks_count as i64;
-        if bg_free_blocks_count != 0 {
-            bg_free_blocks_count += block_free
-        }
-        desc.bg_free_blocks_count = bg_free_blocks_count as u16;
+        // Clamp at zero instead of skipping, a full group can get blocks back
+        let bg_free_blocks_count = desc.bg_free_blocks_count as i64 + block_free;
+        desc.bg_free_blocks_count = bg_free_blocks_count.max(0) as u16;
 
-        let mut bg_free_inodes_count = desc.bg_free_inodes_count as i64;
-        if bg_free_inodes_count != 0 {
-            bg_free_inodes_count += inode_free
-        }
-        desc.bg_free_inodes_count = bg_free_inodes_count as u16;
+        let bg_free_inodes_count = desc.bg_free_inodes_count as i64 + inode_free;
+        desc.bg_free_inodes_count = bg_free_inodes_count.max(0) as u16;
         self.disk
             .write_at(&offset, to_slice!(&desc, Ext2GroupDesc))?;
         Ok(())
     }
 
-    pub fn get_block_num(&self, block_num: u64, level: u32) -> Vec<u64> {
+    /// Adjust the directory count of the group holding `inode_num`
+    fn set_group_dirs(&self, inode_num: u64, dirs: i64) -> Result<(), Error> {
+        let group_num = (inode_num - 1) / self.super_block.s_inodes_per_group as u64;
+        let size = EXT2_GROUP_DESC_SIZE as u64;
+        let block_size = self.get_block_size();
+        let offset = Offset::new_offset(
+            block_size,
+            self.super_block.group_desc_block(),
+            group_num * size,
+        );
+        let buffer = self.disk.read_at(&offset, size)?;
+        let mut desc = buffer.as_slice().read_struct::<Ext2GroupDesc>()?;
+        desc.bg_used_dirs_count = (desc.bg_used_dirs_count as i64 + dirs).max(0) as u16;
+        self.disk
+            .write_at(&offset, to_slice!(&desc, Ext2GroupDesc))?;
+        Ok(())
+    }
+
+    /// Block numbers listed under the index block `block_num`, `level` tables deep.
+    /// Corrupt trees (loops, out of range pointers, more blocks than the disk holds) are errors.
+    pub fn get_block_num(&self, block_num: u64, level: u32) -> Result<Vec<u64>, Error> {
         assert!(level > 0 && level <= 3);
-        let buffer = self.read_block(block_num).unwrap();
+        let mut visited = BTreeSet::new();
         let mut blocks = Vec::new();
+        self.collect_block_num(block_num, level, &mut visited, &mut blocks)?;
+        Ok(blocks)
+    }
+    fn collect_block_num(
+        &self,
+        block_num: u64,
+        level: u32,
+        visited: &mut BTreeSet<u64>,
+        blocks: &mut Vec<u64>,
+    ) -> Result<(), Error> {
+        let blocks_count = self.super_block.s_blocks_count as u64;
+        if block_num == 0 || block_num >= blocks_count {
+            return Err(Error::InvalidData(format!("index block {} out of range", block_num)));
+        }
+        if !visited.insert(block_num) {
+            return Err(Error::InvalidData(format!("index block {} referenced twice", block_num)));
+        }
+        let buffer = self.read_block(block_num)?;
         let mut bytes = buffer.as_slice();
-        for _ in 0..buffer.len() / 4 {
+        for _ in 0..self.pointers_per_block() {
             let block = int_get!(bytes, u32);
             if block == 0 {
                 break;
             }
             if level == 1 {
+                if blocks.len() as u64 >= blocks_count {
+                    return Err(Error::InvalidData(format!("index block {} lists too many blocks", block_num)));
+                }
                 blocks.push(block as u64)
+            } else {
+                self.collect_block_num(block as u64, level - 1, visited, blocks)?;
             }
-            if level == 2 {
-                let b = self.get_block_num(block as u64, 1);
-                blocks.extend(b)
+        }
+        Ok(())
+    }
+    /// Where the next data block pointer goes in the `[indirect, doubly, triply]` tree:
+    /// the index block and the byte offset of its first unused slot.
+    /// None when that slot needs an index block which is not allocated yet.
+    pub fn indirect_block_table_offset(&self, block_table: [u64; 3]) -> Result<Option<(u64, usize)>, Error> {
+        for (level, table) in block_table.into_iter().enumerate() {
+            if table == 0 {
+                return Ok(None);
             }
-            if level == 3 {
-                for blk_num in self.get_block_num(block as u64, 1) {
-                    let b = self.get_block_num(blk_num, 1);
-                    blocks.extend(b)
-                }
+            if let Some(slot) = self.last_table_slot(table, level as u32 + 1)? {
+                return Ok(Some(slot));
             }
         }
-        blocks
+        Ok(None)
     }
-    pub fn indirect_block_table_offset(&self, block_table: [u64; 3]) -> Option<(u64, usize)> {
+    /// Free slot in the last level 1 table below `table`, only the last entry
+    /// of each level can be partly filled since blocks are appended in order
+    fn last_table_slot(&self, table: u64, level: u32) -> Result<Option<(u64, usize)>, Error> {
         let blk_num_size = core::mem::size_of::<u32>();
-        let b1 = self.get_block_num(block_table[0], 1);
-        if b1.len() < 1024 / blk_num_size {
-            return Some((block_table[0], (b1.len() - 1) * blk_num_size));
-        }
-        let b1 = self.get_block_num(block_table[1], 1);
-        for b2 in b1 {
-            let blocks = self.get_block_num(b2, 1);
-            if blocks.len() < 1024 / blk_num_size {
-                return Some((b2, (blocks.len()) * blk_num_size));
-            }
-        }
-        let b1 = self.get_block_num(block_table[2], 1);
-        for b2 in b1 {
-            let blocks2 = self.get_block_num(b2, 1);
-            for b3 in &blocks2 {
-                if blocks2.len() < 1024 / blk_num_size {
-                    return Some((*b3, (blocks2.len()) * blk_num_size));
+        let fanout = self.pointers_per_block() as usize;
+        let entries = self.get_block_num(table, 1)?;
+        if level == 1 {
+            return match entries.len() < fanout {
+                true => Ok(Some((table, entries.len() * blk_num_size))),
+                false => Ok(None),
+            };
+        }
+        match entries.last() {
+            Some(&last) => self.last_table_slot(last, level - 1),
+            None => Ok(None),
+        }
+    }
+    pub fn set_sb_free(&mut self, inode_free: i64, block_free: i64) -> Result<(), Error> {
+        let clamp = |count: u32, delta: i64| (count as i64 + delta).clamp(0, u32::MAX as i64) as u32;
+        self.super_block.s_free_blocks_count = clamp(self.super_block.s_free_blocks_count, block_free);
+        self.super_block.s_free_inodes_count = clamp(self.super_block.s_free_inodes_count, inode_free);
+        self.super_block.write(self.disk.as_ref())
+    }
+
+    /// Replace the on-disk free counts with the ones of the scanned bitmaps.
+    /// The superblock is only updated in memory, mount writes it unless read-only.
+    fn apply_scanned_counts(&mut self) -> Result<(), Error> {
+        let Some(bitmaps) = &self.bitmaps else {
+            return Ok(());
+        };
+        let counts = bitmaps.borrow().free_counts(self);
+        self.super_block.s_free_blocks_count = counts.iter().map(|(blocks, _)| blocks).sum::<u64>() as u32;
+        self.super_block.s_free_inodes_count = counts.iter().map(|(_, inodes)| inodes).sum::<u64>() as u32;
+        if self.read_only {
+            return Ok(());
+        }
+        for (group, (blocks, inodes)) in counts.into_iter().enumerate() {
+            let desc = self.block_groups.fetch_group_desc(group as u64, &self.disk)?;
+            self.set_group_free(
+                group as u32,
+                inodes as i64 - desc.bg_free_inodes_count as i64,
+                blocks as i64 - desc.bg_free_blocks_count as i64,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Mark `blocks` free after the backing disk grew.
+    /// The range has to start at or past the current last block and stay inside
+    /// the existing block groups, s_blocks_count is extended to cover it.
+    pub fn register_free_blocks(&mut self, blocks: Range<u64>) -> Result<(), Error> {
+        let first = self.super_block.s_first_data_block as u64;
+        let per_group = self.super_block.s_blocks_per_group as u64;
+        let capacity = first + self.get_groups_count() as u64 * per_group;
+        if blocks.start < self.get_blocks_count() || blocks.end > capacity {
+            return Err(Error::InvalidInput(format!(
+                "blocks {:?} outside {}..{}",
+                blocks,
+                self.get_blocks_count(),
+                capacity
+            )));
+        }
+        let mut block = blocks.start;
+        while block < blocks.end {
+            let group = (block - first) / per_group;
+            let group_end = core::cmp::min(blocks.end, first + (group + 1) * per_group);
+            let mut bitmap = self.get_block_bitmap(group)?;
+            let mut freed = 0;
+            for b in block..group_end {
+                if bitmap.set(((b - first) % per_group) as usize, false) {
+                    freed += 1;
                 }
             }
+            self.set_block_bitmap(group, &bitmap)?;
+            self.set_group_free(group as u32, 0, freed)?;
+            self.super_block.s_free_blocks_count += freed as u32;
+            block = group_end;
         }
-        None
+        if blocks.end > self.get_blocks_count() {
+            self.super_block.s_blocks_count = blocks.end as u32;
+        }
+        self.super_block.write(self.disk.as_ref())
+    }
+
+    /// Set the large_file feature once a file size no longer fits in 31 bits
+    pub fn set_large_file(&mut self, size: u64) -> Result<(), Error> {
+        if size > i32::MAX as u64 && !self.super_block.has_large_file() {
+            self.super_block.s_feature_ro_compat |= FeatureRoCompat::LARGE_FILE.bits();
+            self.super_block.write(self.disk.as_ref())?;
+        }
+        Ok(())
     }
-    pub fn set_sb_free(&mut self, inode_free: i64, block_free: i64) {
-        self.super_block.s_free_blocks_count = (self.super_block.s_free_blocks_count as i64 + block_free) as u32;
-        self.super_block.s_free_inodes_count = (self.super_block.s_free_inodes_count as i64 + inode_free) as u32;
-        self.super_block.write(self.disk.as_ref());
+
+    /// Write the in-memory superblock, write back cached blocks and flush the disk
+    /// Nothing to do on a read-only mount.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.flush_block_bitmap()?;
+        let offset = Offset::new(Ext2SuperBlock::SUPER_BLOCK_SIZE, Ext2SuperBlock::SUPER_BLOCK);
+        self.disk.write_at(&offset, to_slice!(&self.super_block, Ext2SuperBlock))?;
+        self.disk.flush()
+    }
+
+    /// Mounted with `MountOptions::read_only` or on a metadata_csum filesystem
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether the mount count or check interval says a consistency check is due.
+    /// There is no clock, the interval is measured up to the last mount or write time.
+    pub fn needs_check(&self) -> bool {
+        let sb = &self.super_block;
+        // A negative maximum disables the mount count check
+        let max_mnt_count = sb.s_max_mnt_count as i16;
+        if max_mnt_count > 0 && sb.s_mnt_count >= max_mnt_count as u16 {
+            return true;
+        }
+        let now = sb.s_wtime.max(sb.s_mtime);
+        sb.s_checkinterval > 0 && (sb.s_lastcheck as u64 + sb.s_checkinterval as u64) < now as u64
+    }
+
+    /// Sync and mark the filesystem clean. Dropping without unmounting leaves it
+    /// marked not clean. There is no clock, the write time becomes the newest time known.
+    pub fn unmount(mut self) -> Result<(), Error> {
+        self.super_block.s_state |= EXT2_VALID_FS;
+        self.super_block.s_wtime = self.super_block.s_wtime.max(self.super_block.s_mtime);
+        self.sync()
+    }
+}
+
+impl Drop for Ext2Filesystem {
+    fn drop(&mut self) {
+        let _ = self.sync();
     }
 }
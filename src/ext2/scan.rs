@@ -0,0 +1,134 @@
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::ext2::bitmap::BitMap;
+use crate::ext2::Ext2Filesystem;
+use crate::ext2::group::EXT2_GROUP_DESC_SIZE;
+use crate::ext2::inode::{EXT2_IND_BLOCK, EXT2_N_BLOCKS};
+use crate::fs::error::Error;
+
+/// Block and inode bitmaps rebuilt from the inodes instead of read from disk
+#[derive(Debug)]
+pub struct ScannedBitmaps {
+    pub blocks: Vec<BitMap>,
+    pub inodes: Vec<BitMap>,
+}
+
+impl ScannedBitmaps {
+    /// Mark every block the group metadata and the in-use inodes reference
+    pub fn scan(fs: &Ext2Filesystem) -> Result<ScannedBitmaps, Error> {
+        let sb = &fs.super_block;
+        let block_size = sb.get_block_size() as usize;
+        let groups = sb.get_groups_count();
+        let mut bitmaps = ScannedBitmaps {
+            blocks: vec![BitMap::zeroed(block_size); groups],
+            inodes: vec![BitMap::zeroed(block_size); groups],
+        };
+        let first = sb.s_first_data_block as u64;
+        let per_group = sb.s_blocks_per_group as u64;
+        let blocks_count = sb.s_blocks_count as u64;
+        // Bits past the end of the disk and of the group stay set, like mke2fs does
+        for bit in blocks_count - first..groups as u64 * per_group {
+            bitmaps.set_block(fs, first + bit);
+        }
+        let inodes_per_group = sb.s_inodes_per_group as usize;
+        for group in bitmaps.inodes.iter_mut() {
+            for bit in inodes_per_group..group.len() {
+                group.set(bit, true);
+            }
+        }
+        let desc_blocks = (groups * EXT2_GROUP_DESC_SIZE).div_ceil(block_size) as u64;
+        let inode_table_blocks = (inodes_per_group * sb.s_inode_size as usize).div_ceil(block_size) as u64;
+        for group in 0..groups as u64 {
+            if sb.has_backup_in_group(group) {
+                let start = first + group * per_group;
+                let end = start + 1 + desc_blocks + sb.s_reserved_gdt_blocks as u64;
+                for block in start..end {
+                    bitmaps.set_block(fs, block);
+                }
+            }
+            let desc = fs.block_groups.fetch_group_desc(group, &fs.disk)?;
+            bitmaps.set_block(fs, desc.bg_block_bitmap as u64);
+            bitmaps.set_block(fs, desc.bg_inode_bitmap as u64);
+            let table = desc.bg_inode_table as u64;
+            for block in table..table + inode_table_blocks {
+                bitmaps.set_block(fs, block);
+            }
+        }
+        for inode_num in 1..=sb.s_inodes_count as u64 {
+            // Reserved inodes count as used even when empty
+            let reserved = inode_num < sb.s_first_ino as u64;
+            let inode = match fs.read_inode(inode_num) {
+                Ok(inode) => inode,
+                Err(_) if !reserved => continue,
+                Err(e) => return Err(e),
+            };
+            if !reserved && inode.ext2_inode.i_links_count == 0 {
+                continue;
+            }
+            let group = ((inode_num - 1) / inodes_per_group as u64) as usize;
+            let bit = ((inode_num - 1) % inodes_per_group as u64) as usize;
+            bitmaps.inodes[group].set(bit, true);
+            // Fast symlinks keep their target in i_block and own no blocks
+            if inode.ext2_inode.i_blocks == 0 {
+                continue;
+            }
+            let i_block = inode.ext2_inode.i_block;
+            for (i, &block) in i_block.iter().enumerate().take(EXT2_N_BLOCKS) {
+                let level = i.saturating_sub(EXT2_IND_BLOCK - 1) as u32;
+                bitmaps.mark_tree(fs, block as u64, level)?;
+            }
+            bitmaps.mark_tree(fs, inode.file_acl() as u64, 0)?;
+        }
+        Ok(bitmaps)
+    }
+
+    /// Free blocks and free inodes of every group according to the scan
+    pub fn free_counts(&self, fs: &Ext2Filesystem) -> Vec<(u64, u64)> {
+        let blocks_per_group = fs.super_block.s_blocks_per_group as usize;
+        let inodes_per_group = fs.super_block.s_inodes_per_group as usize;
+        let clear = |bitmap: &BitMap, bits: usize| (0..bits.min(bitmap.len())).filter(|&bit| !bitmap.get(bit)).count() as u64;
+        self.blocks
+            .iter()
+            .zip(&self.inodes)
+            .map(|(blocks, inodes)| (clear(blocks, blocks_per_group), clear(inodes, inodes_per_group)))
+            .collect()
+    }
+
+    /// Mark `block` and, for index blocks, everything `level` tables below it
+    fn mark_tree(&mut self, fs: &Ext2Filesystem, block: u64, level: u32) -> Result<(), Error> {
+        if block == 0 {
+            return Ok(());
+        }
+        if block >= fs.super_block.s_blocks_count as u64 {
+            return Err(Error::InvalidData(format!("block {} out of range", block)));
+        }
+        // A block seen twice is shared or part of a loop, do not walk it again
+        if !self.set_block(fs, block) || level == 0 {
+            return Ok(());
+        }
+        let buffer = fs.read_block(block)?;
+        for entry in buffer.chunks_exact(4) {
+            let child = u32::from_le_bytes(entry.try_into().expect("incorrect length"));
+            self.mark_tree(fs, child as u64, level - 1)?;
+        }
+        Ok(())
+    }
+
+    /// Set the bit of `block`, false when it was set already
+    fn set_block(&mut self, fs: &Ext2Filesystem, block: u64) -> bool {
+        let first = fs.super_block.s_first_data_block as u64;
+        let per_group = fs.super_block.s_blocks_per_group as u64;
+        if block < first {
+            return true;
+        }
+        let group = ((block - first) / per_group) as usize;
+        let bit = ((block - first) % per_group) as usize;
+        let Some(bitmap) = self.blocks.get_mut(group) else {
+            return true;
+        };
+        !bitmap.set(bit, true)
+    }
+}
+
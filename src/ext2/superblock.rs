@@ -1,11 +1,67 @@
 use alloc::string::ToString;
 use core::mem;
 
+use crate::ext2::csum::crc32c;
 use crate::fs::disk::{Disk, Offset};
 use crate::fs::error::Error;
 use crate::fs::io::CoreRead;
 use crate::to_slice;
 
+bitflags::bitflags! {
+    /// Features an implementation may ignore and still read and write safely
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct FeatureCompat: u32 {
+        const DIR_PREALLOC = 0x0001;
+        const IMAGIC_INODES = 0x0002;
+        const HAS_JOURNAL = 0x0004;
+        const EXT_ATTR = 0x0008;
+        const RESIZE_INODE = 0x0010;
+        const DIR_INDEX = 0x0020;
+        const SPARSE_SUPER2 = 0x0200;
+    }
+}
+
+bitflags::bitflags! {
+    /// Features an implementation has to understand to mount at all
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct FeatureIncompat: u32 {
+        const COMPRESSION = 0x0001;
+        const FILETYPE = 0x0002;
+        const RECOVER = 0x0004;
+        const JOURNAL_DEV = 0x0008;
+        const META_BG = 0x0010;
+        const EXTENTS = 0x0040;
+        const BIT64 = 0x0080;
+        const MMP = 0x0100;
+        const FLEX_BG = 0x0200;
+        const EA_INODE = 0x0400;
+        const DIRDATA = 0x1000;
+        const CSUM_SEED = 0x2000;
+        const LARGEDIR = 0x4000;
+        const INLINE_DATA = 0x8000;
+        const ENCRYPT = 0x10000;
+    }
+}
+
+bitflags::bitflags! {
+    /// Features an implementation has to understand to mount read-write
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct FeatureRoCompat: u32 {
+        const SPARSE_SUPER = 0x0001;
+        const LARGE_FILE = 0x0002;
+        const BTREE_DIR = 0x0004;
+        const HUGE_FILE = 0x0008;
+        const GDT_CSUM = 0x0010;
+        const DIR_NLINK = 0x0020;
+        const EXTRA_ISIZE = 0x0040;
+        const QUOTA = 0x0100;
+        const BIGALLOC = 0x0200;
+        const METADATA_CSUM = 0x0400;
+        const READONLY = 0x1000;
+        const PROJECT = 0x2000;
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct Ext2SuperBlock {
@@ -79,16 +135,122 @@ pub struct Ext2SuperBlock {
     // Nr to preallocate for dirs
     pub s_reserved_gdt_blocks: u16,
     // Per group table for online growth
-    s_reserved: [u32; 204],
+    // -- EXT3_FEATURE_COMPAT_HAS_JOURNAL only ---
+    pub s_journal_uuid: [u8; 16],
+    // uuid of journal superblock
+    pub s_journal_inum: u32,
+    // inode number of journal file
+    pub s_journal_dev: u32,
+    // device number of journal file
+    pub s_last_orphan: u32,
+    // start of list of inodes to delete
+    // -- Directory indexing support ---
+    pub s_hash_seed: [u32; 4],
+    // HTREE hash seed
+    pub s_def_hash_version: u8,
+    // Default hash version to use
+    pub s_jnl_backup_type: u8,
+    // Whether s_jnl_blocks holds a backup of the journal inode
+    pub s_desc_size: u16,
+    // Size of group descriptors, if 64bit
+    pub s_default_mount_opts: u32,
+    // Default mount options
+    pub s_first_meta_bg: u32,
+    // First metablock block group
+    pub s_mkfs_time: u32,
+    // When the filesystem was created
+    pub s_jnl_blocks: [u32; 17],
+    // Backup of the journal inode
+    // -- 64bit support ---
+    pub s_blocks_count_hi: u32,
+    // Blocks count high 32 bits
+    pub s_r_blocks_count_hi: u32,
+    // Reserved blocks count high 32 bits
+    pub s_free_blocks_count_hi: u32,
+    // Free blocks count high 32 bits
+    pub s_min_extra_isize: u16,
+    // All inodes have at least # bytes
+    pub s_want_extra_isize: u16,
+    // New inodes should reserve # bytes
+    pub s_flags: u32,
+    // Miscellaneous flags
+    s_reserved: [u32; 167],
 }
 
+// The on-disk superblock is exactly 1024 bytes
+const _: () = assert!(mem::size_of::<Ext2SuperBlock>() == Ext2SuperBlock::SUPER_BLOCK_SIZE as usize);
+
 impl Ext2SuperBlock {
     pub const SUPER_BLOCK_SIZE: u64 = 1024;
     pub const SUPER_BLOCK: u64 = 1;
     pub const MAGIC: u16 = 0xef53;
+    // ext3 journal, the image may need a replay this crate does not do
+    pub fn has_journal(&self) -> bool {
+        FeatureCompat::from_bits_retain(self.s_feature_compat).contains(FeatureCompat::HAS_JOURNAL)
+    }
+    // Directory entries record the file type
+    pub fn has_filetype(&self) -> bool {
+        FeatureIncompat::from_bits_retain(self.s_feature_incompat).contains(FeatureIncompat::FILETYPE)
+    }
+    // Files may be larger than 2 GiB, i_size_high holds the upper 32 bits
+    pub fn has_large_file(&self) -> bool {
+        FeatureRoCompat::from_bits_retain(self.s_feature_ro_compat).contains(FeatureRoCompat::LARGE_FILE)
+    }
+    // First non-reserved inode, fixed at 11 before dynamic revisions
+    pub fn first_ino(&self) -> u32 {
+        match self.s_rev_level {
+            0 => 11,
+            _ => self.s_first_ino,
+        }
+    }
+    // Superblock and group descriptor backups only in groups 0, 1 and powers of 3, 5 and 7
+    pub fn has_sparse_super(&self) -> bool {
+        FeatureRoCompat::from_bits_retain(self.s_feature_ro_compat).contains(FeatureRoCompat::SPARSE_SUPER)
+    }
+    // Whether a group starts with a copy of the superblock and group descriptors,
+    // every group without sparse_super
+    pub fn has_backup_in_group(&self, group: u64) -> bool {
+        let is_power_of = |base: u64| {
+            let mut n = base;
+            while n < group {
+                match n.checked_mul(base) {
+                    Some(next) => n = next,
+                    None => return false,
+                }
+            }
+            n == group
+        };
+        !self.has_sparse_super() || group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+    }
+    // Metadata blocks and inodes carry crc32c checksums
+    pub fn has_metadata_csum(&self) -> bool {
+        FeatureRoCompat::from_bits_retain(self.s_feature_ro_compat).contains(FeatureRoCompat::METADATA_CSUM)
+    }
+    // Seed every metadata checksum starts from, None without metadata_csum
+    pub fn csum_seed(&self) -> Option<u32> {
+        match self.has_metadata_csum() {
+            true => Some(crc32c(!0, &self.s_uuid)),
+            false => None,
+        }
+    }
+    pub fn feature_compat(&self) -> FeatureCompat {
+        FeatureCompat::from_bits_retain(self.s_feature_compat)
+    }
+    pub fn feature_incompat(&self) -> FeatureIncompat {
+        FeatureIncompat::from_bits_retain(self.s_feature_incompat)
+    }
+    pub fn feature_ro_compat(&self) -> FeatureRoCompat {
+        FeatureRoCompat::from_bits_retain(self.s_feature_ro_compat)
+    }
+    // The group descriptor table follows the block holding the superblock
+    pub fn group_desc_block(&self) -> u64 {
+        self.s_first_data_block as u64 + 1
+    }
     pub fn get_groups_count(&self) -> usize {
-        let count = (self.s_blocks_count / self.s_blocks_per_group) as usize;
-        match self.s_blocks_count % self.s_blocks_per_group == 0 {
+        // Groups start counting at the first data block
+        let blocks = self.s_blocks_count - self.s_first_data_block;
+        let count = (blocks / self.s_blocks_per_group) as usize;
+        match blocks.is_multiple_of(self.s_blocks_per_group) {
             true => count,
             false => count + 1
         }
@@ -99,8 +261,12 @@ impl Ext2SuperBlock {
     }
     // Read the Superblock
     pub fn new(disk: &dyn Disk) -> Result<Ext2SuperBlock, Error> {
+        Self::new_at(disk, Self::SUPER_BLOCK_SIZE * Self::SUPER_BLOCK)
+    }
+    // Read a superblock copy starting at byte `offset`
+    pub fn new_at(disk: &dyn Disk, offset: u64) -> Result<Ext2SuperBlock, Error> {
         assert_eq!(mem::size_of::<Ext2SuperBlock>(), Self::SUPER_BLOCK_SIZE as usize);
-        let offset = Offset::new(Self::SUPER_BLOCK_SIZE, Self::SUPER_BLOCK);
+        let offset = Offset::absolute(offset);
         let buffer = disk.read_at(&offset, Self::SUPER_BLOCK_SIZE)?;
         let super_block = buffer.as_slice().read_struct::<Ext2SuperBlock>()?;
         // Check ext2 signature
@@ -110,8 +276,38 @@ impl Ext2SuperBlock {
             Err(Error::InvalidData("Invalid filesystem".to_string()))
         }
     }
-    pub fn write(&self, disk: &dyn Disk) {
+    pub fn write(&self, disk: &dyn Disk) -> Result<(), Error> {
         let offset = Offset::new(Self::SUPER_BLOCK_SIZE, Self::SUPER_BLOCK);
-        disk.write_at(&offset, to_slice!(self, Self)).unwrap();
+        disk.write_at(&offset, to_slice!(self, Self))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemDisk, Mkfs};
+    use alloc::vec::Vec;
+    use core::mem::offset_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(mem::size_of::<Ext2SuperBlock>(), 1024);
+        assert_eq!(offset_of!(Ext2SuperBlock, s_uuid), 0x68);
+        assert_eq!(offset_of!(Ext2SuperBlock, s_journal_inum), 0xe0);
+        assert_eq!(offset_of!(Ext2SuperBlock, s_default_mount_opts), 0x100);
+        assert_eq!(offset_of!(Ext2SuperBlock, s_first_meta_bg), 0x104);
+        assert_eq!(offset_of!(Ext2SuperBlock, s_blocks_count_hi), 0x150);
+    }
+
+    #[test]
+    fn sparse_super_backup_groups() {
+        let disk = MemDisk::new(Mkfs::default().build());
+        let mut super_block = Ext2SuperBlock::new(&disk).unwrap();
+        assert!(super_block.has_sparse_super());
+        let backups: Vec<u64> = (0..400).filter(|&group| super_block.has_backup_in_group(group)).collect();
+        assert_eq!(backups, [0, 1, 3, 5, 7, 9, 25, 27, 49, 81, 125, 243, 343]);
+        super_block.s_feature_ro_compat &= !FeatureRoCompat::SPARSE_SUPER.bits();
+        assert!((0..400).all(|group| super_block.has_backup_in_group(group)));
     }
 }
@@ -0,0 +1,1655 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::ext2::bitmap::BitMap;
+use crate::ext2::csum::{crc32c, inode_checksum_valid};
+use crate::ext2::inode::{BlockData, Ext2Inode, Ext2InodeStruct};
+use crate::ext2::superblock::{Ext2SuperBlock, FeatureRoCompat};
+use crate::ext2::{Ext2Filesystem, MountOptions, EXT2_ROOT_INO};
+use crate::ext2::dir::Ext2DirEntry;
+use crate::fs::error::Error;
+use crate::fs::faulty::FaultyDisk;
+use crate::fs::io::CoreRead;
+use crate::fs::stat::{FileFlags, Mode};
+use crate::testing::{create_file, mount_default, pattern, MemDisk, Mkfs, MKFS_TIME};
+
+/// Add an entry for `inode_num` to the directory `dir`, the link count is left alone
+fn link(fs: &Ext2Filesystem, dir: &str, name: &str, inode_num: u64, file_type: u8) {
+    let (dir, _) = fs.resolve(dir).unwrap();
+    let (block, offset) = dir.find_last_dir_entry(&fs.disk, name.len(), true).unwrap();
+    let buffer = fs.read_block(block).unwrap();
+    fs.add_dir_entry(block, offset, &buffer, name, inode_num, file_type).unwrap();
+}
+
+/// Point the `..` entry of the directory `dir` at `parent`
+fn set_dotdot(fs: &Ext2Filesystem, dir: &str, parent: u64) {
+    let (dir, _) = fs.resolve(dir).unwrap();
+    fs.write_block(dir.blocks()[0] as u64, 12, &(parent as u32).to_le_bytes()).unwrap();
+}
+
+#[test]
+fn sync_survives_remount() {
+    let (mut fs, disk) = mount_default();
+    let free = fs.describe().free_blocks;
+    fs.mk_dir("/dir", 0o755).unwrap();
+    create_file(&mut fs, "/dir/file", &pattern(3000));
+    fs.sync().unwrap();
+    drop(fs);
+    let fs = Ext2Filesystem::mount(Box::new(disk)).unwrap();
+    assert!(fs.metadata("/dir").unwrap().is_dir());
+    let inode = fs.metadata("/dir/file").unwrap().ino;
+    assert_eq!(fs.pread(inode, 0, 4096).unwrap(), pattern(3000));
+    // One block for the directory, three for the file
+    assert_eq!(fs.describe().free_blocks, free - 4);
+    assert_eq!(fs.verify_links().unwrap(), vec![]);
+}
+
+#[test]
+fn open_at_resolves_from_a_subdirectory() {
+    let (mut fs, _) = mount_default();
+    assert_eq!(fs.root_inode_num(), 2);
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    let file = create_file(&mut fs, "/a/b/file", b"data");
+    let a = fs.metadata("/a").unwrap().ino;
+    assert_eq!(fs.open_at(a, "b/file").unwrap().inode(), file);
+    assert_eq!(fs.open_at(a, "b/../b/file").unwrap().inode(), file);
+    // A leading slash still starts at the real root
+    assert_eq!(fs.open_at(a, "/a").unwrap().inode(), a);
+    assert!(matches!(fs.open_at(file, "x"), Err(Error::InvalidInput(_))));
+    assert!(matches!(fs.open_at(a, "missing"), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn path_of_nested_inodes() {
+    let (mut fs, _) = mount_default();
+    let mut path = String::new();
+    for i in 0..8 {
+        path = format!("{}/d{}", path, i);
+        fs.mk_dir(&path, 0o755).unwrap();
+    }
+    let file = create_file(&mut fs, &format!("{}/file", path), b"x");
+    assert_eq!(fs.path_of(file).unwrap(), format!("{}/file", path));
+    let dir = fs.metadata(&path).unwrap().ino;
+    assert_eq!(fs.path_of(dir).unwrap(), path);
+    assert_eq!(fs.path_of(EXT2_ROOT_INO).unwrap(), "/");
+}
+
+#[test]
+fn path_of_stops_on_loops() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    let a = fs.metadata("/a").unwrap().ino;
+    let b = fs.metadata("/a/b").unwrap().ino;
+    // b links back to a and a's parent claims to be b
+    link(&fs, "/a/b", "up", a, 2);
+    set_dotdot(&fs, "/a", b);
+    assert!(matches!(fs.path_of(b), Err(Error::InvalidData(_))));
+    // The search for a file never linked anywhere ends instead of recursing forever
+    let orphan = create_file(&mut fs, "/orphan", b"x");
+    fs.remove_dir_entry(&fs.read_inode(EXT2_ROOT_INO).unwrap(), "orphan").unwrap();
+    assert!(matches!(fs.path_of(orphan), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn entry_types_come_from_the_entries() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/dir", 0o755).unwrap();
+    create_file(&mut fs, "/file", b"x");
+    let entries = fs.read_dir("/").unwrap();
+    assert!(entries["dir"].file_type().unwrap().is_dir());
+    assert!(entries["file"].file_type().unwrap().is_file());
+    assert!(entries["."].file_type().unwrap().is_dir());
+    // Without FILETYPE there is nothing to report
+    let (mut fs, _) = crate::testing::mount(Mkfs { filetype: false, ..Default::default() }.build());
+    create_file(&mut fs, "/file", b"x");
+    assert!(fs.read_dir("/").unwrap()["file"].file_type().is_none());
+}
+
+#[test]
+fn read_dir_raw_matches_read_dir() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/dir", 0o755).unwrap();
+    for name in ["a", "bb", "ccc", "dddd"] {
+        create_file(&mut fs, &format!("/dir/{}", name), name.as_bytes());
+    }
+    let (dir, _) = fs.resolve("/dir").unwrap();
+    let mut raw = dir.read_dir_raw(&fs.disk).unwrap();
+    raw.sort_by(|a, b| a.2.cmp(&b.2));
+    let entries = fs.read_dir("/dir").unwrap();
+    assert_eq!(raw.len(), entries.len());
+    for ((inode, file_type, name), (entry_name, entry)) in raw.iter().zip(entries.iter()) {
+        assert_eq!(name.as_slice(), entry_name.as_bytes());
+        assert_eq!(*inode, entry.inode_num());
+        assert_eq!(Ext2DirEntry::file_type_mode(*file_type).unwrap().bits(), entry.stat().mode.file_type().bits());
+    }
+}
+
+#[test]
+fn huge_inode_table_block_is_rejected() {
+    let mkfs = Mkfs::default();
+    let (fs, disk) = crate::testing::mount(mkfs.build());
+    drop(fs);
+    // bg_inode_table of group 0
+    disk.poke(2 * 1024 + 8, &u32::MAX.to_le_bytes());
+    let result = Ext2Filesystem::mount(Box::new(disk.clone()));
+    assert!(matches!(result, Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn out_of_range_entry_inode() {
+    let (fs, _) = mount_default();
+    link(&fs, "/", "bad", 100_000, 1);
+    assert!(matches!(fs.read_dir("/"), Err(Error::InvalidData(_))));
+    assert!(matches!(fs.read_inode(0), Err(Error::InvalidData(_))));
+    assert!(matches!(fs.read_inode(129), Err(Error::InvalidData(_))));
+    assert!(fs.read_inode(128).is_ok());
+    assert!(!fs.is_exist("/bad"));
+}
+
+#[test]
+fn glob_patterns() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/etc", 0o755).unwrap();
+    fs.mk_dir("/etc/sub", 0o755).unwrap();
+    for name in ["host.conf", "resolv.conf", "hosts", "sub/x.conf"] {
+        create_file(&mut fs, &format!("/etc/{}", name), b"x");
+    }
+    assert_eq!(fs.glob("/etc/*.conf").unwrap(), ["/etc/host.conf", "/etc/resolv.conf"]);
+    assert_eq!(fs.glob("/etc/host?").unwrap(), ["/etc/hosts"]);
+    assert_eq!(fs.glob("/*/*/*.conf").unwrap(), ["/etc/sub/x.conf"]);
+    assert_eq!(fs.glob("/etc/hosts").unwrap(), ["/etc/hosts"]);
+    assert_eq!(fs.glob("/etc/*.txt").unwrap(), Vec::<String>::new());
+    assert_eq!(fs.glob("/missing/*").unwrap(), Vec::<String>::new());
+    assert_eq!(fs.glob("/etc/hosts/*").unwrap(), Vec::<String>::new());
+    assert_eq!(fs.glob("/etc/*/x.conf").unwrap(), ["/etc/sub/x.conf"]);
+    // A corrupt directory is an error, not an empty match
+    let (sub, _) = fs.resolve("/etc/sub").unwrap();
+    fs.write_block(sub.blocks()[0] as u64, 4, &[0, 0]).unwrap();
+    assert!(matches!(fs.glob("/etc/*/*.conf"), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn walk_yields_the_tree() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    create_file(&mut fs, "/a/b/f1", b"1");
+    create_file(&mut fs, "/a/f2", b"2");
+    create_file(&mut fs, "/f3", b"3");
+    let paths: Vec<String> = fs.walk("/").unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(paths, ["/a", "/a/b", "/a/b/f1", "/a/f2", "/f3"]);
+    let paths: Vec<String> = fs.walk("/a").unwrap().max_depth(1).map(|entry| entry.unwrap().0).collect();
+    assert_eq!(paths, ["/a/b", "/a/f2"]);
+    assert!(fs.walk("/f3").is_err());
+}
+
+#[test]
+fn disk_usage_counts_hard_links_once() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    let shared = create_file(&mut fs, "/d/one", &pattern(3000));
+    link(&fs, "/d", "two", shared, 1);
+    fs.add_links(shared, 1).unwrap();
+    create_file(&mut fs, "/d/other", b"x");
+    // Directory, three blocks of the shared file and one of the other file, 2 sectors each
+    assert_eq!(fs.disk_usage("/d").unwrap(), (1 + 3 + 1) * 1024);
+    assert_eq!(fs.disk_usage("/d/two").unwrap(), 3 * 1024);
+}
+
+#[test]
+fn access_checks_permission_classes() {
+    let (mut fs, _) = mount_default();
+    create_file(&mut fs, "/file", b"x");
+    let mut inode = fs.resolve("/file").unwrap().0;
+    inode.ext2_inode.i_uid = 1000;
+    inode.ext2_inode.i_gid = 100;
+    inode.ext2_inode.i_mode = Mode::FILE.bits() | 0o640;
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+    // Owner reads and writes, the group only reads, others get nothing
+    assert!(fs.access("/file", 1000, 1, Mode::U_READ | Mode::U_WRITE).unwrap());
+    assert!(!fs.access("/file", 1000, 1, Mode::U_EXEC).unwrap());
+    assert!(fs.access("/file", 2000, 100, Mode::G_READ).unwrap());
+    assert!(!fs.access("/file", 2000, 100, Mode::G_WRITE).unwrap());
+    assert!(!fs.access("/file", 2000, 200, Mode::O_READ).unwrap());
+    assert!(fs.access("/file", 0, 0, Mode::U_READ | Mode::U_WRITE | Mode::U_EXEC).unwrap());
+}
+
+#[test]
+fn raw_inode_getters() {
+    let (mut fs, _) = mount_default();
+    create_file(&mut fs, "/file", b"x");
+    let (mut inode, _) = fs.resolve("/file").unwrap();
+    inode.ext2_inode.i_generation = 0xdead_beef;
+    inode.ext2_inode.i_file_acl = 77;
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+    let inode = fs.read_inode(inode.inode()).unwrap();
+    assert_eq!(inode.generation(), 0xdead_beef);
+    assert_eq!(inode.raw().i_generation, 0xdead_beef);
+    assert_eq!(inode.file_acl(), 77);
+    assert_eq!(inode.dir_acl(), 0);
+}
+
+#[test]
+fn relocate_a_data_block() {
+    let (mut fs, _) = mount_default();
+    let data = pattern(14 * 1024);
+    let inode_num = create_file(&mut fs, "/file", &data);
+    let mut inode = fs.read_inode(inode_num).unwrap();
+    for logical in [3, 13] {
+        let old = inode.get_block_ptr(&fs.disk, logical).unwrap();
+        let new = fs.alloc_block().unwrap();
+        fs.write_block_full(new as u64, &fs.read_block(old).unwrap()).unwrap();
+        inode.set_block_ptr(&mut fs, logical, new).unwrap();
+        fs.free_block(old).unwrap();
+        assert_eq!(fs.read_inode(inode_num).unwrap().get_block_ptr(&fs.disk, logical).unwrap(), new as u64);
+    }
+    assert_eq!(fs.pread(inode_num, 0, data.len() as u64).unwrap(), data);
+    assert!(fs.verify_links().unwrap().is_empty());
+}
+
+#[test]
+fn set_block_ptr_allocates_the_index_block() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", b"x");
+    let mut inode = fs.read_inode(inode_num).unwrap();
+    let sectors = inode.raw().i_blocks;
+    let block = fs.alloc_block().unwrap();
+    inode.set_block_ptr(&mut fs, 20, block).unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert_ne!(inode.raw().i_block[12], 0);
+    assert_eq!(inode.raw().i_blocks, sectors + 2);
+    assert_eq!(inode.get_block_ptr(&fs.disk, 20).unwrap(), block as u64);
+}
+
+#[test]
+fn crc32c_check_value() {
+    assert_eq!(crc32c(!0, b"123456789") ^ !0, 0xe306_9283);
+}
+
+#[test]
+fn inode_checksums() {
+    let mkfs = Mkfs { inode_size: 256, ..Mkfs::default() };
+    let (mut fs, disk) = crate::testing::mount(mkfs.build());
+    let inode_num = create_file(&mut fs, "/file", b"checksummed");
+    let offset = fs
+        .block_groups
+        .get_inode_group(inode_num, &fs.disk)
+        .unwrap()
+        .inode_offset(inode_num, 256, 1024)
+        .value()
+        .unwrap();
+    let seed = crc32c(!0, b"ext2-fs testimg!");
+    // i_extra_isize covering i_checksum_hi, then the full 32 bit checksum
+    disk.poke(offset + 0x80, &32u16.to_le_bytes());
+    let mut raw = disk.peek(offset, 256);
+    raw[0x7c..0x7e].fill(0);
+    raw[0x82..0x84].fill(0);
+    let mut crc = crc32c(seed, &(inode_num as u32).to_le_bytes());
+    crc = crc32c(crc, &raw[0x64..0x68]);
+    crc = crc32c(crc, &raw);
+    disk.poke(offset + 0x7c, &(crc as u16).to_le_bytes());
+    disk.poke(offset + 0x82, &((crc >> 16) as u16).to_le_bytes());
+    let read = |seed| Ext2Inode::new(&fs.disk, 256, 1024, &fs.block_groups, inode_num, seed);
+    assert!(inode_checksum_valid(seed, inode_num, &disk.peek(offset, 256)));
+    assert!(read(Some(seed)).is_ok());
+    assert!(matches!(read(Some(seed ^ 1)), Err(Error::InvalidData(_))));
+    // A flipped mode bit is caught, and ignored without metadata_csum
+    disk.poke(offset, &[disk.peek(offset, 1)[0] ^ 1]);
+    assert!(matches!(read(Some(seed)), Err(Error::InvalidData(_))));
+    assert!(read(None).is_ok());
+}
+
+#[test]
+fn set_times_reads_back() {
+    let (mut fs, _) = mount_default();
+    create_file(&mut fs, "/file", b"x");
+    fs.set_times("/file", Some(1_000), Some(2_000_000_000)).unwrap();
+    let stat = fs.metadata("/file").unwrap();
+    assert_eq!((stat.atime, stat.mtime), (1_000, 2_000_000_000));
+    assert!(stat.ctime >= 2_000_000_000);
+    // None keeps the current value
+    fs.set_times("/file", None, Some(5)).unwrap();
+    let stat = fs.metadata("/file").unwrap();
+    assert_eq!((stat.atime, stat.mtime), (1_000, 5));
+}
+
+#[test]
+fn ext3_journal_is_detected() {
+    let (fs, _) = mount_default();
+    assert!(!fs.has_journal());
+    assert_eq!(fs.journal_inode(), None);
+    let mut image = Mkfs::default().build();
+    // s_feature_compat and s_journal_inum of the primary superblock
+    image[1024 + 0x5c] |= 0x04;
+    image[1024 + 0xe0..1024 + 0xe4].copy_from_slice(&8u32.to_le_bytes());
+    let (fs, _) = crate::testing::mount(image);
+    assert!(fs.has_journal());
+    assert_eq!(fs.journal_inode(), Some(8));
+}
+
+#[test]
+fn register_free_blocks_after_growing_the_disk() {
+    let (mut fs, disk) = mount_default();
+    while fs.alloc_block().is_some() {}
+    assert_eq!(fs.describe().free_blocks, 0);
+    disk.grow(1024 * 1024);
+    assert!(fs.register_free_blocks(2048..8194).is_err());
+    fs.register_free_blocks(2048..3072).unwrap();
+    assert_eq!(fs.describe().free_blocks, 1024);
+    let block = fs.alloc_block().unwrap() as u64;
+    assert!((2048..3072).contains(&block));
+    fs.write_block_full(block, &[0x5a; 1024]).unwrap();
+    assert_eq!(fs.read_block(block).unwrap(), [0x5a; 1024]);
+    fs.sync().unwrap();
+    let fs = Ext2Filesystem::mount(Box::new(disk)).unwrap();
+    assert_eq!(fs.describe().blocks_count, 3072);
+    assert_eq!(fs.describe().free_blocks, 1023);
+}
+
+#[test]
+fn reused_blocks_are_zeroed() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", &[0xaa; 1024]);
+    let block = fs.read_inode(inode_num).unwrap().get_block_ptr(&fs.disk, 0).unwrap();
+    fs.remove_file("/file").unwrap();
+    assert_eq!(fs.read_block(block).unwrap(), [0xaa; 1024]);
+    assert_eq!(fs.alloc_block().unwrap() as u64, block);
+    assert_eq!(fs.read_block(block).unwrap(), [0; 1024]);
+    // Without zeroing the stale bytes stay
+    fs.write_block_full(block, &[0xbb; 1024]).unwrap();
+    fs.free_block(block).unwrap();
+    assert_eq!(fs.alloc_block_uninit().unwrap() as u64, block);
+    assert_eq!(fs.read_block(block).unwrap(), [0xbb; 1024]);
+}
+
+#[test]
+fn reused_inode_slot_is_zeroed() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", &pattern(3000));
+    fs.remove_file("/file").unwrap();
+    assert_eq!(fs.alloc_inode_num(), Some(inode_num));
+    let raw = *fs.read_inode(inode_num).unwrap().raw();
+    assert_eq!(raw.i_block, [0; 15]);
+    assert_eq!((raw.i_size, raw.i_blocks, raw.i_mode), (0, 0, 0));
+}
+
+#[test]
+fn open_dir_streams_entries() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    create_file(&mut fs, "/d/a", b"a");
+    create_file(&mut fs, "/d/b", b"b");
+    assert!(fs.open_dir("/d/a").is_err());
+    let mut handle = fs.open_dir("/d").unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = handle.read_entry().unwrap() {
+        names.push(entry.file_name());
+    }
+    assert_eq!(names, [".", "..", "a", "b"]);
+    assert!(handle.read_entry().unwrap().is_none());
+    handle.rewind();
+    assert_eq!(handle.read_entry().unwrap().unwrap().file_name(), ".");
+}
+
+#[test]
+fn block_map_reports_holes() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", b"head");
+    fs.open("/file").unwrap().truncate(6 * 1024).unwrap();
+    fs.pwrite(inode_num, 5 * 1024, b"tail").unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    let map = inode.block_map(&fs.disk).unwrap();
+    assert_eq!(map.iter().map(|&(logical, _)| logical).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5]);
+    assert!(map[1..5].iter().all(|&(_, physical)| physical == 0));
+    assert_ne!(map[0].1, 0);
+    assert_ne!(map[5].1, 0);
+    assert_eq!(fs.read_block(map[5].1).unwrap()[..4], *b"tail");
+}
+
+#[test]
+fn self_referencing_index_block() {
+    let (mut fs, _) = mount_default();
+    let table = fs.alloc_block().unwrap();
+    fs.write_block(table as u64, 0, &table.to_le_bytes()).unwrap();
+    assert!(matches!(fs.get_block_num(table as u64, 2), Err(Error::InvalidData(_))));
+    // A two block cycle three levels deep
+    let other = fs.alloc_block().unwrap();
+    fs.write_block(table as u64, 0, &other.to_le_bytes()).unwrap();
+    fs.write_block(other as u64, 0, &table.to_le_bytes()).unwrap();
+    assert!(matches!(fs.get_block_num(table as u64, 3), Err(Error::InvalidData(_))));
+    // Untrusted block numbers past the end of the disk
+    fs.write_block(other as u64, 0, &u32::MAX.to_le_bytes()).unwrap();
+    assert!(matches!(fs.get_block_num(table as u64, 3), Err(Error::InvalidData(_))));
+    assert!(matches!(fs.get_block_num(0, 1), Err(Error::InvalidData(_))));
+}
+
+/// An index block listing `entries`
+fn index_block(fs: &mut Ext2Filesystem, entries: &[u32]) -> u64 {
+    let block = fs.alloc_block().unwrap();
+    let bytes: Vec<u8> = entries.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+    fs.write_block(block as u64, 0, &bytes).unwrap();
+    block as u64
+}
+
+#[test]
+fn indirect_block_table_offset_crosses_levels() {
+    let (mut fs, _) = mount_default();
+    let data = fs.alloc_block().unwrap();
+    let partial = index_block(&mut fs, &[data; 3]);
+    let full = index_block(&mut fs, &[data; 256]);
+    assert_eq!(fs.indirect_block_table_offset([0, 0, 0]).unwrap(), None);
+    assert_eq!(fs.indirect_block_table_offset([partial, 0, 0]).unwrap(), Some((partial, 12)));
+    // Single indirect full, the next pointer needs a doubly indirect table
+    assert_eq!(fs.indirect_block_table_offset([full, 0, 0]).unwrap(), None);
+    let doubly = index_block(&mut fs, &[full as u32, partial as u32]);
+    assert_eq!(fs.indirect_block_table_offset([full, doubly, 0]).unwrap(), Some((partial, 12)));
+    let doubly_last_full = index_block(&mut fs, &[full as u32, full as u32]);
+    assert_eq!(fs.indirect_block_table_offset([full, doubly_last_full, 0]).unwrap(), None);
+    // Doubly indirect full, continue in the triply indirect tree
+    let doubly_full = index_block(&mut fs, &[full as u32; 256]);
+    assert_eq!(fs.indirect_block_table_offset([full, doubly_full, 0]).unwrap(), None);
+    let triply = index_block(&mut fs, &[doubly as u32]);
+    assert_eq!(fs.indirect_block_table_offset([full, doubly_full, triply]).unwrap(), Some((partial, 12)));
+}
+
+/// Offset, rec_len and name of every entry in the first block of `dir`
+fn entry_chain(fs: &Ext2Filesystem, dir: &str) -> Vec<(usize, usize, String)> {
+    let (dir, _) = fs.resolve(dir).unwrap();
+    let block = fs.read_block(dir.get_blocks(&fs.disk).unwrap()[0]).unwrap();
+    let mut chain = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        let rec_len = u16::from_le_bytes([block[offset + 4], block[offset + 5]]) as usize;
+        let name_len = block[offset + 6] as usize;
+        let name = String::from_utf8(block[offset + 8..offset + 8 + name_len].to_vec()).unwrap();
+        chain.push((offset, rec_len, name));
+        offset += rec_len;
+    }
+    assert_eq!(offset, block.len());
+    chain
+}
+
+#[test]
+fn interleaved_deletes_keep_the_chain() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    for i in 0..10 {
+        create_file(&mut fs, &format!("/d/file{}", i), b"x");
+    }
+    for i in (1..10).step_by(2) {
+        fs.remove_file(&format!("/d/file{}", i)).unwrap();
+    }
+    let names: Vec<String> = entry_chain(&fs, "/d").into_iter().map(|(_, _, name)| name).collect();
+    assert_eq!(names, [".", "..", "file0", "file2", "file4", "file6", "file8"]);
+    // Entries are packed, only the last one spans the rest of the block
+    let chain = entry_chain(&fs, "/d");
+    assert!(chain[..chain.len() - 1].iter().all(|(_, rec_len, name)| *rec_len == (8 + name.len()).next_multiple_of(4)));
+    for i in (0..10).step_by(2) {
+        fs.remove_file(&format!("/d/file{}", i)).unwrap();
+    }
+    let chain = entry_chain(&fs, "/d");
+    assert_eq!(chain.last().unwrap().1, 1024 - 12);
+    assert!(fs.verify_links().unwrap().is_empty());
+}
+
+#[test]
+fn double_free_inode() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", b"x");
+    let free = fs.describe().free_inodes;
+    fs.free_inode(inode_num).unwrap();
+    assert!(matches!(fs.free_inode(inode_num), Err(Error::InvalidData(_))));
+    assert_eq!(fs.describe().free_inodes, free + 1);
+}
+
+#[test]
+fn remove_file_releases_the_acl_block() {
+    let (mut fs, _) = mount_default();
+    let acl = fs.alloc_block().unwrap();
+    // h_magic and an h_refcount shared by two inodes
+    fs.write_block(acl as u64, 0, &[0x00, 0x00, 0x02, 0xea, 2, 0, 0, 0]).unwrap();
+    for name in ["/a", "/b"] {
+        let inode_num = create_file(&mut fs, name, b"x");
+        let mut inode = fs.read_inode(inode_num).unwrap();
+        inode.ext2_inode.i_file_acl = acl;
+        inode.ext2_inode.i_blocks += 2;
+        inode.write(&fs.disk, &fs.block_groups).unwrap();
+    }
+    let free = fs.describe().free_blocks;
+    fs.remove_file("/a").unwrap();
+    assert_eq!(fs.read_block(acl as u64).unwrap()[4], 1);
+    assert_eq!(fs.describe().free_blocks, free + 1);
+    fs.remove_file("/b").unwrap();
+    assert_eq!(fs.describe().free_blocks, free + 3);
+    assert!(matches!(fs.free_block(acl as u64), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn untrusted_bitmaps_are_rebuilt() {
+    let mkfs = Mkfs::default();
+    let (block_bitmap, inode_bitmap, _) = mkfs.group_layout(0);
+    let damaged = |fill: u8| {
+        let mut image = mkfs.build();
+        for bitmap in [block_bitmap, inode_bitmap] {
+            image[bitmap as usize * 1024..(bitmap as usize + 1) * 1024].fill(fill);
+        }
+        image
+    };
+    // Everything marked in use: the on-disk bitmaps leave nothing to allocate
+    let (mut fs, _) = crate::testing::mount(damaged(0xff));
+    assert!(fs.new_file("/file", 0o644).is_err());
+    let options = MountOptions { trust_bitmaps: false, ..MountOptions::default() };
+    let (mut fs, _) = crate::testing::mount_with_options(damaged(0xff), options);
+    create_file(&mut fs, "/file", b"recovered");
+    assert_eq!(fs.pread(fs.resolve("/file").unwrap().0.inode(), 0, 9).unwrap(), b"recovered");
+    // Everything marked free: the scan keeps the metadata and the root directory in use
+    let (mut fs, _) = crate::testing::mount_with_options(damaged(0), options);
+    let block = fs.alloc_block().unwrap() as u64;
+    assert!(block > mkfs.root_block());
+    assert!(fs.alloc_inode_num().unwrap() >= 11);
+    assert!(fs.verify_links().unwrap().is_empty());
+    // Zeroed free counts as well, the scan brings them back
+    let image = damaged(0xff);
+    let expected = crate::testing::mount(mkfs.build()).0.describe();
+    let disk = MemDisk::new(image);
+    disk.poke(1024 + 12, &[0; 8]);
+    disk.poke(2 * 1024 + 12, &[0; 4]);
+    let mut fs = Ext2Filesystem::mount_with_options(Box::new(disk.clone()), options).unwrap();
+    assert_eq!((fs.describe().free_blocks, fs.describe().free_inodes), (expected.free_blocks, expected.free_inodes));
+    let stats = fs.group_stats().unwrap();
+    assert_eq!((stats[0].free_blocks, stats[0].free_inodes), (expected.free_blocks, expected.free_inodes));
+    create_file(&mut fs, "/file", b"recovered");
+    assert_eq!(fs.describe().free_blocks, expected.free_blocks - 1);
+}
+
+#[test]
+fn fix_dir_types_backfills_zeroed_types() {
+    let (mut fs, disk) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    fs.mk_dir("/d/sub", 0o755).unwrap();
+    create_file(&mut fs, "/d/file", b"x");
+    let (dir, _) = fs.resolve("/d").unwrap();
+    let block = dir.get_blocks(&fs.disk).unwrap()[0];
+    let chain = entry_chain(&fs, "/d");
+    for (offset, _, _) in &chain {
+        disk.poke(block * 1024 + *offset as u64 + 7, &[0]);
+    }
+    let types = |fs: &Ext2Filesystem| {
+        let block = fs.read_block(block).unwrap();
+        chain.iter().map(|(offset, _, _)| block[offset + 7]).collect::<Vec<_>>()
+    };
+    assert_eq!(types(&fs), [0, 0, 0, 0]);
+    assert_eq!(fs.fix_dir_types("/d").unwrap(), 4);
+    // ., .., sub and file
+    assert_eq!(types(&fs), [2, 2, 2, 1]);
+    assert_eq!(fs.fix_dir_types("/d").unwrap(), 0);
+    assert!(fs.fix_dir_types("/d/file").is_err());
+}
+
+#[test]
+fn reserved_blocks_are_kept_from_other_users() {
+    let image = Mkfs { reserved_blocks: 100, ..Mkfs::default() }.build();
+    let options = MountOptions { uid: 1000, ..MountOptions::default() };
+    let (mut fs, disk) = crate::testing::mount_with_options(image, options);
+    let free = fs.describe().free_blocks;
+    assert_eq!(fs.available_for(0), free);
+    assert_eq!(fs.available_for(1000), free - 100);
+    assert!(fs.alloc_block_run(free - 99).is_err());
+    while fs.alloc_block().is_some() {}
+    assert_eq!(fs.describe().free_blocks, 100);
+    assert_eq!(fs.available_for(1000), 0);
+    assert!(fs.alloc_block_run(1).is_err());
+    assert!(fs.new_file("/file", 0o644).is_err());
+    fs.sync().unwrap();
+    // Root may use the reserve
+    let mut fs = Ext2Filesystem::mount(Box::new(disk)).unwrap();
+    assert!(fs.alloc_block().is_some());
+    assert_eq!(fs.alloc_block_run(99).unwrap().len(), 99);
+}
+
+#[test]
+fn read_exact_at_middle_of_a_file() {
+    let (mut fs, _) = mount_default();
+    let mut data = pattern(3000);
+    // A little endian (u32, u16) header straddling the first block boundary
+    data[1020..1026].copy_from_slice(&[0x78, 0x56, 0x34, 0x12, 0xcd, 0xab]);
+    let inode_num = create_file(&mut fs, "/file", &data);
+    let inode = fs.read_inode(inode_num).unwrap();
+    let mut header = [0u8; 6];
+    fs.read_exact_at(&inode, 1020, &mut header).unwrap();
+    #[repr(C, packed)]
+    struct Header {
+        magic: u32,
+        version: u16,
+    }
+    let header = (&header[..]).read_struct::<Header>().unwrap();
+    assert_eq!(({ header.magic }, { header.version }), (0x1234_5678, 0xabcd));
+    let mut tail = [0u8; 10];
+    fs.read_exact_at(&inode, 2990, &mut tail).unwrap();
+    assert_eq!(tail, data[2990..]);
+    assert!(matches!(fs.read_exact_at(&inode, 2991, &mut tail), Err(Error::UnexpectedEof(_))));
+}
+
+#[test]
+fn entries_stay_four_byte_aligned() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    for len in 1..=13 {
+        create_file(&mut fs, &format!("/d/{}", "x".repeat(len)), b"x");
+        fs.mk_dir(&format!("/d/{}", "y".repeat(len)), 0o755).unwrap();
+    }
+    fs.remove_file("/d/xxxxx").unwrap();
+    for dir in ["/", "/d", "/d/yyy"] {
+        let chain = entry_chain(&fs, dir);
+        assert!(chain.iter().all(|(offset, rec_len, _)| offset % 4 == 0 && rec_len % 4 == 0));
+    }
+    assert_eq!(entry_chain(&fs, "/d").len(), 2 + 25);
+}
+
+#[test]
+fn read_bytes_at_the_superblock() {
+    let (fs, disk) = mount_default();
+    let raw = fs.read_bytes(1024, 1024).unwrap();
+    let parsed = (&raw[..]).read_struct::<Ext2SuperBlock>().unwrap();
+    assert_eq!(parsed.s_magic, fs.super_block.s_magic);
+    assert_eq!(parsed.s_uuid, fs.super_block.s_uuid);
+    assert_eq!(parsed.s_blocks_count, fs.super_block.s_blocks_count);
+    // Not block aligned
+    assert_eq!(fs.write_bytes(2000 * 1024 + 5, b"abc").unwrap(), 3);
+    assert_eq!(fs.read_bytes(2000 * 1024 + 4, 5).unwrap(), [0, b'a', b'b', b'c', 0]);
+    assert_eq!(disk.peek(2000 * 1024 + 5, 3), b"abc");
+}
+
+#[test]
+fn group_stats_add_up() {
+    let (mut fs, _) = crate::testing::mount(Mkfs { blocks: 20000, ..Mkfs::default() }.build());
+    fs.mk_dir("/d", 0o755).unwrap();
+    create_file(&mut fs, "/d/file", &pattern(5000));
+    fs.sync().unwrap();
+    let stats = fs.group_stats().unwrap();
+    assert_eq!(stats.len(), 3);
+    let info = fs.describe();
+    assert_eq!(stats.iter().map(|stat| stat.free_blocks).sum::<u64>(), info.free_blocks);
+    assert_eq!(stats.iter().map(|stat| stat.free_inodes).sum::<u64>(), info.free_inodes);
+    assert_eq!(stats.iter().map(|stat| stat.used_dirs).sum::<u64>(), 2);
+    let mkfs = Mkfs::default();
+    for stat in &stats {
+        let layout = mkfs.group_layout(stat.group_num as u64);
+        assert_eq!((stat.block_bitmap_loc, stat.inode_bitmap_loc, stat.inode_table_loc), layout);
+    }
+}
+
+#[test]
+fn describe_a_known_image() {
+    let (fs, _) = mount_default();
+    let info = fs.describe();
+    assert_eq!((info.block_size, info.blocks_count, info.inodes_count), (1024, 2048, 128));
+    assert_eq!((info.blocks_per_group, info.inodes_per_group, info.inode_size), (8192, 128, 128));
+    assert_eq!(info.uuid, *b"ext2-fs testimg!");
+    assert_eq!(info.rev_level, 1);
+    let report = format!("{}", info);
+    assert!(report.contains("Block count:              2048"));
+    assert!(report.contains("Filesystem UUID:          65787432-2d66-7320-7465-7374696d6721"));
+    assert!(report.contains("filetype"));
+}
+
+/// Make `path` a fast symlink to `target`
+fn symlink(fs: &mut Ext2Filesystem, path: &str, target: &str) -> u64 {
+    let inode_num = create_file(fs, path, b"");
+    let mut inode = fs.read_inode(inode_num).unwrap();
+    assert_eq!(inode.raw().i_blocks, 0);
+    let mut i_block = [0u8; 60];
+    i_block[..target.len()].copy_from_slice(target.as_bytes());
+    for (slot, bytes) in inode.ext2_inode.i_block.iter_mut().zip(i_block.chunks(4)) {
+        *slot = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    inode.ext2_inode.i_mode = Mode::SYMLINK.bits() | 0o777;
+    inode.ext2_inode.i_size = target.len() as u32;
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+    inode_num
+}
+
+#[test]
+fn mid_path_directory_symlinks() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/b", 0o755).unwrap();
+    let file = create_file(&mut fs, "/b/file", b"x");
+    symlink(&mut fs, "/a/relative", "../b");
+    symlink(&mut fs, "/a/absolute", "/b");
+    symlink(&mut fs, "/a/broken", "missing");
+    for path in ["/a/relative/file", "/a/absolute/file", "/a/relative/../b/file"] {
+        let (inode, name) = fs.resolve(path).unwrap();
+        assert_eq!((inode.inode(), name.as_str()), (file, "file"), "{}", path);
+    }
+    assert!(matches!(fs.resolve("/a/broken/file"), Err(Error::NotFound(_))));
+    // The working directory is still /a after the failed lookup
+    assert!(fs.resolve("/a/relative/missing").is_err());
+    assert_eq!(fs.resolve("/a/absolute/file").unwrap().0.inode(), file);
+}
+
+#[test]
+fn resolve_components_of_a_nested_path() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    let file = create_file(&mut fs, "/a/b/c", b"x");
+    let trail = fs.resolve_components("/a/b/c").unwrap();
+    let names: Vec<&str> = trail.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["/", "a", "b", "c"]);
+    let inodes: Vec<u64> = trail.iter().map(|(_, inode)| inode.inode()).collect();
+    let a = fs.resolve("/a").unwrap().0.inode();
+    let b = fs.resolve("/a/b").unwrap().0.inode();
+    assert_eq!(inodes, [EXT2_ROOT_INO, a, b, file]);
+    assert!(fs.resolve_components("/a/x/c").is_err());
+}
+
+#[test]
+fn resolve_checked_needs_search_permission() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    let file = create_file(&mut fs, "/a/b/file", b"x");
+    // Only the owner and the group may search /a/b
+    let mut dir = fs.resolve("/a/b").unwrap().0;
+    dir.ext2_inode.i_mode = Mode::DIRECTORY.bits() | 0o750;
+    dir.ext2_inode.i_uid = 1000;
+    dir.ext2_inode.i_gid = 100;
+    dir.write(&fs.disk, &fs.block_groups).unwrap();
+    assert_eq!(fs.resolve_checked("/a/b/file", 1000, 1).unwrap().inode(), file);
+    assert_eq!(fs.resolve_checked("/a/b/file", 2000, 100).unwrap().inode(), file);
+    assert_eq!(fs.resolve_checked("/a/b/file", 0, 0).unwrap().inode(), file);
+    assert!(matches!(fs.resolve_checked("/a/b/file", 2000, 200), Err(Error::PermissionDenied(_))));
+    // The final component needs no search permission
+    assert!(fs.resolve_checked("/a/b", 2000, 200).is_ok());
+}
+
+#[test]
+fn links_of_a_hard_linked_file() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/b", 0o755).unwrap();
+    let inode_num = create_file(&mut fs, "/a/one", b"x");
+    link(&fs, "/b", "two", inode_num, 1);
+    fs.add_links(inode_num, 1).unwrap();
+    symlink(&mut fs, "/b/link", "/a/one");
+    let mut links = fs.links_of(inode_num).unwrap();
+    links.sort();
+    assert_eq!(links, ["/a/one", "/b/two"]);
+    assert_eq!(fs.links_of(EXT2_ROOT_INO).unwrap(), ["/"]);
+}
+
+#[test]
+fn wrong_link_counts_are_reported_and_fixed() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    let file = create_file(&mut fs, "/d/file", b"x");
+    link(&fs, "/", "again", file, 1);
+    let dir = fs.resolve("/d").unwrap().0.inode();
+    let mut inode = fs.read_inode(dir).unwrap();
+    inode.ext2_inode.i_links_count = 7;
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+    let mut mismatches = fs.verify_links().unwrap();
+    mismatches.sort();
+    assert_eq!(mismatches, [(dir, 7, 2), (file, 1, 2)]);
+    assert_eq!(fs.fix_links().unwrap(), 2);
+    assert!(fs.verify_links().unwrap().is_empty());
+    assert_eq!(fs.metadata("/again").unwrap().nlink, 2);
+}
+
+#[test]
+fn exclusive_locks_conflict() {
+    let (fs, _) = mount_default();
+    fs.lock(EXT2_ROOT_INO, true).unwrap();
+    assert!(matches!(fs.lock(EXT2_ROOT_INO, true), Err(Error::PermissionDenied(_))));
+    assert!(fs.lock(EXT2_ROOT_INO, false).is_err());
+    fs.unlock(EXT2_ROOT_INO).unwrap();
+    fs.lock(EXT2_ROOT_INO, true).unwrap();
+    fs.unlock(EXT2_ROOT_INO).unwrap();
+    // Shared locks stack, an exclusive one waits for all of them
+    fs.lock(EXT2_ROOT_INO, false).unwrap();
+    fs.lock(EXT2_ROOT_INO, false).unwrap();
+    fs.unlock(EXT2_ROOT_INO).unwrap();
+    assert!(fs.lock(EXT2_ROOT_INO, true).is_err());
+    fs.unlock(EXT2_ROOT_INO).unwrap();
+    assert!(fs.unlock(EXT2_ROOT_INO).is_err());
+}
+
+#[test]
+fn content_hash_follows_the_contents() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", &pattern(5000));
+    let hash = fs.content_hash("/file").unwrap();
+    assert_eq!(hash, crc32c(!0, &pattern(5000)) ^ !0);
+    fs.pwrite(inode_num, 4321, &[pattern(5000)[4321] ^ 1]).unwrap();
+    assert_ne!(fs.content_hash("/file").unwrap(), hash);
+    fs.pwrite(inode_num, 4321, &[pattern(5000)[4321]]).unwrap();
+    assert_eq!(fs.content_hash("/file").unwrap(), hash);
+    assert!(fs.content_hash("/").is_err());
+}
+
+#[test]
+fn read_with_streams_blocks() {
+    let (mut fs, _) = mount_default();
+    let data = pattern(5000);
+    let inode_num = create_file(&mut fs, "/file", &data);
+    let inode = fs.read_inode(inode_num).unwrap();
+    let (mut calls, mut sum, mut len) = (0, 0u64, 0);
+    inode
+        .read_with(&fs.disk, |block| {
+            calls += 1;
+            len += block.len();
+            sum += block.iter().map(|&b| b as u64).sum::<u64>();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!((calls, len), (5, 5000));
+    assert_eq!(sum, data.iter().map(|&b| b as u64).sum::<u64>());
+    // An error from the callback ends the read
+    let mut calls = 0;
+    let result = inode.read_with(&fs.disk, |_| {
+        calls += 1;
+        Err(Error::InvalidData("stop".into()))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn inode_allocation_in_later_groups() {
+    let (mut fs, _) = crate::testing::mount(Mkfs { blocks: 20000, ..Mkfs::default() }.build());
+    // Group 1 has no reserved inodes, its first one is usable
+    assert_eq!(fs.alloc_inode_num_group(1), Some(129));
+    assert_eq!(fs.alloc_inode_num_group(1), Some(130));
+    assert_eq!(fs.alloc_inode_num_group(0), Some(11));
+    while fs.alloc_inode_num_group(0).is_some() {}
+    assert_eq!(fs.alloc_inode_num(), Some(131));
+}
+
+#[test]
+fn unmount_marks_the_image_clean() {
+    let (fs, disk) = mount_default();
+    let state = |disk: &MemDisk| u16::from_le_bytes(disk.peek(1024 + 0x3a, 2).try_into().unwrap());
+    let mnt_count = |disk: &MemDisk| u16::from_le_bytes(disk.peek(1024 + 0x34, 2).try_into().unwrap());
+    assert_eq!(state(&disk) & 1, 0);
+    assert_eq!(mnt_count(&disk), 1);
+    fs.unmount().unwrap();
+    assert_eq!(state(&disk) & 1, 1);
+    let fs = Ext2Filesystem::mount(Box::new(disk.clone())).unwrap();
+    assert_eq!((state(&disk) & 1, mnt_count(&disk)), (0, 2));
+    fs.unmount().unwrap();
+    assert_eq!(state(&disk) & 1, 1);
+}
+
+#[test]
+fn mount_count_limit_needs_a_check() {
+    let (fs, _) = mount_default();
+    assert!(!fs.needs_check());
+    let mut image = Mkfs::default().build();
+    // s_mnt_count 19 and s_max_mnt_count 20, the mount makes it 20
+    image[1024 + 0x34..1024 + 0x38].copy_from_slice(&[19, 0, 20, 0]);
+    let (fs, _) = crate::testing::mount(image);
+    assert!(fs.needs_check());
+    // s_lastcheck far before s_wtime with a one day interval
+    let mut image = Mkfs::default().build();
+    image[1024 + 0x30..1024 + 0x34].copy_from_slice(&MKFS_TIME.to_le_bytes());
+    image[1024 + 0x40..1024 + 0x48].copy_from_slice(&[0, 0, 0, 0, 0x80, 0x51, 0x01, 0]);
+    let (fs, _) = crate::testing::mount(image);
+    assert!(fs.needs_check());
+}
+
+#[test]
+fn device_numbers() {
+    let (mut fs, _) = mount_default();
+    // /dev/null in the old 8:8 form, a block device needing the 12:20 form
+    for (path, mode, i_block, major, minor) in
+        [("/null", Mode::CHAR_DEVICE, [0x0103, 0], 1, 3), ("/disk", Mode::BLOCK_DEVICE, [0, 0x0011_032c], 259, 300)]
+    {
+        let inode_num = create_file(&mut fs, path, b"");
+        let mut inode = fs.read_inode(inode_num).unwrap();
+        inode.ext2_inode.i_mode = mode.bits() | 0o660;
+        inode.ext2_inode.i_block[..2].copy_from_slice(&i_block);
+        inode.write(&fs.disk, &fs.block_groups).unwrap();
+        let stat = fs.metadata(path).unwrap();
+        assert_eq!((stat.rdev_major(), stat.rdev_minor()), (major, minor), "{}", path);
+    }
+    let stat = fs.metadata("/").unwrap();
+    assert_eq!((stat.rdev, stat.rdev_major(), stat.rdev_minor()), (0, 0, 0));
+}
+
+#[test]
+fn failed_create_rolls_back_the_allocation() {
+    let mkfs = Mkfs::default();
+    let root_block = mkfs.root_block();
+    let disk = MemDisk::new(mkfs.build());
+    // Everything but the new entry in the root directory goes through
+    let faulty = FaultyDisk::new(disk.clone()).fail_write_blocks(move |block| block == root_block);
+    let mut fs = Ext2Filesystem::mount(Box::new(faulty)).unwrap();
+    let (info, blocks, inodes) = (fs.describe(), fs.block_bitmap(0).unwrap(), fs.inode_bitmap(0).unwrap());
+    assert!(fs.new_file("/file", 0o644).is_err());
+    assert!(fs.mk_dir("/dir", 0o755).is_err());
+    assert_eq!(fs.block_bitmap(0).unwrap(), blocks);
+    assert_eq!(fs.inode_bitmap(0).unwrap(), inodes);
+    assert_eq!((fs.describe().free_blocks, fs.describe().free_inodes), (info.free_blocks, info.free_inodes));
+    assert!(!fs.is_exist("/file"));
+    drop(fs);
+    let (fs, _) = crate::testing::mount(disk.bytes());
+    assert_eq!((fs.describe().free_blocks, fs.describe().free_inodes), (info.free_blocks, info.free_inodes));
+}
+
+#[test]
+fn last_inode_of_a_group() {
+    let mkfs = Mkfs { blocks: 20000, ..Mkfs::default() };
+    let (fs, disk) = crate::testing::mount(mkfs.build());
+    for (group, inode_num) in [(0, 128), (1, 129), (1, 256)] {
+        let (_, _, inode_table) = mkfs.group_layout(group);
+        // 8 inodes per block, the last one of the group is at the end of the table
+        let index = (inode_num - 1) % 128;
+        let offset = (inode_table + index / 8) * 1024 + index % 8 * 128;
+        let mut inode = fs.read_inode(inode_num).unwrap();
+        inode.ext2_inode.i_generation = inode_num as u32 * 3;
+        inode.write(&fs.disk, &fs.block_groups).unwrap();
+        assert_eq!(disk.peek(offset + 0x64, 4), (inode_num as u32 * 3).to_le_bytes());
+        assert_eq!(fs.locate_inode(inode_num).unwrap(), (group as usize, offset));
+        assert_eq!(fs.read_inode(inode_num).unwrap().generation(), inode_num as u32 * 3);
+    }
+    assert_eq!(fs.locate_inode(128).unwrap().1, (mkfs.group_layout(0).2 + 15) * 1024 + 896);
+    assert!(fs.read_inode(3 * 128 + 1).is_err());
+}
+
+#[test]
+fn read_dir_ordered_follows_the_block() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    for name in ["zeta", "alpha", "mid", "beta"] {
+        create_file(&mut fs, &format!("/d/{}", name), b"x");
+    }
+    fs.remove_file("/d/alpha").unwrap();
+    create_file(&mut fs, "/d/gamma", b"x");
+    let ordered: Vec<String> = fs.read_dir_ordered("/d").unwrap().iter().map(|entry| entry.file_name()).collect();
+    let layout: Vec<String> = entry_chain(&fs, "/d").into_iter().map(|(_, _, name)| name).collect();
+    assert_eq!(ordered, layout);
+    assert_eq!(ordered, [".", "..", "zeta", "mid", "beta", "gamma"]);
+}
+
+#[test]
+fn corrupt_entries_are_errors() {
+    let (mut fs, disk) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    create_file(&mut fs, "/d/name", b"x");
+    create_file(&mut fs, "/d/next", b"x");
+    let block = fs.resolve("/d").unwrap().0.get_blocks(&fs.disk).unwrap()[0];
+    let (offset, _, _) = entry_chain(&fs, "/d")[2];
+    let entry = block * 1024 + offset as u64;
+    // Not UTF-8, read lossily
+    disk.poke(entry + 8, &[b'n', 0xff, b'm', b'e']);
+    let names: Vec<String> = fs.read_dir_ordered("/d").unwrap().iter().map(|entry| entry.file_name()).collect();
+    assert_eq!(names[2], "n\u{fffd}me");
+    // A name running past the record
+    disk.poke(entry + 6, &[255]);
+    assert!(matches!(fs.read_dir_ordered("/d"), Err(Error::InvalidData(_))));
+    assert!(matches!(fs.read_dir("/d"), Err(Error::InvalidData(_))));
+    let mut handle = fs.open_dir("/d").unwrap();
+    assert!(handle.read_entry().unwrap().is_some());
+    assert!(handle.read_entry().unwrap().is_some());
+    assert!(handle.read_entry().is_err());
+    // A rec_len running past the block
+    disk.poke(entry + 6, &[4]);
+    disk.poke(entry + 4, &2000u16.to_le_bytes());
+    assert!(fs.read_dir_ordered("/d").is_err());
+    let buffer = fs.read_block(block).unwrap();
+    assert!(Ext2DirEntry::new(&buffer, 1020, true).is_err());
+    assert!(Ext2DirEntry::new(&buffer, 0, true).is_ok());
+}
+
+#[test]
+fn htree_directories_read_but_refuse_inserts() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/big", 0o755).unwrap();
+    let target = create_file(&mut fs, "/target", b"x");
+    let mut dir = fs.resolve("/big").unwrap().0;
+    let root = dir.get_blocks(&fs.disk).unwrap()[0];
+    // dx_root: ".." spans the block and hides the index, one leaf at logical block 1
+    fs.write_block(root, 16, &1012u16.to_le_bytes()).unwrap();
+    fs.write_block(root, 24, &[0, 0, 0, 0, 1, 8, 0, 0, 124, 0, 1, 0, 1, 0, 0, 0]).unwrap();
+    let leaf = fs.alloc_block().unwrap();
+    let mut entry = vec![0u8; 1024];
+    entry[..8].copy_from_slice(&[target as u8, 0, 0, 0, 0, 4, 4, 1]);
+    entry[8..12].copy_from_slice(b"file");
+    fs.write_block_full(leaf as u64, &entry).unwrap();
+    dir.ext2_inode.i_block[1] = leaf;
+    dir.ext2_inode.i_size = 2048;
+    dir.ext2_inode.i_blocks += 2;
+    dir.ext2_inode.i_flags |= FileFlags::HASH_DIR.bits();
+    dir.write(&fs.disk, &fs.block_groups).unwrap();
+    assert!(fs.read_inode(dir.inode()).unwrap().is_indexed());
+    let names: Vec<String> = fs.read_dir_ordered("/big").unwrap().iter().map(|entry| entry.file_name()).collect();
+    assert_eq!(names, [".", "..", "file"]);
+    assert_eq!(fs.resolve("/big/file").unwrap().0.inode(), target);
+    let free = (fs.describe().free_blocks, fs.describe().free_inodes);
+    assert!(matches!(fs.new_file("/big/new", 0o644), Err(Error::InvalidInput(_))));
+    assert!(fs.mk_dir("/big/sub", 0o755).is_err());
+    assert_eq!((fs.describe().free_blocks, fs.describe().free_inodes), free);
+}
+
+#[test]
+fn fast_symlinks_own_no_blocks() {
+    let (mut fs, _) = mount_default();
+    let target = "/some/where/else";
+    let inode_num = symlink(&mut fs, "/link", target);
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert!(inode.get_blocks(&fs.disk).unwrap().is_empty());
+    assert!(inode.block_map(&fs.disk).unwrap().is_empty());
+    assert_eq!(inode.read_link(&fs.disk).unwrap(), target);
+    assert_eq!(fs.read_link("/link").unwrap(), target);
+    // Removing it frees no blocks
+    let free = fs.describe().free_blocks;
+    fs.remove_file("/link").unwrap();
+    assert_eq!(fs.describe().free_blocks, free);
+}
+
+#[test]
+fn allocation_predicates() {
+    let mkfs = Mkfs::default();
+    let (mut fs, _) = crate::testing::mount(mkfs.build());
+    let (block_bitmap, inode_bitmap, inode_table) = mkfs.group_layout(0);
+    for block in [1, block_bitmap, inode_bitmap, inode_table, mkfs.root_block()] {
+        assert!(fs.is_block_allocated(block).unwrap(), "block {}", block);
+    }
+    assert!(!fs.is_block_allocated(mkfs.root_block() + 1).unwrap());
+    assert!(!fs.is_block_allocated(2047).unwrap());
+    assert!(fs.is_block_allocated(0).is_err());
+    assert!(fs.is_block_allocated(2048).is_err());
+    assert!(fs.is_inode_allocated(EXT2_ROOT_INO).unwrap());
+    assert!(fs.is_inode_allocated(10).unwrap());
+    assert!(!fs.is_inode_allocated(11).unwrap());
+    assert!(fs.is_inode_allocated(129).is_err());
+    let inode_num = create_file(&mut fs, "/file", b"x");
+    let block = fs.read_inode(inode_num).unwrap().get_block_ptr(&fs.disk, 0).unwrap();
+    assert!(fs.is_inode_allocated(inode_num).unwrap());
+    assert!(fs.is_block_allocated(block).unwrap());
+    assert_eq!(fs.block_bitmap(0).unwrap().get(block as usize - 1), true);
+    assert_eq!(fs.inode_bitmap(0).unwrap().get(inode_num as usize - 1), true);
+}
+
+#[test]
+fn write_block_full_needs_a_whole_block() {
+    let (fs, disk) = mount_default();
+    assert!(matches!(fs.write_block_full(1500, &[1; 1023]), Err(Error::InvalidInput(_))));
+    assert!(matches!(fs.write_block_full(1500, &[1; 1025]), Err(Error::InvalidInput(_))));
+    assert!(fs.write_block_full(2048, &[1; 1024]).is_err());
+    assert_eq!(disk.peek(1500 * 1024, 1024), [0; 1024]);
+    fs.write_block_full(1500, &[7; 1024]).unwrap();
+    assert_eq!(disk.peek(1500 * 1024, 1024), [7; 1024]);
+    assert_eq!(fs.read_block(1500).unwrap(), [7; 1024]);
+}
+
+#[test]
+fn used_dirs_count_follows_mk_dir_and_remove_dir() {
+    let (mut fs, _) = crate::testing::mount(Mkfs { blocks: 20000, ..Mkfs::default() }.build());
+    let used_dirs = |fs: &Ext2Filesystem| fs.group_stats().unwrap().iter().map(|stat| stat.used_dirs).sum::<u64>();
+    assert_eq!(used_dirs(&fs), 1);
+    for i in 0..5 {
+        fs.mk_dir(&format!("/d{}", i), 0o755).unwrap();
+    }
+    create_file(&mut fs, "/file", b"x");
+    assert_eq!(used_dirs(&fs), 6);
+    fs.remove_dir("/d3").unwrap();
+    assert_eq!(used_dirs(&fs), 5);
+    fs.sync().unwrap();
+    assert_eq!(fs.group_stats().unwrap()[0].used_dirs, 5);
+}
+
+#[test]
+fn names_match_exactly() {
+    let (mut fs, _) = mount_default();
+    let foo = create_file(&mut fs, "/foo", b"1");
+    let foobar = create_file(&mut fs, "/foobar", b"2");
+    let (root, _) = fs.resolve("/").unwrap();
+    assert_eq!(root.find_entry(&fs.disk, b"foo").unwrap(), Some(foo));
+    assert_eq!(root.find_entry(&fs.disk, b"foobar").unwrap(), Some(foobar));
+    assert_eq!(root.find_entry(&fs.disk, b"fo").unwrap(), None);
+    assert_eq!(root.find_entry(&fs.disk, b"foo\0").unwrap(), None);
+    assert_eq!(root.find_entry(&fs.disk, b"foob").unwrap(), None);
+    assert_eq!(fs.resolve("/foo").unwrap().0.inode(), foo);
+    assert_eq!(fs.resolve("/foobar").unwrap().0.inode(), foobar);
+    assert!(fs.resolve("/fooba").is_err());
+}
+
+#[test]
+fn write_inode_struct_round_trips() {
+    let (fs, disk) = mount_default();
+    let mut raw = Ext2InodeStruct::new_file(0o600, 1500, 700);
+    raw.i_uid = 1000;
+    raw.i_links_count = 1;
+    raw.i_mtime = MKFS_TIME + 5;
+    fs.write_inode_struct(20, &raw).unwrap();
+    let inode = fs.read_inode(20).unwrap();
+    assert_eq!(inode.get_size(), 700);
+    assert_eq!(inode.blocks()[0], 1500);
+    let stat = inode.metadata();
+    assert!(stat.is_file());
+    assert_eq!((stat.uid, stat.mtime), (1000, MKFS_TIME as i64 + 5));
+    let (_, offset) = fs.locate_inode(20).unwrap();
+    assert_eq!(disk.peek(offset + 4, 4), 700u32.to_le_bytes());
+    assert!(matches!(fs.write_inode_struct(0, &raw), Err(Error::InvalidInput(_))));
+    assert!(matches!(fs.write_inode_struct(129, &raw), Err(Error::InvalidInput(_))));
+}
+
+#[test]
+fn nanoseconds_of_large_inodes() {
+    let (mut fs, disk) = crate::testing::mount(Mkfs { inode_size: 256, ..Mkfs::default() }.build());
+    let inode_num = create_file(&mut fs, "/file", b"x");
+    let (_, offset) = fs.locate_inode(inode_num).unwrap();
+    // i_extra_isize covering ctime, mtime, atime and crtime extras
+    disk.poke(offset + 128, &24u16.to_le_bytes());
+    disk.poke(offset + 128 + 8, &(123_456_789u32 << 2).to_le_bytes());
+    disk.poke(offset + 128 + 12, &(999_999_999u32 << 2 | 1).to_le_bytes());
+    let stat = fs.metadata("/file").unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert_eq!(inode.extra().extra_isize, 24);
+    assert_eq!((stat.mtime, stat.mtime_nsec), (inode.raw().i_mtime as i64, 123_456_789));
+    // Epoch bits carry the seconds past 2038
+    assert_eq!(stat.atime, inode.raw().i_atime as i64 + (1 << 32));
+    assert_eq!(stat.atime_nsec, 999_999_999);
+    assert_eq!(stat.ctime_nsec, 0);
+}
+
+#[test]
+fn small_inodes_have_no_nanoseconds() {
+    let (mut fs, _) = mount_default();
+    create_file(&mut fs, "/file", b"x");
+    fs.set_times("/file", Some(MKFS_TIME + 1), Some(MKFS_TIME + 2)).unwrap();
+    let stat = fs.metadata("/file").unwrap();
+    assert_eq!((stat.atime, stat.atime_nsec), (MKFS_TIME as i64 + 1, 0));
+    assert_eq!((stat.mtime, stat.mtime_nsec), (MKFS_TIME as i64 + 2, 0));
+    assert_eq!(stat.ctime_nsec, 0);
+}
+
+#[test]
+fn locate_the_root_inode() {
+    let mkfs = Mkfs::default();
+    let (fs, _) = crate::testing::mount(mkfs.build());
+    // Second slot of the first inode table block
+    let offset = mkfs.group_layout(0).2 * 1024 + 128;
+    assert_eq!(fs.locate_inode(EXT2_ROOT_INO).unwrap(), (0, offset));
+    assert!(fs.locate_inode(0).is_err());
+}
+
+#[test]
+fn inserts_without_the_filetype_feature() {
+    let mkfs = Mkfs { filetype: false, ..Mkfs::default() };
+    let (mut fs, disk) = crate::testing::mount(mkfs.build());
+    let names = ["a", "bb", "ccc", "dddd", "eeeee"];
+    for name in names {
+        create_file(&mut fs, &format!("/{}", name), name.as_bytes());
+    }
+    drop(fs);
+    // Every name_len keeps its high byte zero, so the entries read back in place
+    let block = disk.peek(mkfs.root_block() * 1024, 1024);
+    let mut offset = 0;
+    let mut found = Vec::new();
+    while offset < block.len() {
+        let (entry, rec_len) = Ext2DirEntry::new(&block, offset, false).unwrap();
+        found.push(entry.file_name());
+        offset += rec_len;
+    }
+    assert_eq!(found, [".", "..", "a", "bb", "ccc", "dddd", "eeeee"]);
+    let (fs, _) = crate::testing::mount(disk.bytes());
+    for name in names {
+        let (file, _) = fs.resolve(&format!("/{}", name)).unwrap();
+        assert_eq!(file.read(&fs.disk).unwrap(), name.as_bytes());
+    }
+}
+
+#[test]
+fn open_a_listed_inode() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/d", 0o755).unwrap();
+    create_file(&mut fs, "/d/file", b"by inode");
+    let inode_num = fs.read_dir("/d").unwrap()["file"].inode_num();
+    let mut file = fs.open_inode(inode_num).unwrap();
+    assert!(file.name().is_empty());
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"by inode");
+    let dir = fs.read_dir("/").unwrap()["d"].inode_num();
+    assert!(matches!(fs.open_inode(dir), Err(Error::InvalidInput(_))));
+    assert!(matches!(fs.open_inode(50), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn allocations_reuse_the_cached_bitmap() {
+    let mkfs = Mkfs::default();
+    let (mut fs, disk) = crate::testing::mount(mkfs.build());
+    let (block_bitmap, _, _) = mkfs.group_layout(0);
+    let (reads, writes) = (disk.reads(), disk.writes());
+    let blocks: Vec<u32> = (0..200).map(|_| fs.alloc_block_uninit().unwrap()).collect();
+    // Every allocation updates its descriptor and the superblock,
+    // the bitmap is read once and not written before sync
+    let (reads, writes) = (disk.reads() - reads, disk.writes() - writes);
+    assert!(reads <= 200 + 2, "{} reads", reads);
+    assert_eq!(writes, 2 * 200);
+    let on_disk = BitMap::new(disk.peek(block_bitmap * 1024, 1024));
+    assert!(blocks.iter().all(|&block| !on_disk.get(block as usize - 1)));
+    // The cached copy is what the filesystem sees, sync writes it out
+    assert!(blocks.iter().all(|&block| fs.is_block_allocated(block as u64).unwrap()));
+    fs.sync().unwrap();
+    let on_disk = BitMap::new(disk.peek(block_bitmap * 1024, 1024));
+    assert!(blocks.iter().all(|&block| on_disk.get(block as usize - 1)));
+}
+
+#[test]
+fn alloc_block_run_is_contiguous() {
+    let (mut fs, _) = mount_default();
+    let free = fs.describe().free_blocks;
+    let run = fs.alloc_block_run(300).unwrap();
+    assert_eq!(run.len(), 300);
+    assert!(run.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    assert!(run.iter().all(|&block| fs.is_block_allocated(block).unwrap()));
+    assert_eq!(fs.describe().free_blocks, free - 300);
+    // With only single free blocks left a run of 6 is put together from them
+    for block in (run[0]..run[0] + 300).step_by(50) {
+        fs.free_block(block).unwrap();
+    }
+    let rest = fs.describe().free_blocks - 6;
+    fs.alloc_block_run(rest).unwrap();
+    let scattered = fs.alloc_block_run(6).unwrap();
+    assert_eq!(scattered, (run[0]..run[0] + 300).step_by(50).collect::<Vec<_>>());
+    assert!(matches!(fs.alloc_block_run(1), Err(Error::NoSpace(_))));
+    assert!(matches!(fs.new_file("/file", 0o644), Err(Error::NoSpace(_))));
+}
+
+#[test]
+fn group_count_is_checked_against_the_descriptors() {
+    let mkfs = Mkfs { blocks: 20000, ..Mkfs::default() };
+    let image = mkfs.build();
+    assert!(Ext2Filesystem::mount(Box::new(MemDisk::new(image.clone()))).is_ok());
+    // Half as many blocks per group claims 5 groups, the last descriptors are empty
+    let disk = MemDisk::new(image.clone());
+    disk.poke(1024 + 32, &4096u32.to_le_bytes());
+    assert!(matches!(Ext2Filesystem::mount(Box::new(disk)), Err(Error::InvalidData(_))));
+    // The last group's inode table past the end of the filesystem
+    let disk = MemDisk::new(image);
+    disk.poke(2 * 1024 + 2 * 32 + 8, &30000u32.to_le_bytes());
+    assert!(matches!(Ext2Filesystem::mount(Box::new(disk)), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn mount_from_a_backup_superblock() {
+    let mkfs = Mkfs { blocks: 20000, ..Mkfs::default() };
+    let (mut fs, disk) = crate::testing::mount(mkfs.build());
+    create_file(&mut fs, "/file", b"survives");
+    fs.unmount().unwrap();
+    // Lose the primary superblock, the backups still hold the mkfs one
+    disk.poke(1024, &[0; 1024]);
+    assert!(Ext2Filesystem::mount(Box::new(disk.clone())).is_err());
+    let damaged = disk.bytes();
+
+    // Read-only leaves the image as it is
+    let options = MountOptions { read_only: true, ..MountOptions::default() };
+    let fs = Ext2Filesystem::mount_from_backup_with_options(Box::new(disk.clone()), 1, options).unwrap();
+    assert!(fs.is_read_only());
+    assert_eq!(fs.resolve("/file").unwrap().0.read(&fs.disk).unwrap(), b"survives");
+    drop(fs);
+    assert!(disk.bytes() == damaged);
+
+    // So does metadata_csum, whose checksums a copy would leave stale
+    let csum = MemDisk::new(damaged.clone());
+    let sb_offset = (1 + 8192) * 1024;
+    let ro_compat = (FeatureRoCompat::SPARSE_SUPER | FeatureRoCompat::METADATA_CSUM).bits();
+    csum.poke(sb_offset + 0x64, &ro_compat.to_le_bytes());
+    let fs = Ext2Filesystem::mount_from_backup(Box::new(csum.clone()), 1).unwrap();
+    assert!(fs.is_read_only());
+    drop(fs);
+    assert!(csum.peek(1024, 1024) == [0; 1024]);
+
+    // A writable mount restores the primary superblock and descriptors
+    let mut fs = Ext2Filesystem::mount_from_backup(Box::new(disk.clone()), 1).unwrap();
+    assert!(!fs.is_read_only());
+    create_file(&mut fs, "/more", b"x");
+    fs.unmount().unwrap();
+    let (fs, _) = crate::testing::mount(disk.bytes());
+    assert!(fs.is_exist("/file") && fs.is_exist("/more"));
+    assert_eq!(fs.super_block.s_block_group_nr, 0);
+}
+
+#[test]
+fn descriptors_follow_the_first_data_block() {
+    for (block_size, blocks) in [(1024, 20000), (2048, 40000), (4096, 40000)] {
+        let mkfs = Mkfs::with_block_size(block_size, blocks);
+        let (mut fs, _) = crate::testing::mount(mkfs.build());
+        // 1 with 1 KiB blocks, else 0 and the table starts right after the superblock block
+        assert_eq!(fs.super_block.s_first_data_block as u64, mkfs.first_data_block());
+        let stats = fs.group_stats().unwrap();
+        assert_eq!(stats.len() as u64, mkfs.groups());
+        for stat in &stats {
+            let layout = mkfs.group_layout(stat.group_num as u64);
+            assert_eq!((stat.block_bitmap_loc, stat.inode_bitmap_loc, stat.inode_table_loc), layout);
+        }
+        let free = stats[0].free_blocks;
+        create_file(&mut fs, "/file", b"x");
+        assert_eq!(fs.group_stats().unwrap()[0].free_blocks, free - 1);
+    }
+}
+
+#[test]
+fn extent_mapped_inodes_are_detected() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", &pattern(2048));
+    let mut inode = fs.read_inode(inode_num).unwrap();
+    assert!(!inode.is_extent_mapped());
+    // Block pointers are no extent header
+    inode.ext2_inode.i_flags |= FileFlags::EXT4_EXTENTS.bits();
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert!(inode.is_extent_mapped());
+    assert!(matches!(inode.get_blocks(&fs.disk), Err(Error::InvalidData(_))));
+    assert!(matches!(inode.read(&fs.disk), Err(Error::InvalidData(_))));
+    assert!(matches!(fs.open_inode(inode_num), Err(Error::InvalidData(_))));
+    // Writes would treat the tree as block pointers
+    let mut inode = inode;
+    assert!(matches!(inode.set_block_ptr(&mut fs, 5, 1500), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn read_a_fragmented_extent_file() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/ext", b"");
+    // Leaf entries: logical block, length, physical block. Block 5 is a hole and
+    // the run at 6 is allocated but unwritten, its stale contents read as zeros.
+    let extents: [(u32, u16, u32); 4] = [(0, 2, 1600), (2, 3, 1500), (6, 32768 + 2, 1700), (8, 2, 1800)];
+    let mut leaf = vec![0u8; 1024];
+    let header = |entries: u16, max: u16, depth: u16| {
+        [0xF30Au16, entries, max, depth].iter().flat_map(|v| v.to_le_bytes()).chain([0; 4]).collect::<Vec<u8>>()
+    };
+    leaf[..12].copy_from_slice(&header(4, 84, 0));
+    let mut expected = Vec::new();
+    for (i, &(logical, len, physical)) in extents.iter().enumerate() {
+        let at = 12 + i * 12;
+        leaf[at..at + 4].copy_from_slice(&logical.to_le_bytes());
+        leaf[at + 4..at + 6].copy_from_slice(&len.to_le_bytes());
+        leaf[at + 8..at + 12].copy_from_slice(&physical.to_le_bytes());
+        for block in 0..(len as u32 % 32768) {
+            let data = vec![(logical + block) as u8 + 1; 1024];
+            fs.write_block_full((physical + block) as u64, &data).unwrap();
+        }
+    }
+    fs.write_block_full(1900, &leaf).unwrap();
+    for block in 0..10u8 {
+        let byte = match block {
+            5..=7 => 0,
+            _ => block + 1,
+        };
+        expected.extend([byte; 1024]);
+    }
+    expected.truncate(10 * 1024 - 300);
+    // The root in i_block has a single index pointing at the leaf
+    let mut root = header(1, 4, 1);
+    root.extend(0u32.to_le_bytes());
+    root.extend(1900u32.to_le_bytes());
+    root.extend([0; 4]);
+    let mut inode = fs.read_inode(inode_num).unwrap();
+    for (slot, bytes) in inode.ext2_inode.i_block.iter_mut().zip(root.chunks(4)) {
+        *slot = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    inode.ext2_inode.i_flags |= FileFlags::EXT4_EXTENTS.bits();
+    inode.ext2_inode.set_size(expected.len() as u64);
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+
+    let inode = fs.read_inode(inode_num).unwrap();
+    let runs = inode.extent_map(&fs.disk).unwrap();
+    let runs: Vec<_> = runs.iter().map(|run| (run.logical, run.physical, run.len)).collect();
+    assert_eq!(runs, [(0, 1600, 2), (2, 1500, 3), (6, 0, 2), (8, 1800, 2)]);
+    assert_eq!(inode.get_blocks(&fs.disk).unwrap(), [1600, 1601, 1500, 1501, 1502, 0, 0, 0, 1800, 1801]);
+    assert_eq!(inode.read(&fs.disk).unwrap(), expected);
+    let mut file = fs.open("/ext").unwrap();
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, expected);
+}
+
+#[test]
+fn block_mappings_agree_with_iteration() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/file", &pattern(20 * 1024));
+    let inode = fs.read_inode(inode_num).unwrap();
+    let blocks = inode.get_blocks(&fs.disk).unwrap();
+    let mut mapping = inode.get_blocks_iter(&fs.disk).unwrap();
+    // Past the direct blocks, then back, random access does not disturb iteration
+    for i in [15, 3, 19, 12, 0] {
+        assert_eq!(mapping.physical(i).unwrap(), blocks[i as usize]);
+    }
+    assert_eq!(mapping.map(|block| block.unwrap()).collect::<Vec<_>>(), blocks);
+
+    // The same file as two extents in i_block
+    let mut inode = inode;
+    // Header, then block, length and start of each extent
+    let root: [u32; 9] = [0xF30A | 2 << 16, 4, 0, 0, 12, blocks[0] as u32, 12, 8, blocks[12] as u32];
+    inode.ext2_inode.i_block = [0; 15];
+    inode.ext2_inode.i_block[..9].copy_from_slice(&root);
+    inode.ext2_inode.i_flags |= FileFlags::EXT4_EXTENTS.bits();
+    inode.write(&fs.disk, &fs.block_groups).unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    let mut mapping = inode.get_blocks_iter(&fs.disk).unwrap();
+    assert_eq!(mapping.physical(13).unwrap(), blocks[12] + 1);
+    assert_eq!(mapping.physical(20).unwrap(), 0);
+    let expected: Vec<u64> = (0..20).map(|i| if i < 12 { blocks[0] + i } else { blocks[12] + i - 12 }).collect();
+    assert_eq!(mapping.map(|block| block.unwrap()).collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn directory_link_counts() {
+    let (mut fs, _) = mount_default();
+    let nlink = |fs: &Ext2Filesystem, path: &str| fs.metadata(path).unwrap().nlink;
+    let root = nlink(&fs, "/");
+    for dir in ["/a", "/a/b", "/a/c", "/a/b/d"] {
+        fs.mk_dir(dir, 0o755).unwrap();
+    }
+    create_file(&mut fs, "/a/file", b"x");
+    assert_eq!(nlink(&fs, "/"), root + 1);
+    assert_eq!(nlink(&fs, "/a"), 2 + 2);
+    assert_eq!(nlink(&fs, "/a/b"), 2 + 1);
+    assert_eq!(nlink(&fs, "/a/c"), 2);
+    assert_eq!(nlink(&fs, "/a/b/d"), 2);
+    fs.remove_dir("/a/c").unwrap();
+    fs.remove_dir("/a/b/d").unwrap();
+    assert_eq!(nlink(&fs, "/a"), 3);
+    assert_eq!(nlink(&fs, "/a/b"), 2);
+    assert!(fs.verify_links().unwrap().is_empty());
+}
+
+#[test]
+fn pread_overlapping_ranges() {
+    let (mut fs, _) = mount_default();
+    let data = pattern(5000);
+    let inode_num = create_file(&mut fs, "/file", &data);
+    for (offset, len) in [(0, 5000), (1000, 100), (1020, 10), (1050, 2000), (4990, 100), (5000, 1), (9000, 5)] {
+        let end = (offset + len).min(5000);
+        let start = offset.min(end);
+        assert_eq!(fs.pread(inode_num, offset, len).unwrap(), data[start as usize..end as usize]);
+    }
+    // Overlapping reads see the same bytes
+    let a = fs.pread(inode_num, 500, 1500).unwrap();
+    let b = fs.pread(inode_num, 1000, 1500).unwrap();
+    assert_eq!(a[500..], b[..1000]);
+}
+
+#[test]
+fn pwrite_in_the_middle_and_past_the_end() {
+    let (mut fs, _) = mount_default();
+    let mut data = pattern(3000);
+    let inode_num = create_file(&mut fs, "/file", &data);
+    assert_eq!(fs.pwrite(inode_num, 1000, &[0xee; 100]).unwrap(), 100);
+    data[1000..1100].fill(0xee);
+    assert_eq!(fs.metadata("/file").unwrap().size, 3000);
+    assert_eq!(fs.pread(inode_num, 0, 3000).unwrap(), data);
+    // Past the end leaves a hole, the size follows the write
+    assert_eq!(fs.pwrite(inode_num, 10_000, b"tail").unwrap(), 4);
+    data.resize(10_000, 0);
+    data.extend(b"tail");
+    assert_eq!(fs.metadata("/file").unwrap().size, 10_004);
+    assert_eq!(fs.pread(inode_num, 0, 20_000).unwrap(), data);
+}
+
+#[test]
+fn long_names_and_paths() {
+    let (mut fs, _) = mount_default();
+    let name = "n".repeat(255);
+    create_file(&mut fs, &format!("/{}", name), b"x");
+    assert!(fs.is_exist(&format!("/{}", name)));
+    let too_long = format!("/{}", "n".repeat(256));
+    assert!(matches!(fs.metadata(&too_long), Err(Error::NameTooLong(_))));
+    assert!(matches!(fs.new_file(&too_long, 0o644), Err(Error::NameTooLong(_))));
+    // Short components, but far more of them than the path limit allows
+    let pathological = "/a".repeat(100_000);
+    assert!(matches!(fs.metadata(&pathological), Err(Error::NameTooLong(_))));
+    let options = MountOptions { max_path_len: 16, ..MountOptions::default() };
+    let (fs, _) = crate::testing::mount_with_options(Mkfs::default().build(), options);
+    assert!(fs.is_exist("/"));
+    assert!(matches!(fs.metadata("/0123456789abcdef"), Err(Error::NameTooLong(_))));
+    assert!(matches!(fs.metadata("/0123456789abcde"), Err(Error::NotFound(_))));
+}
+
+#[test]
+fn list_matches_read_dir() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/sub", 0o755).unwrap();
+    create_file(&mut fs, "/file", b"x");
+    symlink(&mut fs, "/link", "/file");
+    // The helper leaves the entry typed as a regular file
+    assert_eq!(fs.fix_dir_types("/").unwrap(), 1);
+    let listed = fs.list("/").unwrap();
+    let names: Vec<&str> = listed.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, [".", "..", "file", "link", "sub"]);
+    let entries = fs.read_dir("/").unwrap();
+    for entry in &listed {
+        let read = &entries[&entry.name];
+        assert_eq!(entry.inode, read.inode_num());
+        assert_eq!(entry.file_type.unwrap().bits(), read.stat().mode().bits() & 0o170000);
+    }
+    let types: Vec<bool> = listed.iter().map(|entry| entry.file_type.unwrap().is_dir()).collect();
+    assert_eq!(types, [true, true, false, false, true]);
+}
+
+#[test]
+fn read_dir_children_skips_dot_entries() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/sub", 0o755).unwrap();
+    fs.mk_dir("/sub/inner", 0o755).unwrap();
+    create_file(&mut fs, "/sub/file", b"x");
+    let all = fs.read_dir("/sub").unwrap();
+    assert!(all.contains_key(".") && all.contains_key(".."));
+    let children = fs.read_dir_children("/sub").unwrap();
+    assert_eq!(children.keys().collect::<Vec<_>>(), ["file", "inner"]);
+    assert!(fs.read_dir_children("/sub/inner").unwrap().is_empty());
+}
+
+#[test]
+fn sparse_blocks_report_holes() {
+    let (mut fs, _) = mount_default();
+    let inode_num = create_file(&mut fs, "/sparse", b"");
+    fs.pwrite(inode_num, 0, &[1; 10]).unwrap();
+    fs.pwrite(inode_num, 3 * 1024, &[2; 10]).unwrap();
+    fs.pwrite(inode_num, 20 * 1024, &[3; 10]).unwrap();
+    let mut file = fs.open("/sparse").unwrap();
+    file.truncate(22 * 1024 + 100).unwrap();
+    drop(file);
+    let inode = fs.read_inode(inode_num).unwrap();
+    let blocks: Vec<BlockData> = inode.read_blocks_sparse(&fs.disk).unwrap().map(|block| block.unwrap()).collect();
+    assert_eq!(blocks.len(), 23);
+    for (index, block) in blocks.iter().enumerate() {
+        match index {
+            0 | 3 | 20 => assert!(matches!(block, BlockData::Data(data) if data.len() == 1024), "block {}", index),
+            22 => assert_eq!(*block, BlockData::Hole(100)),
+            _ => assert_eq!(*block, BlockData::Hole(1024), "block {}", index),
+        }
+    }
+    let BlockData::Data(data) = &blocks[20] else { unreachable!() };
+    assert_eq!(data[..10], [3; 10]);
+    assert!(data[10..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn i_blocks_counts_sectors() {
+    for block_size in [1024u64, 4096] {
+        let (mut fs, _) = crate::testing::mount(Mkfs::with_block_size(block_size, 4096).build());
+        let sectors = block_size / 512;
+        let inode_num = create_file(&mut fs, "/file", &pattern(12 * block_size as usize));
+        assert_eq!(fs.read_inode(inode_num).unwrap().raw().i_blocks as u64, 12 * sectors);
+        // The 13th block needs the singly indirect table too
+        fs.pwrite(inode_num, 12 * block_size, b"x").unwrap();
+        assert_eq!(fs.read_inode(inode_num).unwrap().raw().i_blocks as u64, 14 * sectors);
+        assert_eq!(fs.metadata("/file").unwrap().blocks, 14 * sectors);
+    }
+}
+
+#[test]
+fn pointers_per_block_follows_the_block_size() {
+    for (block_size, pointers) in [(1024, 256), (2048, 512), (4096, 1024)] {
+        let (mut fs, _) = crate::testing::mount(Mkfs::with_block_size(block_size, 8192).build());
+        assert_eq!(fs.pointers_per_block(), pointers);
+        // The first block through the doubly indirect table sits at 12 + pointers
+        let inode_num = create_file(&mut fs, "/file", b"");
+        let logical = 12 + pointers;
+        fs.pwrite(inode_num, logical * block_size, b"far").unwrap();
+        let inode = fs.read_inode(inode_num).unwrap();
+        assert_ne!(inode.raw().i_block[13], 0);
+        assert_eq!(inode.raw().i_block[12], 0);
+        assert_ne!(inode.get_block_ptr(&fs.disk, logical).unwrap(), 0);
+        assert_eq!(fs.pread(inode_num, logical * block_size, 3).unwrap(), b"far");
+    }
+}
+
+#[test]
+fn links_of_survives_a_directory_loop() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    let file = create_file(&mut fs, "/a/b/file", b"x");
+    let a = fs.metadata("/a").unwrap().ino;
+    // b links back to a, the walk must not go round forever
+    link(&fs, "/a/b", "up", a, 2);
+    assert_eq!(fs.links_of(a).unwrap(), ["/a", "/a/b/up"]);
+    assert_eq!(fs.links_of(file).unwrap(), ["/a/b/file"]);
+    let paths: Vec<String> = fs.walk("/").unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(paths, ["/a", "/a/b", "/a/b/file", "/a/b/up"]);
+}
+
+#[test]
+fn verify_links_survives_loops_and_skips_free_inodes() {
+    let (mut fs, _) = mount_default();
+    fs.mk_dir("/a", 0o755).unwrap();
+    fs.mk_dir("/a/b", 0o755).unwrap();
+    let a = fs.metadata("/a").unwrap().ino;
+    link(&fs, "/a/b", "up", a, 2);
+    // Named by the root, its own `.`, b's `..` and the loop
+    assert_eq!(fs.verify_links().unwrap(), [(a, 3, 4)]);
+    // Free inodes with a stale link count are not reported
+    let removed = create_file(&mut fs, "/removed", b"x");
+    fs.remove_file("/removed").unwrap();
+    let mut raw = Ext2InodeStruct::new_file(0o644, 0, 0);
+    raw.i_links_count = 1;
+    fs.write_inode_struct(removed, &raw).unwrap();
+    fs.write_inode_struct(100, &raw).unwrap();
+    assert!(!fs.is_inode_allocated(100).unwrap());
+    assert_eq!(fs.verify_links().unwrap(), [(a, 3, 4)]);
+}
+
+#[test]
+fn read_dir_moves_names_into_the_keys() {
+    let (mut fs, _) = mount_default();
+    let file = create_file(&mut fs, "/file", b"x");
+    let entries = fs.read_dir("/").unwrap();
+    assert_eq!(entries.keys().collect::<Vec<_>>(), [".", "..", "file"]);
+    assert_eq!(entries["file"].inode_num(), file);
+    assert!(entries.values().all(|entry| entry.file_name_ref().is_empty()));
+    // Entries read one at a time keep their names
+    let ordered = fs.read_dir_ordered("/").unwrap();
+    assert_eq!(ordered[2].file_name_ref(), "file");
+}
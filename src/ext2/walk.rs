@@ -0,0 +1,76 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::ext2::Ext2Filesystem;
+use crate::ext2::inode::Ext2Inode;
+use crate::fs::error::Error;
+use crate::fs::stat::{Mode, Stat};
+
+/// Depth first iterator over every entry below a directory.
+/// `.` and `..` are skipped and symbolic links are not followed. A directory
+/// reached a second time, only possible through a corrupt loop, is yielded but not entered.
+pub struct WalkIter<'a> {
+    fs: &'a Ext2Filesystem,
+    // Directory the walk starts from, expanded on the first call to next
+    root: Option<(String, Ext2Inode)>,
+    // Entries still to visit: path, inode, depth
+    stack: Vec<(String, Ext2Inode, usize)>,
+    max_depth: Option<usize>,
+    // Directories entered so far
+    visited: BTreeSet<u64>,
+}
+
+impl<'a> WalkIter<'a> {
+    pub fn new(fs: &'a Ext2Filesystem, path: &str, root: Ext2Inode) -> WalkIter<'a> {
+        WalkIter {
+            fs,
+            root: Some((path.trim_end_matches("/").to_string(), root)),
+            stack: Vec::new(),
+            max_depth: None,
+            visited: BTreeSet::from([root.inode()]),
+        }
+    }
+
+    /// Only descend `depth` levels, 1 yields the direct children of the root
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    fn push_children(&mut self, path: &str, inode: &Ext2Inode, depth: usize) -> Result<(), Error> {
+        if self.max_depth.is_some_and(|max| depth > max) {
+            return Ok(());
+        }
+        let entries = inode.read_dir(&self.fs.disk, self.fs, path)?;
+        // Reversed so entries pop off the stack in name order
+        for (name, entry) in entries.into_iter().rev() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            self.stack.push((format!("{}/{}", path, name), entry.inode(), depth));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for WalkIter<'_> {
+    type Item = Result<(String, Stat), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((path, root)) = self.root.take() {
+            if let Err(e) = self.push_children(&path, &root, 1) {
+                return Some(Err(e));
+            }
+        }
+        let (path, inode, depth) = self.stack.pop()?;
+        let stat = inode.metadata();
+        if stat.mode().file_type().bits() == Mode::DIRECTORY.bits() && self.visited.insert(stat.ino) {
+            if let Err(e) = self.push_children(&path, &inode, depth + 1) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok((path, stat)))
+    }
+}
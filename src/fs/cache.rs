@@ -0,0 +1,160 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::btree_map::Entry;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::fs::disk::{Disk, Offset};
+use crate::fs::error::Error;
+
+/// Write-back cache in front of a disk.
+/// Writes are buffered per block and only reach the underlying disk on `flush`,
+/// so repeated updates of the same bitmap / descriptor block cost a single write.
+pub struct WriteCache {
+    disk: Box<dyn Disk>,
+    block_size: u64,
+    // Dirty blocks keyed by block number
+    dirty: RefCell<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl WriteCache {
+    pub fn new(disk: Box<dyn Disk>, block_size: u64) -> WriteCache {
+        WriteCache {
+            disk,
+            block_size,
+            dirty: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Number of blocks waiting to be written back
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.borrow().len()
+    }
+
+    /// Write every dirty block back to the underlying disk
+    pub fn write_back(&self) -> Result<(), Error> {
+        let mut dirty = self.dirty.borrow_mut();
+        for (block_num, block) in dirty.iter() {
+            let offset = Offset::new(self.block_size, *block_num);
+            self.disk.write_at(&offset, block.as_slice())?;
+        }
+        dirty.clear();
+        Ok(())
+    }
+}
+
+impl Disk for WriteCache {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.write_back()?;
+        self.disk.read(buffer)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
+        self.write_back()?;
+        self.disk.write(buffer)
+    }
+
+    fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
+        let mut buffer = self.disk.read_at(offset, size)?;
+        if size == 0 {
+            return Ok(buffer);
+        }
+        let start = offset.value()?;
+        let end = start + size;
+        let dirty = self.dirty.borrow();
+        for (block_num, block) in dirty.range(start / self.block_size..=(end - 1) / self.block_size) {
+            let block_start = block_num * self.block_size;
+            let from = core::cmp::max(start, block_start);
+            let to = core::cmp::min(end, block_start + self.block_size);
+            buffer[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&block[(from - block_start) as usize..(to - block_start) as usize]);
+        }
+        Ok(buffer)
+    }
+
+    fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error> {
+        let start = offset.value()?;
+        let end = match start.checked_add(buffer.len() as u64) {
+            Some(end) => end,
+            None => return Err(Error::InvalidInput("write past the end of the disk".to_string())),
+        };
+        let mut pos = start;
+        let mut dirty = self.dirty.borrow_mut();
+        while pos < end {
+            let block_num = pos / self.block_size;
+            let block_pos = (pos % self.block_size) as usize;
+            let n = core::cmp::min(self.block_size - block_pos as u64, end - pos) as usize;
+            let data = &buffer[(pos - start) as usize..(pos - start) as usize + n];
+            match dirty.entry(block_num) {
+                Entry::Occupied(entry) => {
+                    entry.into_mut()[block_pos..block_pos + n].copy_from_slice(data);
+                }
+                Entry::Vacant(entry) => {
+                    let mut block = match n as u64 == self.block_size {
+                        true => Vec::from(data),
+                        false => self
+                            .disk
+                            .read_at(&Offset::new(self.block_size, block_num), self.block_size)?,
+                    };
+                    block[block_pos..block_pos + n].copy_from_slice(data);
+                    entry.insert(block);
+                }
+            }
+            pos += n as u64;
+        }
+        Ok(buffer.len())
+    }
+
+    fn seek(&self, offset: u64) -> Result<(), Error> {
+        self.disk.seek(offset)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.write_back()?;
+        self.disk.flush()
+    }
+}
+
+impl Drop for WriteCache {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::format;
+
+    use crate::ext2::Ext2Filesystem;
+    use crate::testing::{MemDisk, Mkfs};
+
+    fn create_files(fs: &mut Ext2Filesystem) {
+        for i in 0..100 {
+            fs.new_file(&format!("/f{:02}", i), 0o644).unwrap();
+        }
+        fs.sync().unwrap();
+    }
+
+    #[test]
+    fn coalesces_metadata_writes() {
+        let image = Mkfs::with_block_size(4096, 256).build();
+        let plain = MemDisk::new(image.clone());
+        let mut fs = Ext2Filesystem::mount(Box::new(plain.clone())).unwrap();
+        create_files(&mut fs);
+        let cached = MemDisk::new(image);
+        let mut cached_fs = Ext2Filesystem::mount_cached(Box::new(cached.clone())).unwrap();
+        create_files(&mut cached_fs);
+        // Every file still gets its own zeroed data block, the metadata blocks are written once
+        assert!(cached.writes() < 100 + 20, "{} cached writes", cached.writes());
+        assert!(plain.writes() > 500, "{} plain writes", plain.writes());
+        drop(fs);
+        drop(cached_fs);
+        let mut fs = Ext2Filesystem::mount(Box::new(cached)).unwrap();
+        assert_eq!(fs.read_dir_children("/").unwrap().len(), 100);
+        assert_eq!(fs.verify_links().unwrap(), []);
+        assert!(fs.is_exist("/f99"));
+        fs.sync().unwrap();
+    }
+}
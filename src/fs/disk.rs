@@ -1,3 +1,6 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::ToString;
 use alloc::vec::Vec;
 
 use crate::fs::error::Error;
@@ -13,6 +16,8 @@ pub enum Offset {
         block_num: u64,
         offset: u64,
     },
+    // Byte offset from the start of the disk
+    Absolute(u64),
 }
 
 impl Offset {
@@ -22,21 +27,38 @@ impl Offset {
     pub const fn new_offset(block_size: u64, block_num: u64, offset: u64) -> Self {
         Self::BlockOffset { block_size, block_num, offset }
     }
-    pub fn value(&self) -> u64 {
-        match self {
+    pub const fn absolute(offset: u64) -> Self {
+        Self::Absolute(offset)
+    }
+    /// Byte offset from the start of the disk, fails instead of overflowing
+    pub fn value(&self) -> Result<u64, Error> {
+        let value = match self {
             Offset::Block {
                 block_size,
                 block_num,
-            } => (*block_num) * (*block_size),
+            } => block_num.checked_mul(*block_size),
             Offset::BlockOffset {
                 block_size,
                 block_num,
                 offset,
-            } => *block_num * *block_size + *offset,
+            } => block_num
+                .checked_mul(*block_size)
+                .and_then(|v| v.checked_add(*offset)),
+            Offset::Absolute(offset) => Some(*offset),
+        };
+        match value {
+            Some(value) => Ok(value),
+            None => Err(Error::InvalidInput(format!("{:?} out of range", self))),
         }
     }
 }
 
+/// Block device the filesystem lives on.
+///
+/// `read`, `write` and `seek` share a cursor and are not safe to interleave between users.
+/// The `*_at` methods must not depend on that cursor: every call is complete on its own
+/// (pread/pwrite semantics), so an implementation that is `Sync` can serve concurrent
+/// positioned reads without them contaminating each other.
 pub trait Disk {
     fn read(&self, buffer: &mut [u8]) -> Result<usize, Error>;
     fn write(&self, buffer: &[u8]) -> Result<usize, Error>;
@@ -44,5 +66,74 @@ pub trait Disk {
     fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error>;
     fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error>;
 
+    /// Fill `buffer` from the absolute byte `offset` in a single call
+    fn read_exact_at(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let data = self.read_at(&Offset::absolute(offset), buffer.len() as u64)?;
+        buffer.copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Write all of `buffer` at the absolute byte `offset` in a single call
+    fn write_all_at(&self, offset: u64, buffer: &[u8]) -> Result<(), Error> {
+        match self.write_at(&Offset::absolute(offset), buffer)? == buffer.len() {
+            true => Ok(()),
+            false => Err(Error::IOError("short write".to_string())),
+        }
+    }
+
     fn seek(&self, offset: u64) -> Result<(), Error>;
+
+    /// Make sure everything written so far has reached the device
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Wraps a disk for read-only mounts, every write fails with `PermissionDenied`
+pub struct ReadOnlyDisk {
+    disk: Box<dyn Disk>,
+}
+
+impl ReadOnlyDisk {
+    pub fn new(disk: Box<dyn Disk>) -> Self {
+        Self { disk }
+    }
+    fn refuse() -> Error {
+        Error::PermissionDenied("read-only filesystem".to_string())
+    }
+}
+
+impl Disk for ReadOnlyDisk {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.disk.read(buffer)
+    }
+    fn write(&self, _buffer: &[u8]) -> Result<usize, Error> {
+        Err(Self::refuse())
+    }
+    fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
+        self.disk.read_at(offset, size)
+    }
+    fn write_at(&self, _offset: &Offset, _buffer: &[u8]) -> Result<usize, Error> {
+        Err(Self::refuse())
+    }
+    fn read_exact_at(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.disk.read_exact_at(offset, buffer)
+    }
+    fn seek(&self, offset: u64) -> Result<(), Error> {
+        self.disk.seek(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_overflow_is_an_error() {
+        assert_eq!(Offset::new(1024, 3).value().unwrap(), 3072);
+        assert_eq!(Offset::new_offset(1024, 3, 5).value().unwrap(), 3077);
+        assert_eq!(Offset::absolute(u64::MAX).value().unwrap(), u64::MAX);
+        assert!(matches!(Offset::new(4096, u64::MAX / 1024).value(), Err(Error::InvalidInput(_))));
+        assert!(matches!(Offset::new_offset(1024, u64::MAX / 1024, 1024).value(), Err(Error::InvalidInput(_))));
+    }
 }
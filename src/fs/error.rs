@@ -1,7 +1,8 @@
 use alloc::string::String;
+use core::fmt::{Display, Formatter};
 
 /// The set of all possible errors
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Error {
     InvalidInput(String),
     NotFound(String),
@@ -9,4 +10,51 @@ pub enum Error {
     UnexpectedEof(String),
     InvalidData(String),
     FileExists(String),
-}
\ No newline at end of file
+    PermissionDenied(String),
+    NameTooLong(String),
+    NoSpace(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::IOError(msg) => write!(f, "io error: {}", msg),
+            Error::UnexpectedEof(msg) => write!(f, "unexpected eof: {}", msg),
+            Error::InvalidData(msg) => write!(f, "invalid data: {}", msg),
+            Error::FileExists(msg) => write!(f, "file exists: {}", msg),
+            Error::PermissionDenied(msg) => write!(f, "permission denied: {}", msg),
+            Error::NameTooLong(msg) => write!(f, "name too long: {}", msg),
+            Error::NoSpace(msg) => write!(f, "no space left on device: {}", msg),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use alloc::format;
+    use alloc::string::ToString;
+
+    #[test]
+    fn display() {
+        let cases = [
+            (Error::InvalidInput("/a".to_string()), "invalid input: /a"),
+            (Error::NotFound("/foo".to_string()), "not found: /foo"),
+            (Error::IOError("bad sector".to_string()), "io error: bad sector"),
+            (Error::UnexpectedEof("block 7".to_string()), "unexpected eof: block 7"),
+            (Error::InvalidData("magic".to_string()), "invalid data: magic"),
+            (Error::FileExists("/b".to_string()), "file exists: /b"),
+            (Error::PermissionDenied("/c".to_string()), "permission denied: /c"),
+            (Error::NameTooLong("x".to_string()), "name too long: x"),
+            (Error::NoSpace("3 blocks".to_string()), "no space left on device: 3 blocks"),
+        ];
+        for (error, message) in cases {
+            assert_eq!(format!("{}", error), message);
+        }
+    }
+}
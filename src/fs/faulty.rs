@@ -0,0 +1,177 @@
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use crate::fs::disk::{Disk, Offset};
+use crate::fs::error::Error;
+
+/// Disk wrapper that fails chosen reads and writes, for exercising error paths,
+/// e.g. `FaultyDisk::new(inner).fail_write_after(3)`
+pub struct FaultyDisk<D: Disk> {
+    disk: D,
+    error: Error,
+    block_size: u64,
+    // Position of the shared cursor used by `read` and `write`
+    pos: Cell<u64>,
+    reads: Cell<u64>,
+    writes: Cell<u64>,
+    read_limit: Option<u64>,
+    write_limit: Option<u64>,
+    read_blocks: Option<Box<dyn Fn(u64) -> bool>>,
+    write_blocks: Option<Box<dyn Fn(u64) -> bool>>,
+}
+
+impl<D: Disk> FaultyDisk<D> {
+    /// Pass everything through until a failure is configured
+    pub fn new(disk: D) -> FaultyDisk<D> {
+        FaultyDisk {
+            disk,
+            error: Error::IOError("injected failure".to_string()),
+            block_size: 1024,
+            pos: Cell::new(0),
+            reads: Cell::new(0),
+            writes: Cell::new(0),
+            read_limit: None,
+            write_limit: None,
+            read_blocks: None,
+            write_blocks: None,
+        }
+    }
+
+    /// Error returned by failing calls, an IOError by default
+    pub fn with_error(mut self, error: Error) -> Self {
+        self.error = error;
+        self
+    }
+
+    /// Block size the block predicates count in, 1024 by default
+    pub fn block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Let `n` reads succeed, every later one fails
+    pub fn fail_read_after(mut self, n: u64) -> Self {
+        self.read_limit = Some(n);
+        self
+    }
+
+    /// Let `n` writes succeed, every later one fails
+    pub fn fail_write_after(mut self, n: u64) -> Self {
+        self.write_limit = Some(n);
+        self
+    }
+
+    /// Fail reads touching any block `predicate` accepts
+    pub fn fail_read_blocks(mut self, predicate: impl Fn(u64) -> bool + 'static) -> Self {
+        self.read_blocks = Some(Box::new(predicate));
+        self
+    }
+
+    /// Fail writes touching any block `predicate` accepts
+    pub fn fail_write_blocks(mut self, predicate: impl Fn(u64) -> bool + 'static) -> Self {
+        self.write_blocks = Some(Box::new(predicate));
+        self
+    }
+
+    /// Reads and writes that reached the inner disk
+    pub fn counts(&self) -> (u64, u64) {
+        (self.reads.get(), self.writes.get())
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    fn check(&self, start: u64, size: u64, write: bool) -> Result<(), Error> {
+        let (count, limit, blocks) = match write {
+            true => (&self.writes, self.write_limit, &self.write_blocks),
+            false => (&self.reads, self.read_limit, &self.read_blocks),
+        };
+        if limit.is_some_and(|limit| count.get() >= limit) {
+            return Err(self.error.clone());
+        }
+        if let Some(predicate) = blocks {
+            let last = (start + size.max(1) - 1) / self.block_size;
+            if (start / self.block_size..=last).any(predicate) {
+                return Err(self.error.clone());
+            }
+        }
+        count.set(count.get() + 1);
+        Ok(())
+    }
+}
+
+impl<D: Disk> Disk for FaultyDisk<D> {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.check(self.pos.get(), buffer.len() as u64, false)?;
+        let n = self.disk.read(buffer)?;
+        self.pos.set(self.pos.get() + n as u64);
+        Ok(n)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
+        self.check(self.pos.get(), buffer.len() as u64, true)?;
+        let n = self.disk.write(buffer)?;
+        self.pos.set(self.pos.get() + n as u64);
+        Ok(n)
+    }
+
+    fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
+        self.check(offset.value()?, size, false)?;
+        self.disk.read_at(offset, size)
+    }
+
+    fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error> {
+        self.check(offset.value()?, buffer.len() as u64, true)?;
+        self.disk.write_at(offset, buffer)
+    }
+
+    fn seek(&self, offset: u64) -> Result<(), Error> {
+        self.disk.seek(offset)?;
+        self.pos.set(offset);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemDisk;
+    use alloc::vec;
+
+    #[test]
+    fn nth_write_fails() {
+        let disk = FaultyDisk::new(MemDisk::new(vec![0; 4096])).fail_write_after(2);
+        disk.write_at(&Offset::absolute(0), &[1]).unwrap();
+        disk.write_at(&Offset::absolute(1), &[2]).unwrap();
+        assert!(matches!(disk.write_at(&Offset::absolute(2), &[3]), Err(Error::IOError(_))));
+        assert!(disk.write_all_at(3, &[4]).is_err());
+        // Reads are unaffected and the failed writes never reached the disk
+        assert_eq!(disk.read_at(&Offset::absolute(0), 4).unwrap(), [1, 2, 0, 0]);
+        assert_eq!(disk.counts(), (1, 2));
+    }
+
+    #[test]
+    fn reads_of_chosen_blocks_fail() {
+        let disk = FaultyDisk::new(MemDisk::new(vec![0; 4096]))
+            .block_size(1024)
+            .with_error(Error::UnexpectedEof("bad sector".to_string()))
+            .fail_read_blocks(|block| block == 2);
+        assert!(disk.read_at(&Offset::new(1024, 1), 1024).is_ok());
+        assert!(matches!(disk.read_at(&Offset::new(1024, 2), 1024), Err(Error::UnexpectedEof(_))));
+        // Any read touching the block fails
+        assert!(disk.read_at(&Offset::absolute(2047), 2).is_err());
+        assert!(disk.read_at(&Offset::absolute(3072), 1024).is_ok());
+        disk.seek(2048).unwrap();
+        assert!(disk.read(&mut [0; 8]).is_err());
+        let disk = FaultyDisk::new(MemDisk::new(vec![0; 4096])).fail_read_after(0);
+        assert!(disk.read_at(&Offset::absolute(0), 1).is_err());
+        assert!(disk.write_at(&Offset::absolute(0), &[1]).is_ok());
+    }
+}
@@ -1,12 +1,13 @@
 #![allow(dead_code)]
 
+use alloc::format;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::{Display, Formatter};
 
 use crate::ext2::Ext2Filesystem;
-use crate::ext2::inode::{EXT2_NDIR_BLOCKS, Ext2Inode};
+use crate::ext2::inode::Ext2Inode;
 use crate::fs::disk::Offset;
 use crate::fs::error::Error;
 use crate::fs::io::CoreRead;
@@ -17,7 +18,7 @@ pub struct FsFile<'a> {
     fs: &'a mut Ext2Filesystem,
     pub inode: Ext2Inode,
     blocks: Vec<u64>,
-    pos: u64,
+    pub(crate) pos: u64,
     stat: Stat,
 }
 
@@ -42,35 +43,70 @@ impl FsFile<'_> {
     pub fn inode(&self) -> u64 {
         self.inode.inode()
     }
+    /// Physical block of `file_block_num`, 0 for a hole. Blocks missing from the cache
+    /// are looked up in the inode's block map and added to it.
+    fn physical_block(&mut self, file_block_num: u64) -> Result<u64, Error> {
+        let index = file_block_num as usize;
+        if self.blocks.get(index).is_none_or(|&block| block == 0) {
+            let block = self.inode.get_block_ptr(&self.fs.disk, file_block_num)?;
+            if block == 0 {
+                return Ok(0);
+            }
+            if index >= self.blocks.len() {
+                self.blocks.resize(index + 1, 0);
+            }
+            self.blocks[index] = block;
+        }
+        Ok(self.blocks[index])
+    }
+
     fn read_block(&mut self, file_block_num: u64) -> Result<Vec<u8>, Error> {
-        let offset = Offset::new(
-            self.inode.get_block_size(),
-            self.blocks[file_block_num as usize],
-        );
-        self.fs.disk.read_at(&offset, self.inode.get_block_size())
+        let block_size = self.inode.get_block_size();
+        if file_block_num >= self.inode.get_size().div_ceil(block_size) {
+            return Err(Error::UnexpectedEof(format!("block {} past the end of the file", file_block_num)));
+        }
+        // Holes read as zeros
+        match self.physical_block(file_block_num)? {
+            0 => Ok(vec![0; block_size as usize]),
+            block => self.fs.disk.read_at(&Offset::new(block_size, block), block_size),
+        }
     }
 
     fn write_block(&mut self, file_block_num: u64, offset: u64, buffer: &[u8]) -> Result<usize, Error> {
-        assert!(file_block_num < EXT2_NDIR_BLOCKS as u64);
-        //TODO: size > 12k file
-        let mut inode = self.inode;
-        if inode.ext2_inode.i_block[file_block_num as usize] == 0 {
-            if let Some(new_block) = self.fs.alloc_block() {
-                self.blocks.push(new_block as u64);
-                inode.ext2_inode.i_block[file_block_num as usize] = new_block;
-                inode.ext2_inode.i_blocks += 1;
-                self.inode.data_blocks_count += 1;
-                inode.write(&self.fs.disk, &self.fs.block_groups)
+        let index = file_block_num as usize;
+        if self.physical_block(file_block_num)? == 0 {
+            // Past the direct blocks the pointer goes through the indirect tables,
+            // set_block_ptr allocates any index block that is still missing
+            let new_block = match self.fs.alloc_block() {
+                Some(new_block) => new_block,
+                None => return Err(Error::NoSpace(format!("no block for block {} of inode {}", file_block_num, self.inode()))),
+            };
+            let mut inode = self.inode;
+            inode.set_block_ptr(self.fs, file_block_num, new_block)?;
+            inode.ext2_inode.i_blocks += (inode.get_block_size() / 512) as u32;
+            inode.data_blocks_count = inode.data_blocks_count.max(file_block_num + 1);
+            inode.write(&self.fs.disk, &self.fs.block_groups)?;
+            self.inode = inode;
+            if index >= self.blocks.len() {
+                self.blocks.resize(index + 1, 0);
             }
+            self.blocks[index] = new_block as u64;
         }
-        self.inode = inode;
         let offset = Offset::new_offset(
             self.inode.get_block_size(),
-            self.blocks[file_block_num as usize],
+            self.blocks[index],
             offset,
         );
         self.fs.disk.write_at(&offset, buffer)
     }
+    /// Whole contents of logical block `block_index` and how many of its bytes are
+    /// inside the file, less than the block size only for the last block. Holes read as zeros.
+    pub fn block_view(&mut self, block_index: u64) -> Result<(Vec<u8>, usize), Error> {
+        let block_size = self.inode.get_block_size();
+        let buffer = self.read_block(block_index)?;
+        let valid = (self.inode.get_size() - block_index * block_size).min(block_size);
+        Ok((buffer, valid as usize))
+    }
     fn how_many_bytes(&self, buffer_len: usize) -> usize {
         if self.pos + buffer_len as u64 > self.inode.get_size() {
             (self.inode.get_size() - self.pos) as usize
@@ -79,68 +115,134 @@ impl FsFile<'_> {
         }
     }
 
-    fn zero_padding(&self, read_bytes: usize, buffer_len: usize, buffer: &mut Vec<u8>) {
-        if read_bytes < buffer_len {
-            let zero: Vec<u8> = vec![0; buffer_len - read_bytes];
-            buffer.extend_from_slice(&zero);
-        }
-    }
-
     fn is_eol(&self) -> bool {
         self.pos >= self.inode.get_size()
     }
+    /// Read up to the end of the current block or the end of the file,
+    /// whichever comes first. Returns 0 at the end of the file, `buf` is then left untouched.
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         if self.is_eol() {
-            // End of file
-            let zero: Vec<u8> = vec![0; buf.len()];
-            buf.copy_from_slice(&zero[..]);
-            Ok(0)
-        } else {
-            let buffer_len = buf.len();
-            let read_bytes = self.how_many_bytes(buffer_len);
-            let block_num = self.pos / self.inode.get_block_size();
-            let mut buffer = self.read_block(block_num)?;
-            self.zero_padding(read_bytes, buffer_len, &mut buffer);
-            let block_pos: usize = (self.pos - block_num * self.inode.get_block_size()) as usize;
-            buf.copy_from_slice(&buffer[block_pos..block_pos + buffer_len]);
-            self.pos += read_bytes as u64;
-            Ok(read_bytes)
+            return Ok(0);
+        }
+        let block_size = self.inode.get_block_size();
+        let block_num = self.pos / block_size;
+        let block_pos = (self.pos % block_size) as usize;
+        let read_bytes = core::cmp::min(
+            self.how_many_bytes(buf.len()),
+            block_size as usize - block_pos,
+        );
+        let buffer = self.read_block(block_num)?;
+        buf[..read_bytes].copy_from_slice(&buffer[block_pos..block_pos + read_bytes]);
+        self.pos += read_bytes as u64;
+        Ok(read_bytes)
+    }
+    /// Bulk read when `pos` and `buf.len()` are multiples of the block size.
+    /// Physically contiguous blocks are fetched with a single disk read,
+    /// anything unaligned falls back to `read`.
+    pub fn read_aligned(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let block_size = self.inode.get_block_size();
+        if !self.pos.is_multiple_of(block_size) || !(buf.len() as u64).is_multiple_of(block_size) {
+            return self.read(buf);
         }
+        if self.is_eol() {
+            return Ok(0);
+        }
+        let read_bytes = self.how_many_bytes(buf.len());
+        let first = self.pos / block_size;
+        let count = (read_bytes as u64).div_ceil(block_size);
+        let mut i = 0;
+        while i < count {
+            let start = self.physical_block(first + i)?;
+            // Extend the run while the next block follows on disk
+            let mut run = 1;
+            while start != 0 && i + run < count && self.physical_block(first + i + run)? == start + run {
+                run += 1;
+            }
+            let range = (i * block_size) as usize..((i + run) * block_size) as usize;
+            match start {
+                0 => buf[range].fill(0),
+                _ => self.fs.disk.read_exact_at(start * block_size, &mut buf[range])?,
+            }
+            i += run;
+        }
+        self.pos += read_bytes as u64;
+        Ok(read_bytes)
     }
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         let block_size = self.inode.get_block_size();
         let mut write_bytes = 0;
         let mut buffer = buf;
-        loop {
-            let write_buf = match buffer.len() <= block_size as usize {
-                true => {
-                    buffer
-                }
-                false => {
-                    let b = buffer.split_at(block_size as usize);
-                    buffer = b.1;
-                    b.0
-                }
-            };
+        while !buffer.is_empty() {
             let blk_num = self.pos / block_size;
             let blk_pos = self.pos % block_size;
+            // Never run past the end of the current block into whatever follows it on disk
+            let len = core::cmp::min(buffer.len(), (block_size - blk_pos) as usize);
+            let (write_buf, rest) = buffer.split_at(len);
+            buffer = rest;
             let size = self.write_block(blk_num, blk_pos, write_buf)?;
             self.pos += size as u64;
             write_bytes += size;
-            if write_bytes == buf.len() {
-                break;
+        }
+        // Overwrites keep the size, writes past the end leave a hole before them
+        if self.pos > self.inode.size {
+            self.set_size(self.pos)?;
+        }
+        Ok(write_bytes)
+    }
+    /// Set the file size, growing leaves a hole. Shrinking frees the blocks past
+    /// the new end and zeroes the rest of the last block, so growing again reads zeros.
+    pub fn truncate(&mut self, size: u64) -> Result<(), Error> {
+        let block_size = self.inode.get_block_size();
+        if size < self.inode.get_size() {
+            let keep = size.div_ceil(block_size);
+            let mut inode = self.inode;
+            inode.free_indirect_blocks(self.fs, keep)?;
+            self.inode = inode;
+            self.blocks.truncate(keep as usize);
+            let tail = size % block_size;
+            if tail != 0 {
+                let block = self.physical_block(keep - 1)?;
+                if block != 0 {
+                    self.fs.write_block(block, tail, &vec![0u8; (block_size - tail) as usize])?;
+                }
             }
         }
+        self.set_size(size)?;
+        if self.pos > size {
+            self.pos = size
+        }
+        Ok(())
+    }
+    fn set_size(&mut self, size: u64) -> Result<(), Error> {
         let mut inode = self.inode;
-        inode.ext2_inode.i_size += write_bytes as u32;
-        inode.size += write_bytes as u64;
+        inode.ext2_inode.set_size(size);
+        inode.size = size;
+        inode.data_blocks_count = size.div_ceil(inode.get_block_size());
         self.inode = inode;
-        inode.write(&self.fs.disk, &self.fs.block_groups);
-        Ok(write_bytes)
+        self.fs.set_large_file(size)?;
+        inode.write(&self.fs.disk, &self.fs.block_groups)
+    }
+    /// Write the inode and flush the disk so data and size are durable
+    pub fn sync_data(&mut self) -> Result<(), Error> {
+        self.inode.write(&self.fs.disk, &self.fs.block_groups)?;
+        self.fs.disk.flush()
+    }
+    /// Like `sync_data`, also writing the superblock and cached bitmaps
+    pub fn sync_all(&mut self) -> Result<(), Error> {
+        self.inode.write(&self.fs.disk, &self.fs.block_groups)?;
+        self.fs.sync()
     }
     pub fn seek(&mut self, offset: u64) {
         self.pos = offset
     }
+    /// Current read and write position
+    pub fn stream_position(&self) -> u64 {
+        self.pos
+    }
+    /// Go back to the start of the file
+    pub fn rewind(&mut self) {
+        self.pos = 0
+    }
     pub fn stat(&self) -> Stat {
         self.stat
     }
@@ -158,16 +260,281 @@ impl CoreRead for FsFile<'_> {
     }
 
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, Error> {
-        let file_size = self.inode.metadata().size as usize;
         let mut tmp = [0u8; 32];
         let mut n = 0;
         loop {
-            let rn = self.read(&mut tmp).unwrap();
+            let rn = self.read(&mut tmp)?;
+            if rn == 0 { break; }
             buf.extend_from_slice(&tmp[0..rn]);
             n += rn;
-            if n >= file_size { break; }
         }
         Ok(n)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::ext2::Ext2Filesystem;
+    use crate::fs::error::Error;
+    use crate::fs::file::FsFile;
+    use crate::ext2::superblock::FeatureRoCompat;
+    use crate::testing::{create_file, mount, mount_default, pattern, Mkfs};
+
+    #[test]
+    fn truncate_past_4_gib() {
+        let (mut fs, disk) = mount_default();
+        let size = (5u64 << 30) + 123;
+        {
+            let mut file = fs.new_file("/big", 0o644).unwrap();
+            file.truncate(size).unwrap();
+            let mut buf = [0xffu8; 8];
+            file.seek(4 << 30);
+            assert_eq!(file.read(&mut buf).unwrap(), 8);
+            assert_eq!(buf, [0u8; 8]);
+            file.seek(size - 3);
+            assert_eq!(file.read(&mut buf).unwrap(), 3);
+        }
+        assert_eq!(fs.metadata("/big").unwrap().size, size);
+        assert!(fs.describe().ro_compat.contains(FeatureRoCompat::LARGE_FILE));
+        drop(fs);
+        let fs = Ext2Filesystem::mount(Box::new(disk)).unwrap();
+        assert_eq!(fs.metadata("/big").unwrap().size, size);
+    }
+
+    #[test]
+    fn shrink_frees_blocks_and_regrows_as_a_hole() {
+        let (mut fs, _) = mount_default();
+        let data = pattern(5000);
+        let inode = create_file(&mut fs, "/file", &data);
+        let free = fs.describe().free_blocks;
+        {
+            let mut file = fs.open("/file").unwrap();
+            file.truncate(1500).unwrap();
+            file.truncate(5000).unwrap();
+        }
+        // Blocks 2, 3 and 4 went back, block 1 keeps its first 476 bytes
+        assert_eq!(fs.describe().free_blocks, free + 3);
+        let read = fs.pread(inode, 0, 5000).unwrap();
+        assert_eq!(read[..1500], data[..1500]);
+        assert!(read[1500..].iter().all(|&b| b == 0));
+        // A write past the new end does not bring the old bytes back either
+        fs.pwrite(inode, 4000, b"x").unwrap();
+        assert!(fs.pread(inode, 1500, 2500).unwrap().iter().all(|&b| b == 0));
+        assert_eq!(fs.metadata("/file").unwrap().blocks, 3 * 2);
+    }
+
+    /// Index blocks of `inode_num`: the single and doubly indirect tables and the tables below the latter
+    fn index_blocks(fs: &Ext2Filesystem, inode_num: u64) -> Vec<u64> {
+        let i_block = fs.read_inode(inode_num).unwrap().blocks();
+        let mut tables = vec![i_block[12] as u64, i_block[13] as u64];
+        tables.extend(fs.get_block_num(i_block[13] as u64, 1).unwrap());
+        tables
+    }
+
+    fn block_in_use(fs: &Ext2Filesystem, block: u64) -> bool {
+        fs.block_bitmap((block - 1) / 8192).unwrap().get((block as usize - 1) % 8192)
+    }
+
+    #[test]
+    fn ten_mib_through_the_indirect_tree() {
+        let (mut fs, _) = mount(Mkfs { blocks: 16384, ..Mkfs::default() }.build());
+        let free = fs.describe().free_blocks;
+        let data = pattern(10 << 20);
+        let inode_num = create_file(&mut fs, "/big", &data);
+        let tables = index_blocks(&fs, inode_num);
+        // 12 direct, 256 single indirect, the other 9972 below 39 level 1 tables
+        assert_eq!(tables.len(), 2 + 39);
+        assert_eq!(fs.describe().free_blocks, free - 10240 - tables.len() as u64);
+        let mut file = fs.open("/big").unwrap();
+        let mut block = vec![0u8; 1024];
+        for (i, expected) in data.chunks(1024).enumerate() {
+            assert_eq!(file.read(&mut block).unwrap(), 1024);
+            assert!(block == expected, "block {} differs", i);
+        }
+        fs.remove_file("/big").unwrap();
+        assert!(tables.iter().all(|&table| !block_in_use(&fs, table)));
+        assert_eq!(fs.describe().free_blocks, free);
+    }
+
+    #[test]
+    fn shrink_out_of_the_doubly_indirect_tree() {
+        let (mut fs, _) = mount_default();
+        let free = fs.describe().free_blocks;
+        let inode_num = create_file(&mut fs, "/file", &pattern(300 * 1024));
+        let tables = index_blocks(&fs, inode_num);
+        assert_eq!(tables.len(), 3);
+        fs.open("/file").unwrap().truncate(5 * 1024).unwrap();
+        let inode = fs.read_inode(inode_num).unwrap();
+        assert_eq!(inode.blocks()[5..], [0; 10]);
+        assert_eq!(inode.raw().i_blocks, 5 * 2);
+        assert!(tables.iter().all(|&table| !block_in_use(&fs, table)));
+        assert_eq!(fs.describe().free_blocks, free - 5);
+        assert_eq!(fs.pread(inode_num, 0, 5 * 1024).unwrap(), pattern(5 * 1024));
+    }
+
+    #[test]
+    fn read_up_to_and_past_eof() {
+        let (mut fs, _) = mount_default();
+        let data = pattern(1500);
+        create_file(&mut fs, "/file", &data);
+        let mut file = fs.open("/file").unwrap();
+        // Stops at the end of the block, then at the end of the last, partial block
+        file.seek(1000);
+        let mut buf = vec![0u8; 1024];
+        assert_eq!(file.read(&mut buf).unwrap(), 24);
+        assert_eq!(buf[..24], data[1000..1024]);
+        assert_eq!(file.read(&mut buf).unwrap(), 476);
+        assert_eq!(buf[..476], data[1024..]);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        file.seek(1499);
+        assert_eq!(file.read(&mut buf[..1]).unwrap(), 1);
+        assert_eq!(buf[0], data[1499]);
+        file.seek(1501);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_aligned_coalesces_contiguous_blocks() {
+        let (mut fs, disk) = mount_default();
+        let data = pattern(64 * 1024);
+        create_file(&mut fs, "/file", &data);
+        let mut file = fs.open("/file").unwrap();
+        let mut buf = vec![0u8; data.len()];
+        let reads = disk.reads();
+        assert_eq!(file.read_aligned(&mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+        let aligned = disk.reads() - reads;
+        file.seek(0);
+        let reads = disk.reads();
+        let mut done = 0;
+        while done < buf.len() {
+            done += file.read(&mut buf[done..]).unwrap();
+        }
+        let plain = disk.reads() - reads;
+        // One run for the direct blocks, the index block sits between them and the rest
+        assert!(aligned <= 3, "{} reads", aligned);
+        assert!(plain >= 64, "{} reads", plain);
+        // Unaligned requests fall back to read
+        file.seek(10);
+        assert_eq!(file.read_aligned(&mut buf[..100]).unwrap(), 100);
+        assert_eq!(buf[..100], data[10..110]);
+    }
+
+    #[test]
+    fn read_past_the_cached_blocks() {
+        let (mut fs, _) = mount_default();
+        let data = pattern(20 * 1024 + 10);
+        let inode_num = create_file(&mut fs, "/file", &data);
+        let inode = fs.read_inode(inode_num).unwrap();
+        // Nothing cached, every block comes from the block map
+        let mut file = FsFile::new(&mut fs, inode, Vec::new(), String::new());
+        let mut buf = [0u8; 100];
+        file.seek(15 * 1024 + 3);
+        assert_eq!(file.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf[..], data[15 * 1024 + 3..15 * 1024 + 103]);
+        assert_eq!(file.blocks().len(), 16);
+        assert!(matches!(file.block_view(21), Err(Error::UnexpectedEof(_))));
+    }
+
+    #[test]
+    fn cached_blocks_follow_truncate_and_sparse_writes() {
+        let (mut fs, _) = mount_default();
+        let inode_num = create_file(&mut fs, "/file", &pattern(20 * 1024));
+        let mut file = fs.open("/file").unwrap();
+        let check = |file: &FsFile| {
+            for (index, &block) in file.blocks().iter().enumerate() {
+                assert_eq!(file.inode.get_block_ptr(&file.fs.disk, index as u64).unwrap(), block, "block {}", index);
+            }
+        };
+        file.truncate(5 * 1024).unwrap();
+        assert_eq!(file.blocks().len(), 5);
+        check(&file);
+        file.seek(30 * 1024);
+        file.write(b"tail").unwrap();
+        check(&file);
+        file.seek(8 * 1024);
+        file.write(b"middle").unwrap();
+        check(&file);
+        assert_eq!(file.blocks()[30], file.inode.get_block_ptr(&file.fs.disk, 30).unwrap());
+        drop(file);
+        let mut file = fs.open_inode(inode_num).unwrap();
+        let mut buf = [0u8; 6];
+        file.seek(8 * 1024);
+        file.read(&mut buf).unwrap();
+        assert_eq!(&buf, b"middle");
+    }
+
+    #[test]
+    fn eof_leaves_the_buffer_alone() {
+        let (mut fs, _) = mount_default();
+        create_file(&mut fs, "/file", b"short");
+        let mut file = fs.open("/file").unwrap();
+        let mut buf = [0xa5u8; 16];
+        file.seek(5);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        file.seek(4000);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+        assert_eq!(file.read_aligned(&mut [0xa5; 1024]).unwrap(), 0);
+        assert_eq!(buf, [0xa5; 16]);
+    }
+
+    #[test]
+    fn position_and_rewind() {
+        let (mut fs, _) = mount_default();
+        let mut file = fs.new_file("/file", 0o644).unwrap();
+        assert_eq!(file.stream_position(), 0);
+        file.write(b"hello, ").unwrap();
+        file.write(b"world").unwrap();
+        assert_eq!(file.stream_position(), 12);
+        file.rewind();
+        assert_eq!(file.stream_position(), 0);
+        let mut buf = [0u8; 12];
+        assert_eq!(file.read(&mut buf).unwrap(), 12);
+        assert_eq!(&buf, b"hello, world");
+        assert_eq!(file.stream_position(), 12);
+    }
+
+    #[test]
+    fn block_views() {
+        let (mut fs, _) = mount_default();
+        let data = pattern(3 * 1024 + 100);
+        let inode_num = create_file(&mut fs, "/file", &data);
+        let mut file = fs.open_inode(inode_num).unwrap();
+        for index in 0..3 {
+            let (block, valid) = file.block_view(index).unwrap();
+            assert_eq!(valid, 1024);
+            assert_eq!(block[..], data[index as usize * 1024..(index as usize + 1) * 1024]);
+        }
+        let (block, valid) = file.block_view(3).unwrap();
+        assert_eq!((block.len(), valid), (1024, 100));
+        assert_eq!(block[..valid], data[3 * 1024..]);
+        assert!(file.block_view(4).is_err());
+        // A hole is a block of zeros
+        file.truncate(6 * 1024).unwrap();
+        assert_eq!(file.block_view(5).unwrap(), (vec![0; 1024], 1024));
+        assert_eq!(file.stream_position(), 0);
+    }
+
+    #[test]
+    fn sync_flushes_the_disk() {
+        let (mut fs, disk) = mount_default();
+        let mut file = fs.new_file("/file", 0o644).unwrap();
+        file.write(b"durable").unwrap();
+        let flushes = disk.flushes();
+        file.sync_data().unwrap();
+        assert_eq!(disk.flushes(), flushes + 1);
+        file.sync_all().unwrap();
+        assert_eq!(disk.flushes(), flushes + 2);
+        drop(file);
+        // The superblock sync_all wrote is on disk, mounting the bytes again sees the file
+        let (fs, _) = mount(disk.bytes());
+        let inode_num = fs.resolve_components("/file").unwrap()[1].1.inode();
+        assert_eq!(fs.pread(inode_num, 0, 7).unwrap(), b"durable");
+    }
+}
@@ -27,10 +27,14 @@ pub trait CoreRead {
             Err(Error::UnexpectedEof("".to_string()))
         }
     }
+    /// Read a plain-old-data struct from its on-disk byte representation.
+    /// The bytes are copied out with `read_unaligned`, so the source buffer
+    /// does not need to be aligned to `T`. Less than `size_of::<T>()` bytes
+    /// left is an `UnexpectedEof` from `read_exact`.
     fn read_struct<T: Sized>(&mut self) -> Result<T, Error> {
         let mut buf = vec![0u8; core::mem::size_of::<T>()];
         self.read_exact(buf.as_mut_slice())?;
-        unsafe { Ok((buf.as_ptr() as *const T).read()) }
+        unsafe { Ok((buf.as_ptr() as *const T).read_unaligned()) }
     }
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, Error>;
     fn read_to_string(&mut self, buf: &mut String) -> Result<usize, Error> {
@@ -101,4 +105,33 @@ impl CoreWrite for &[u8] {
         *self = &self[buf.len()..];
         Ok(buf.len())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::ext2::superblock::Ext2SuperBlock;
+    use crate::testing::Mkfs;
+
+    #[test]
+    fn read_struct_from_misaligned_offset() {
+        let image = Mkfs::default().build();
+        // Superblock bytes at an odd offset, nothing about the buffer is aligned to u32
+        let mut buffer = vec![0xaau8; 1 + 1024 + 7];
+        buffer[1..1025].copy_from_slice(&image[1024..2048]);
+        let mut reader = &buffer[1..];
+        let sb = reader.read_struct::<Ext2SuperBlock>().unwrap();
+        assert_eq!(sb.s_magic, Ext2SuperBlock::MAGIC);
+        assert_eq!(sb.s_blocks_count, 2048);
+        assert_eq!(sb.s_inodes_per_group, 128);
+        assert_eq!(reader.len(), 7);
+    }
+
+    #[test]
+    fn read_struct_short_input() {
+        let buffer = vec![0u8; 1023];
+        let result = buffer.as_slice().read_struct::<Ext2SuperBlock>();
+        assert!(matches!(result, Err(Error::UnexpectedEof(_))));
+    }
+}
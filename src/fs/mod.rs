@@ -2,18 +2,33 @@ use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 
-use crate::ext2::Ext2Filesystem;
+use crate::ext2::{Ext2Filesystem, MountOptions};
 use crate::fs::disk::Disk;
 use crate::fs::error::Error;
 
+pub mod cache;
 pub mod disk;
 pub mod error;
+pub mod faulty;
 pub mod file;
 pub mod io;
+pub mod slice;
 pub mod stat;
+#[cfg(feature = "std")]
+pub mod std_io;
 
-pub fn mount(disk: Box<(dyn Disk + 'static)>) -> Result<Ext2Filesystem, Error> {
-    Ok(Ext2Filesystem::mount(disk)?)
+pub fn mount(disk: Box<dyn Disk>) -> Result<Ext2Filesystem, Error> {
+    Ext2Filesystem::mount(disk)
+}
+
+/// Mount with explicit options, e.g. to rebuild damaged bitmaps in memory
+pub fn mount_with_options(disk: Box<dyn Disk>, options: MountOptions) -> Result<Ext2Filesystem, Error> {
+    Ext2Filesystem::mount_with_options(disk, options)
+}
+
+/// Mount with a write-back cache, dirty blocks are written on `sync` or drop
+pub fn mount_cached(disk: Box<dyn Disk>) -> Result<Ext2Filesystem, Error> {
+    Ext2Filesystem::mount_cached(disk)
 }
 
 pub fn base_dir(path: &str) -> String {
@@ -29,4 +44,55 @@ pub fn base_dir(path: &str) -> String {
 pub fn base_file(path: &str) -> String {
     let mut path_vector = path.split("/").collect::<Vec<&str>>();
     path_vector.pop().unwrap().to_string()
-}
\ No newline at end of file
+}
+/// Match a single path component against a pattern, `*` matches any run of characters and `?` one character
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] != '*' && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` swallow one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "*x"));
+        assert!(wildcard_match("a*", "a*"));
+        assert!(wildcard_match("*.conf", "host.conf"));
+        assert!(!wildcard_match("*.conf", "host.conf.bak"));
+        assert!(wildcard_match("h?st*", "host.conf"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("a?", "a"));
+        assert!(wildcard_match("literal", "literal"));
+        assert!(!wildcard_match("literal", "literals"));
+    }
+
+    #[test]
+    fn path_parts() {
+        assert_eq!(base_dir("/a/b/c"), "/a/b");
+        assert_eq!(base_dir("/a"), "/");
+        assert_eq!(base_file("/a/b/c"), "c");
+    }
+}
@@ -0,0 +1,112 @@
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cell::{Cell, UnsafeCell};
+
+use crate::fs::disk::{Disk, Offset};
+use crate::fs::error::Error;
+
+/// Disk backed by a borrowed byte slice, e.g. a static image buffer.
+/// `Ext2Filesystem::mount` takes a `Box<dyn Disk>`, which is `'static`, so only a
+/// `SliceDisk<'static>` can be mounted: a `static mut` array or a leaked buffer.
+/// Shorter borrows, like a stack array, can still be used through the `Disk` methods.
+pub struct SliceDisk<'a> {
+    data: UnsafeCell<&'a mut [u8]>,
+    pos: Cell<u64>,
+}
+
+impl<'a> SliceDisk<'a> {
+    pub fn new(data: &'a mut [u8]) -> SliceDisk<'a> {
+        SliceDisk {
+            data: UnsafeCell::new(data),
+            pos: Cell::new(0),
+        }
+    }
+
+    /// Total size in bytes
+    pub fn size(&self) -> u64 {
+        self.bytes().len() as u64
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { &**self.data.get() }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn bytes_mut(&self) -> &mut [u8] {
+        unsafe { &mut **self.data.get() }
+    }
+
+    fn range(&self, start: u64, size: u64) -> Result<core::ops::Range<usize>, Error> {
+        match start.checked_add(size) {
+            Some(end) if end <= self.size() => Ok(start as usize..end as usize),
+            _ => Err(Error::UnexpectedEof("SliceDisk access past the end".to_string())),
+        }
+    }
+}
+
+impl Disk for SliceDisk<'_> {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let range = self.range(self.pos.get(), buffer.len() as u64)?;
+        buffer.copy_from_slice(&self.bytes()[range]);
+        self.pos.set(self.pos.get() + buffer.len() as u64);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
+        let range = self.range(self.pos.get(), buffer.len() as u64)?;
+        self.bytes_mut()[range].copy_from_slice(buffer);
+        self.pos.set(self.pos.get() + buffer.len() as u64);
+        Ok(buffer.len())
+    }
+
+    fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
+        let range = self.range(offset.value()?, size)?;
+        Ok(self.bytes()[range].to_vec())
+    }
+
+    fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error> {
+        let range = self.range(offset.value()?, buffer.len() as u64)?;
+        self.bytes_mut()[range].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn seek(&self, offset: u64) -> Result<(), Error> {
+        self.range(offset, 0)?;
+        self.pos.set(offset);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+    use crate::ext2::Ext2Filesystem;
+    use crate::testing::{pattern, Mkfs};
+
+    const IMAGE_BLOCKS: u32 = 128;
+    static mut IMAGE: [u8; IMAGE_BLOCKS as usize * 1024] = [0; IMAGE_BLOCKS as usize * 1024];
+
+    #[test]
+    fn mount_image_in_a_static_array() {
+        let image: &'static mut [u8] = unsafe { &mut *core::ptr::addr_of_mut!(IMAGE) };
+        image.copy_from_slice(&Mkfs { blocks: IMAGE_BLOCKS, ..Default::default() }.build());
+        let mut fs = Ext2Filesystem::mount(Box::new(SliceDisk::new(image))).unwrap();
+        fs.mk_dir("/dir", 0o755).unwrap();
+        let inode = crate::testing::create_file(&mut fs, "/dir/file", &pattern(2500));
+        assert_eq!(fs.pread(inode, 0, 2500).unwrap(), pattern(2500));
+        assert_eq!(fs.verify_links().unwrap(), []);
+    }
+
+    #[test]
+    fn bounds_checked() {
+        let mut bytes = [0u8; 64];
+        let disk = SliceDisk::new(&mut bytes);
+        disk.write_at(&Offset::absolute(60), &[1, 2, 3, 4]).unwrap();
+        assert_eq!(disk.read_at(&Offset::absolute(62), 2).unwrap(), [3, 4]);
+        assert!(matches!(disk.read_at(&Offset::absolute(62), 3), Err(Error::UnexpectedEof(_))));
+        assert!(matches!(disk.write_at(&Offset::absolute(u64::MAX), &[0]), Err(Error::UnexpectedEof(_))));
+        assert!(disk.seek(65).is_err());
+    }
+}
@@ -95,6 +95,14 @@ impl Stat {
     pub fn flags(&self) -> FileFlags {
         self.flags
     }
+    /// Major number of a device file, from the Linux `dev_t` encoding
+    pub fn rdev_major(&self) -> u32 {
+        (((self.rdev >> 8) & 0xfff) | ((self.rdev >> 32) & !0xfff)) as u32
+    }
+    /// Minor number of a device file, from the Linux `dev_t` encoding
+    pub fn rdev_minor(&self) -> u32 {
+        ((self.rdev & 0xff) | ((self.rdev >> 12) & !0xff)) as u32
+    }
 }
 
 bitflags::bitflags! {
@@ -218,10 +226,12 @@ bitflags::bitflags! {
         /// Last accessed time should not updated
         const DONT_ATIME = 0x00000080;
         /// Hash indexed directory
-        const HASH_DIR = 0x00010000;
+        const HASH_DIR = 0x00001000;
         /// AFS directory
-        const AFS_DIR = 0x00020000;
+        const AFS_DIR = 0x00002000;
         /// Journal file data
-        const JOURNAL_DATA = 0x00040000;
+        const JOURNAL_DATA = 0x00004000;
+        /// Data mapped by an ext4 extent tree instead of block pointers
+        const EXT4_EXTENTS = 0x00080000;
     }
 }
@@ -0,0 +1,323 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::time::Duration;
+use std::ffi::OsString;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::ext2::dir::DirHandle;
+use crate::ext2::Ext2Filesystem;
+use crate::fs::disk::{Disk, Offset};
+use crate::fs::error::Error;
+use crate::fs::file::FsFile;
+use crate::fs::stat::{Mode, Stat};
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::InvalidInput(_) => ErrorKind::InvalidInput,
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::IOError(_) => ErrorKind::Other,
+            Error::UnexpectedEof(_) => ErrorKind::UnexpectedEof,
+            Error::InvalidData(_) => ErrorKind::InvalidData,
+            Error::FileExists(_) => ErrorKind::AlreadyExists,
+            Error::PermissionDenied(_) => ErrorKind::PermissionDenied,
+            Error::NameTooLong(_) => ErrorKind::InvalidFilename,
+            Error::NoSpace(_) => ErrorKind::StorageFull,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::UnexpectedEof => Error::UnexpectedEof(e.to_string()),
+            ErrorKind::PermissionDenied => Error::PermissionDenied(e.to_string()),
+            ErrorKind::StorageFull => Error::NoSpace(e.to_string()),
+            _ => Error::IOError(e.to_string()),
+        }
+    }
+}
+
+impl Read for FsFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(FsFile::read(self, buf)?)
+    }
+}
+
+impl Write for FsFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(FsFile::write(self, buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(self.sync_data()?)
+    }
+}
+
+/// Any seekable std stream can back a filesystem, e.g. `RefCell::new(File::open(path)?)`
+impl<T: Read + Write + Seek> Disk for RefCell<T> {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.borrow_mut().read(buffer)?)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
+        Ok(self.borrow_mut().write(buffer)?)
+    }
+
+    fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
+        let mut buffer = vec![0u8; size as usize];
+        let mut stream = self.borrow_mut();
+        stream.seek(SeekFrom::Start(offset.value()?))?;
+        stream.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error> {
+        let mut stream = self.borrow_mut();
+        stream.seek(SeekFrom::Start(offset.value()?))?;
+        stream.write_all(buffer)?;
+        Ok(buffer.len())
+    }
+
+    fn seek(&self, offset: u64) -> Result<(), Error> {
+        self.borrow_mut().seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(self.borrow_mut().flush()?)
+    }
+}
+
+/// Counterpart of `std::fs::Metadata`
+#[derive(Debug, Copy, Clone)]
+pub struct StdMetadata {
+    stat: Stat,
+}
+
+/// Counterpart of `std::fs::FileType`
+#[derive(Debug, Copy, Clone)]
+pub struct StdFileType {
+    mode: Mode,
+}
+
+/// Counterpart of `std::fs::DirEntry`
+#[derive(Debug, Clone)]
+pub struct StdDirEntry {
+    path: PathBuf,
+    name: String,
+    stat: Stat,
+}
+
+/// Counterpart of `std::fs::ReadDir`, `.` and `..` are skipped
+pub struct StdReadDir<'a> {
+    path: String,
+    handle: Result<DirHandle<'a>, Option<Error>>,
+}
+
+impl StdMetadata {
+    pub fn file_type(&self) -> StdFileType {
+        StdFileType { mode: self.stat.mode.file_type() }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.stat.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.stat.is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.stat.is_symlink()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.stat.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stat.size == 0
+    }
+
+    /// Permission bits, like `PermissionsExt::mode`
+    pub fn mode(&self) -> u32 {
+        self.stat.mode.perm()
+    }
+
+    pub fn modified(&self) -> std::io::Result<SystemTime> {
+        system_time(self.stat.mtime, self.stat.mtime_nsec)
+    }
+
+    pub fn accessed(&self) -> std::io::Result<SystemTime> {
+        system_time(self.stat.atime, self.stat.atime_nsec)
+    }
+
+    /// ext2 keeps no creation time
+    pub fn created(&self) -> std::io::Result<SystemTime> {
+        Err(std::io::Error::new(ErrorKind::Unsupported, "creation time is not available on ext2"))
+    }
+
+    pub fn stat(&self) -> Stat {
+        self.stat
+    }
+}
+
+/// `secs` from the epoch, negative before it, plus `nsec` forward from there
+fn system_time(secs: i64, nsec: i64) -> std::io::Result<SystemTime> {
+    let time = match secs >= 0 {
+        true => SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64)),
+        false => SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+    };
+    time.and_then(|time| time.checked_add(Duration::from_nanos(nsec as u64)))
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "time out of range"))
+}
+
+impl StdFileType {
+    pub fn is_dir(&self) -> bool {
+        self.mode.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode.is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode.is_symlink()
+    }
+}
+
+impl StdDirEntry {
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    pub fn file_name(&self) -> OsString {
+        OsString::from(self.name.clone())
+    }
+
+    /// Metadata of the entry itself, symlinks are not followed
+    pub fn metadata(&self) -> std::io::Result<StdMetadata> {
+        Ok(StdMetadata { stat: self.stat })
+    }
+
+    pub fn file_type(&self) -> std::io::Result<StdFileType> {
+        Ok(StdFileType { mode: self.stat.mode.file_type() })
+    }
+
+    pub fn ino(&self) -> u64 {
+        self.stat.ino
+    }
+}
+
+impl Iterator for StdReadDir<'_> {
+    type Item = std::io::Result<StdDirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let handle = match &mut self.handle {
+            Ok(handle) => handle,
+            // Report a failed open once, then end
+            Err(e) => return e.take().map(|e| Err(e.into())),
+        };
+        loop {
+            let entry = match handle.read_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if matches!(entry.file_name_ref(), "." | "..") {
+                continue;
+            }
+            let name = entry.file_name();
+            let path = match self.path.ends_with('/') {
+                true => format!("{}{}", self.path, name),
+                false => format!("{}/{}", self.path, name),
+            };
+            return Some(Ok(StdDirEntry {
+                path: PathBuf::from(path),
+                name,
+                stat: entry.stat(),
+            }));
+        }
+    }
+}
+
+impl Ext2Filesystem {
+    /// Like `std::fs::read_dir`, a failed open is reported by the first item
+    pub fn read_dir_std(&self, path: &str) -> StdReadDir<'_> {
+        StdReadDir {
+            path: path.to_string(),
+            handle: self.open_dir(path).map_err(Some),
+        }
+    }
+
+    /// Like `std::fs::metadata`, symlinks are followed
+    pub fn metadata_std(&self, path: &str) -> std::io::Result<StdMetadata> {
+        Ok(StdMetadata { stat: self.metadata(path)? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use crate::testing::{mount_default, pattern, Mkfs};
+    use std::io::Cursor;
+
+    #[test]
+    fn read_and_write_through_std_io() {
+        let (mut fs, disk) = mount_default();
+        let data = pattern(5000);
+        let mut file = fs.new_file("/file", 0o644).unwrap();
+        std::io::Write::write_all(&mut file, &data).unwrap();
+        let flushes = disk.flushes();
+        std::io::Write::flush(&mut file).unwrap();
+        assert!(disk.flushes() > flushes);
+        file.seek(0);
+        let mut read = vec![0u8; data.len()];
+        std::io::Read::read_exact(&mut file, &mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn error_kinds() {
+        let kind = |e: Error| std::io::Error::from(e).kind();
+        assert_eq!(kind(Error::NotFound("/a".to_string())), ErrorKind::NotFound);
+        assert_eq!(kind(Error::NameTooLong("a".to_string())), ErrorKind::InvalidFilename);
+        assert_eq!(kind(Error::NoSpace("a".to_string())), ErrorKind::StorageFull);
+        assert_eq!(kind(Error::FileExists("/a".to_string())), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn times_before_the_epoch() {
+        let (mut fs, _) = mount_default();
+        fs.new_file("/file", 0o644).unwrap().truncate(0).unwrap();
+        fs.set_times("/file", Some(1_000), Some(2_000)).unwrap();
+        let metadata = fs.metadata_std("/file").unwrap();
+        assert!(metadata.is_empty());
+        assert_eq!(metadata.accessed().unwrap(), SystemTime::UNIX_EPOCH + Duration::from_secs(1_000));
+        assert_eq!(metadata.modified().unwrap(), SystemTime::UNIX_EPOCH + Duration::from_secs(2_000));
+        let mut stat = metadata.stat();
+        (stat.mtime, stat.mtime_nsec) = (-2, 500_000_000);
+        (stat.atime, stat.atime_nsec) = (-1_000_000, 0);
+        let metadata = StdMetadata { stat };
+        assert_eq!(metadata.modified().unwrap(), SystemTime::UNIX_EPOCH - Duration::from_millis(1_500));
+        assert_eq!(metadata.accessed().unwrap(), SystemTime::UNIX_EPOCH - Duration::from_secs(1_000_000));
+    }
+
+    #[test]
+    fn mount_a_std_stream() {
+        let disk = RefCell::new(Cursor::new(Mkfs::default().build()));
+        let mut fs = Ext2Filesystem::mount(Box::new(disk)).unwrap();
+        fs.new_file("/file", 0o644).unwrap().write(b"hello").unwrap();
+        let mut read = [0u8; 5];
+        std::io::Read::read_exact(&mut fs.open("/file").unwrap(), &mut read).unwrap();
+        assert_eq!(&read, b"hello");
+    }
+}
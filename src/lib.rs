@@ -4,9 +4,13 @@
 #![no_std]
 extern crate alloc;
 extern crate core;
+#[cfg(feature = "std")]
+extern crate std;
 
 pub mod ext2;
 pub mod fs;
+#[cfg(test)]
+mod testing;
 
 #[macro_export]
 macro_rules! int_get {
@@ -0,0 +1,335 @@
+#![allow(dead_code)]
+//! In-memory ext2 images for the unit tests
+
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::mem;
+
+use crate::ext2::dir::Ext2DirEntryStruct;
+use crate::ext2::group::{EXT2_GROUP_DESC_SIZE, Ext2GroupDesc};
+use crate::ext2::inode::Ext2InodeStruct;
+use crate::ext2::superblock::{Ext2SuperBlock, FeatureIncompat, FeatureRoCompat};
+use crate::ext2::{Ext2Filesystem, MountOptions};
+use crate::fs::disk::{Disk, Offset};
+use crate::fs::error::Error;
+use crate::to_slice;
+
+pub const ROOT_INO: u64 = 2;
+pub const MKFS_TIME: u32 = 1_700_000_000;
+
+/// Disk over an image in memory. Clones share the bytes, so an image can be
+/// inspected or mounted again after the filesystem using it is dropped.
+#[derive(Clone, Default)]
+pub struct MemDisk {
+    data: Rc<RefCell<Vec<u8>>>,
+    pos: Rc<Cell<u64>>,
+    reads: Rc<Cell<u64>>,
+    writes: Rc<Cell<u64>>,
+    flushes: Rc<Cell<u64>>,
+}
+
+impl MemDisk {
+    pub fn new(image: Vec<u8>) -> MemDisk {
+        MemDisk { data: Rc::new(RefCell::new(image)), ..Default::default() }
+    }
+
+    /// Copy of the whole image
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.borrow().clone()
+    }
+
+    pub fn peek(&self, offset: u64, len: usize) -> Vec<u8> {
+        self.data.borrow()[offset as usize..offset as usize + len].to_vec()
+    }
+
+    pub fn poke(&self, offset: u64, bytes: &[u8]) {
+        self.data.borrow_mut()[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Append `len` zero bytes, like growing the device under the filesystem
+    pub fn grow(&self, len: usize) {
+        let mut data = self.data.borrow_mut();
+        let new_len = data.len() + len;
+        data.resize(new_len, 0);
+    }
+
+    /// Positioned reads so far
+    pub fn reads(&self) -> u64 {
+        self.reads.get()
+    }
+
+    /// Positioned writes so far
+    pub fn writes(&self) -> u64 {
+        self.writes.get()
+    }
+
+    pub fn flushes(&self) -> u64 {
+        self.flushes.get()
+    }
+
+    fn range(&self, start: u64, size: u64) -> Result<core::ops::Range<usize>, Error> {
+        match start.checked_add(size) {
+            Some(end) if end <= self.data.borrow().len() as u64 => Ok(start as usize..end as usize),
+            _ => Err(Error::UnexpectedEof("MemDisk access past the end".to_string())),
+        }
+    }
+}
+
+impl Disk for MemDisk {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let range = self.range(self.pos.get(), buffer.len() as u64)?;
+        buffer.copy_from_slice(&self.data.borrow()[range]);
+        self.pos.set(self.pos.get() + buffer.len() as u64);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, Error> {
+        let range = self.range(self.pos.get(), buffer.len() as u64)?;
+        self.data.borrow_mut()[range].copy_from_slice(buffer);
+        self.pos.set(self.pos.get() + buffer.len() as u64);
+        Ok(buffer.len())
+    }
+
+    fn read_at(&self, offset: &Offset, size: u64) -> Result<Vec<u8>, Error> {
+        let range = self.range(offset.value()?, size)?;
+        self.reads.set(self.reads.get() + 1);
+        Ok(self.data.borrow()[range].to_vec())
+    }
+
+    fn write_at(&self, offset: &Offset, buffer: &[u8]) -> Result<usize, Error> {
+        let range = self.range(offset.value()?, buffer.len() as u64)?;
+        self.writes.set(self.writes.get() + 1);
+        self.data.borrow_mut()[range].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn seek(&self, offset: u64) -> Result<(), Error> {
+        self.range(offset, 0)?;
+        self.pos.set(offset);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.flushes.set(self.flushes.get() + 1);
+        Ok(())
+    }
+}
+
+/// Builds an empty ext2 image the way mke2fs lays it out: every group starts
+/// with its bitmaps and inode table, groups 0, 1 and powers of 3, 5 and 7 carry
+/// a superblock and descriptor backup in front, the root directory is the only entry.
+#[derive(Debug, Clone)]
+pub struct Mkfs {
+    pub block_size: u64,
+    pub blocks: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub inode_size: u16,
+    pub reserved_blocks: u32,
+    pub filetype: bool,
+}
+
+impl Default for Mkfs {
+    fn default() -> Self {
+        Mkfs {
+            block_size: 1024,
+            blocks: 2048,
+            blocks_per_group: 8192,
+            inodes_per_group: 128,
+            inode_size: 128,
+            reserved_blocks: 0,
+            filetype: true,
+        }
+    }
+}
+
+impl Mkfs {
+    /// Default layout with another block size, groups stay 8 * block size blocks long
+    pub fn with_block_size(block_size: u64, blocks: u32) -> Mkfs {
+        Mkfs { block_size, blocks, blocks_per_group: block_size as u32 * 8, ..Default::default() }
+    }
+
+    pub fn first_data_block(&self) -> u64 {
+        (self.block_size == 1024) as u64
+    }
+
+    pub fn groups(&self) -> u64 {
+        (self.blocks as u64 - self.first_data_block()).div_ceil(self.blocks_per_group as u64)
+    }
+
+    fn desc_blocks(&self) -> u64 {
+        (self.groups() * EXT2_GROUP_DESC_SIZE as u64).div_ceil(self.block_size)
+    }
+
+    fn inode_table_blocks(&self) -> u64 {
+        (self.inodes_per_group as u64 * self.inode_size as u64).div_ceil(self.block_size)
+    }
+
+    fn has_backup(group: u64) -> bool {
+        let is_power_of = |base: u64| {
+            let mut n = base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        };
+        group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+    }
+
+    /// Block bitmap, inode bitmap and inode table of a group
+    pub fn group_layout(&self, group: u64) -> (u64, u64, u64) {
+        let mut block = self.first_data_block() + group * self.blocks_per_group as u64;
+        if Self::has_backup(group) {
+            block += 1 + self.desc_blocks();
+        }
+        (block, block + 1, block + 2)
+    }
+
+    /// Block holding the entries of the root directory
+    pub fn root_block(&self) -> u64 {
+        self.group_layout(0).2 + self.inode_table_blocks()
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let bs = self.block_size;
+        let mut image = vec![0u8; (self.blocks as u64 * bs) as usize];
+        let first = self.first_data_block();
+        let groups = self.groups();
+        let bpg = self.blocks_per_group as u64;
+        let ipg = self.inodes_per_group as u64;
+        let mut descs = Vec::new();
+        let (mut free_blocks, mut free_inodes) = (0u64, 0u64);
+        for group in 0..groups {
+            let start = first + group * bpg;
+            let end = (start + bpg).min(self.blocks as u64);
+            let (block_bitmap, inode_bitmap, inode_table) = self.group_layout(group);
+            let mut used = inode_table + self.inode_table_blocks();
+            if group == 0 {
+                used += 1;
+            }
+            let mut bitmap = vec![0u8; bs as usize];
+            for bit in 0..bs * 8 {
+                if start + bit < used || start + bit >= end {
+                    bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+                }
+            }
+            image[(block_bitmap * bs) as usize..((block_bitmap + 1) * bs) as usize].copy_from_slice(&bitmap);
+            let mut bitmap = vec![0u8; bs as usize];
+            let reserved = match group {
+                0 => 10,
+                _ => 0,
+            };
+            for bit in 0..bs * 8 {
+                if bit < reserved || bit >= ipg {
+                    bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+                }
+            }
+            image[(inode_bitmap * bs) as usize..((inode_bitmap + 1) * bs) as usize].copy_from_slice(&bitmap);
+            let group_free_blocks = end - used;
+            free_blocks += group_free_blocks;
+            free_inodes += ipg - reserved;
+            let mut desc: Ext2GroupDesc = unsafe { mem::zeroed() };
+            desc.bg_block_bitmap = block_bitmap as u32;
+            desc.bg_inode_bitmap = inode_bitmap as u32;
+            desc.bg_inode_table = inode_table as u32;
+            desc.bg_free_blocks_count = group_free_blocks as u16;
+            desc.bg_free_inodes_count = (ipg - reserved) as u16;
+            desc.bg_used_dirs_count = (group == 0) as u16;
+            descs.extend_from_slice(to_slice!(&desc, Ext2GroupDesc));
+        }
+        let mut sb: Ext2SuperBlock = unsafe { mem::zeroed() };
+        sb.s_inodes_count = (groups * ipg) as u32;
+        sb.s_blocks_count = self.blocks;
+        sb.s_r_blocks_count = self.reserved_blocks;
+        sb.s_free_blocks_count = free_blocks as u32;
+        sb.s_free_inodes_count = free_inodes as u32;
+        sb.s_first_data_block = first as u32;
+        sb.s_log_block_size = (bs / 1024).trailing_zeros();
+        sb.s_log_frag_size = sb.s_log_block_size;
+        sb.s_blocks_per_group = self.blocks_per_group;
+        sb.s_frags_per_group = self.blocks_per_group;
+        sb.s_inodes_per_group = self.inodes_per_group;
+        sb.s_wtime = MKFS_TIME;
+        sb.s_max_mnt_count = u16::MAX;
+        sb.s_magic = Ext2SuperBlock::MAGIC;
+        sb.s_state = 1;
+        sb.s_pad = 1;
+        sb.s_rev_level = 1;
+        sb.s_first_ino = 11;
+        sb.s_inode_size = self.inode_size;
+        sb.s_feature_incompat = match self.filetype {
+            true => FeatureIncompat::FILETYPE.bits(),
+            false => 0,
+        };
+        sb.s_feature_ro_compat = FeatureRoCompat::SPARSE_SUPER.bits();
+        sb.s_uuid = *b"ext2-fs testimg!";
+        sb.s_mkfs_time = MKFS_TIME;
+        for group in (0..groups).filter(|&group| Self::has_backup(group)) {
+            let start = first + group * bpg;
+            sb.s_block_group_nr = group as u16;
+            let sb_offset = match group {
+                0 => 1024,
+                _ => start * bs,
+            };
+            image[sb_offset as usize..sb_offset as usize + 1024].copy_from_slice(to_slice!(&sb, Ext2SuperBlock));
+            let desc_offset = ((start + 1) * bs) as usize;
+            image[desc_offset..desc_offset + descs.len()].copy_from_slice(&descs);
+        }
+        let root_block = self.root_block();
+        let mut root = Ext2InodeStruct::new_dir(0o755, root_block as u32, bs as u32);
+        root.i_mtime = MKFS_TIME;
+        root.i_ctime = MKFS_TIME;
+        root.i_atime = MKFS_TIME;
+        let slot = (self.group_layout(0).2 * bs + (ROOT_INO - 1) * self.inode_size as u64) as usize;
+        image[slot..slot + mem::size_of::<Ext2InodeStruct>()].copy_from_slice(to_slice!(&root, Ext2InodeStruct));
+        let dir_type = match self.filetype {
+            true => 2,
+            false => 0,
+        };
+        let dot = Ext2DirEntryStruct { inode_num: ROOT_INO as u32, rec_len: 12, name_len: 1, file_type: dir_type };
+        let dotdot = Ext2DirEntryStruct {
+            inode_num: ROOT_INO as u32,
+            rec_len: (bs - 12) as u16,
+            name_len: 2,
+            file_type: dir_type,
+        };
+        let mut block = dot.to_bytes(".");
+        block.extend(dotdot.to_bytes(".."));
+        let at = (root_block * bs) as usize;
+        image[at..at + block.len()].copy_from_slice(&block);
+        image
+    }
+}
+
+/// Mount `image`, the returned disk shares the bytes with the mounted one
+pub fn mount(image: Vec<u8>) -> (Ext2Filesystem, MemDisk) {
+    mount_with_options(image, MountOptions::default())
+}
+
+pub fn mount_with_options(image: Vec<u8>, options: MountOptions) -> (Ext2Filesystem, MemDisk) {
+    let disk = MemDisk::new(image);
+    let fs = Ext2Filesystem::mount_with_options(Box::new(disk.clone()), options).unwrap();
+    (fs, disk)
+}
+
+/// A freshly made 2 MiB filesystem with 1 KiB blocks
+pub fn mount_default() -> (Ext2Filesystem, MemDisk) {
+    mount(Mkfs::default().build())
+}
+
+/// Create a regular file holding exactly `data`
+pub fn create_file(fs: &mut Ext2Filesystem, path: &str, data: &[u8]) -> u64 {
+    let mut file = fs.new_file(path, 0o644).unwrap();
+    file.write(data).unwrap();
+    file.truncate(data.len() as u64).unwrap();
+    file.inode()
+}
+
+/// Bytes whose value depends on the position, so misplaced blocks show up
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ (i / 1024) as u8).collect()
+}